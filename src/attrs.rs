@@ -0,0 +1,182 @@
+use std::iter::FromIterator;
+
+use crate::{
+    symbol::{Key, Symbol},
+    sync::{Rc, RedBlackTreeMap},
+    value::Value,
+};
+
+/// The attributes of a Nix attribute set, always iterated in lexicographic
+/// order of their names, as Nix guarantees.
+///
+/// Most sets only have a handful of attributes, so those are kept in a flat
+/// array sorted by name, which is cheap to build and to scan. Sets that grow
+/// beyond [`Attrs::SMALL_MAX`] attributes switch to a persistent sorted map,
+/// so that `//` on a large set shares its structure instead of copying it.
+#[derive(Clone)]
+pub enum Attrs {
+    Small(Rc<[(Symbol, Value)]>),
+    Large(RedBlackTreeMap<Symbol, Value>),
+}
+
+impl Attrs {
+    pub const SMALL_MAX: usize = 8;
+
+    pub fn new() -> Self {
+        Self::Small(Rc::from(Vec::new()))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Attrs::Small(x) => x.len(),
+            Attrs::Large(x) => x.size(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get<K: Key + ?Sized>(&self, key: &K) -> Option<&Value> {
+        match self {
+            Attrs::Small(x) => x.iter().find(|(k, _)| key.matches(k)).map(|(_, v)| v),
+            Attrs::Large(x) => x.get(&key.to_symbol()?),
+        }
+    }
+
+    pub fn contains_key<K: Key + ?Sized>(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&self, key: Symbol, value: Value) -> Self {
+        let mut res = self.clone();
+        res.insert_mut(key, value);
+        res
+    }
+
+    pub fn insert_mut(&mut self, key: Symbol, value: Value) {
+        match self {
+            Attrs::Small(x) => match x.binary_search_by(|(k, _)| k.cmp(&key)) {
+                Ok(i) => {
+                    let mut entries = x.to_vec();
+                    entries[i].1 = value;
+                    *x = Rc::from(entries);
+                }
+                Err(_) if x.len() >= Self::SMALL_MAX => {
+                    let mut map: RedBlackTreeMap<Symbol, Value> = x.iter().cloned().collect();
+                    map.insert_mut(key, value);
+                    *self = Attrs::Large(map);
+                }
+                Err(i) => {
+                    let mut entries = x.to_vec();
+                    entries.insert(i, (key, value));
+                    *x = Rc::from(entries);
+                }
+            },
+            Attrs::Large(x) => x.insert_mut(key, value),
+        }
+    }
+
+    pub fn remove_mut<K: Key + ?Sized>(&mut self, key: &K) {
+        match self {
+            Attrs::Small(x) => {
+                if x.iter().any(|(k, _)| key.matches(k)) {
+                    *x = x.iter().filter(|(k, _)| !key.matches(k)).cloned().collect();
+                }
+            }
+            Attrs::Large(x) => {
+                if let Some(key) = key.to_symbol() {
+                    x.remove_mut(&key);
+                }
+            }
+        }
+    }
+
+    /// The attributes of `self` overridden by those of `other`, as with `//`.
+    pub fn update(&self, other: &Attrs) -> Attrs {
+        match (self, other) {
+            (_, Attrs::Small(r)) if r.is_empty() => self.clone(),
+            (Attrs::Small(l), _) if l.is_empty() => other.clone(),
+            // Add the smaller side into the larger, so that a large set keeps
+            // sharing its structure.
+            (_, Attrs::Large(r)) if self.len() < other.len() => {
+                let mut res = r.clone();
+                for (k, v) in self.iter() {
+                    if !r.contains_key(k) {
+                        res.insert_mut(k.to_owned(), v.to_owned());
+                    }
+                }
+                Attrs::Large(res)
+            }
+            _ => {
+                let mut res = self.clone();
+                for (k, v) in other.iter() {
+                    res.insert_mut(k.to_owned(), v.to_owned());
+                }
+                res
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        match self {
+            Attrs::Small(x) => Iter::Small(x.iter()),
+            Attrs::Large(x) => Iter::Large(Box::new(x.iter())),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Symbol> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl Default for Attrs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromIterator<(Symbol, Value)> for Attrs {
+    fn from_iter<T: IntoIterator<Item = (Symbol, Value)>>(iter: T) -> Self {
+        let mut res = Attrs::new();
+        for (k, v) in iter {
+            res.insert_mut(k, v);
+        }
+        res
+    }
+}
+
+impl PartialEq for Attrs {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+pub enum Iter<'a> {
+    Small(std::slice::Iter<'a, (Symbol, Value)>),
+    Large(Box<dyn Iterator<Item = (&'a Symbol, &'a Value)> + 'a>),
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Symbol, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Iter::Small(x) => x.next().map(|(k, v)| (k, v)),
+            Iter::Large(x) => x.next(),
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a Attrs {
+    type Item = (&'a Symbol, &'a Value);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
@@ -0,0 +1,257 @@
+//! `nix_evaluator eval`: evaluate an expression and print the result, for
+//! scripts that would otherwise use `nix-instantiate --eval`.
+
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use nix_evaluator::{attrs::Attrs, embed::Evaluator, value::Value};
+
+use crate::{print, report};
+
+const USAGE: &str = "\
+usage: nix_evaluator eval [options]
+
+Evaluate an expression and print the result. The expression is read from
+standard input unless --expr or --file is given.
+
+options:
+  -E, --expr EXPR       evaluate EXPR
+  -f, --file FILE       evaluate the file FILE
+  -A, --attr PATH       select the attribute at PATH, like `a.b.0`
+      --arg NAME EXPR   pass the value of EXPR as NAME to functions taking a set
+      --argstr NAME STR pass the string STR as NAME to functions taking a set
+      --apply EXPR      call the function EXPR with the result
+      --strict          evaluate the result deeply
+      --json            print the result as JSON
+      --raw             print the result, which must be a string, unquoted
+  -h, --help            show this message
+";
+
+enum Source {
+    Expr(String),
+    File(PathBuf),
+    Stdin,
+}
+
+enum Arg {
+    Expr(String),
+    Str(String),
+}
+
+enum Output {
+    Nix,
+    Json,
+    Raw,
+}
+
+struct Options {
+    source: Source,
+    attr_path: Option<String>,
+    args: Vec<(String, Arg)>,
+    apply: Option<String>,
+    strict: bool,
+    output: Output,
+}
+
+impl Options {
+    /// Parse the arguments following `eval`, or `None` if help was asked
+    /// for.
+    fn parse(args: Vec<String>) -> std::result::Result<Option<Self>, String> {
+        let mut options = Options {
+            source: Source::Stdin,
+            attr_path: None,
+            args: Vec::new(),
+            apply: None,
+            strict: false,
+            output: Output::Nix,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut next = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| format!("{} requires an argument", flag))
+            };
+            let source = match arg.as_str() {
+                "-E" | "--expr" => Some(Source::Expr(next(&arg)?)),
+                "-f" | "--file" => Some(Source::File(next(&arg)?.into())),
+                _ => None,
+            };
+            if let Some(source) = source {
+                if !matches!(options.source, Source::Stdin) {
+                    return Err("only one of --expr and --file can be given".into());
+                }
+                options.source = source;
+                continue;
+            }
+            let output = match arg.as_str() {
+                "--json" => Some(Output::Json),
+                "--raw" => Some(Output::Raw),
+                _ => None,
+            };
+            if let Some(output) = output {
+                if !matches!(options.output, Output::Nix) {
+                    return Err("only one of --json and --raw can be given".into());
+                }
+                options.output = output;
+                continue;
+            }
+            match arg.as_str() {
+                "-A" | "--attr" => options.attr_path = Some(next(&arg)?),
+                "--arg" => {
+                    let name = next(&arg)?;
+                    options.args.push((name, Arg::Expr(next(&arg)?)));
+                }
+                "--argstr" => {
+                    let name = next(&arg)?;
+                    options.args.push((name, Arg::Str(next(&arg)?)));
+                }
+                "--apply" => options.apply = Some(next(&arg)?),
+                "--strict" => options.strict = true,
+                "-h" | "--help" => return Ok(None),
+                _ => return Err(format!("unrecognised argument '{}'", arg)),
+            }
+        }
+        Ok(Some(options))
+    }
+}
+
+/// Split an attribute path like `a."b.c".0` into its names.
+fn parse_attr_path(path: &str) -> Result<Vec<String>> {
+    let mut res = Vec::new();
+    let mut current = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => res.push(std::mem::take(&mut current)),
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => current.push(c),
+                    None => {
+                        return Err(eyre!("missing closing quote in selection path '{}'", path))
+                    }
+                }
+            },
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        res.push(current);
+    }
+    Ok(res)
+}
+
+/// Select the value at `path` in `value`. Functions along the way are
+/// called with `args`, as Nix does.
+fn select(evaluator: &Evaluator, mut value: Value, path: &str, args: &Attrs) -> Result<Value> {
+    let names = parse_attr_path(path)?;
+    for (i, name) in names.iter().enumerate() {
+        value = match value.materialize()? {
+            Value::AttrSet(attrs) => attrs.get(name.as_str()).cloned().ok_or_else(|| {
+                eyre!("attribute '{}' in selection path '{}' not found", name, path)
+            })?,
+            Value::List(list) => {
+                let index: usize = name.parse().map_err(|_| {
+                    eyre!("'{}' in selection path '{}' is not a list index", name, path)
+                })?;
+                list.get(index).cloned().ok_or_else(|| {
+                    eyre!("list index {} in selection path '{}' is out of range", index, path)
+                })?
+            }
+            x => {
+                return Err(eyre!(
+                    "the expression selected by the selection path '{}' should be a set or a list but is of type {}",
+                    names[..i].join("."),
+                    x.human_readable_type()
+                ))
+            }
+        };
+        value = evaluator.auto_call(value, args)?;
+    }
+    Ok(value)
+}
+
+fn run(options: &Options) -> Result<()> {
+    let evaluator = Evaluator::new();
+    let root = match &options.source {
+        Source::Expr(x) => evaluator.eval_str(x)?,
+        Source::File(x) => evaluator.eval_file(x)?,
+        Source::Stdin => {
+            let mut source = String::new();
+            io::stdin()
+                .read_to_string(&mut source)
+                .wrap_err("failed to read standard input")?;
+            evaluator.eval_str(&source)?
+        }
+    };
+    let mut args = Attrs::new();
+    for (name, arg) in &options.args {
+        let value = match arg {
+            Arg::Expr(x) => evaluator
+                .eval_str(x)
+                .wrap_err_with(|| format!("failed to evaluate --arg {}", name))?,
+            Arg::Str(x) => x.as_str().into(),
+        };
+        args.insert_mut(name.as_str().into(), value);
+    }
+    let mut value = evaluator.auto_call(root, &args)?;
+    if let Some(path) = &options.attr_path {
+        value = select(&evaluator, value, path, &args)?;
+    }
+    if let Some(f) = &options.apply {
+        let f = evaluator
+            .eval_str(f)
+            .wrap_err("failed to evaluate --apply")?;
+        value = f.call(value)?;
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match options.output {
+        Output::Nix => {
+            let value = if options.strict {
+                value.materialize_deep()?
+            } else {
+                value.materialize()?
+            };
+            writeln!(out, "{}", print::to_string(&value))?;
+        }
+        Output::Json => {
+            let json = evaluator.eval_str("builtins.toJSON")?.call(value)?;
+            if let Value::String(json) = json {
+                out.write_all(json.as_bytes())?;
+                writeln!(out)?;
+            }
+        }
+        // Like `nix eval --raw`, without a trailing newline.
+        Output::Raw => out.write_all(value.coerce_to_string()?.as_bytes())?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Run the `eval` command, returning its exit code: 0 on success, 1 if
+/// evaluation failed, and 2 if the arguments were invalid.
+pub fn main(args: Vec<String>) -> i32 {
+    let options = match Options::parse(args) {
+        Ok(Some(x)) => x,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!("Try 'nix_evaluator eval --help' for more information.");
+            return 2;
+        }
+    };
+    match run(&options) {
+        Ok(()) => 0,
+        Err(e) => {
+            report(&e);
+            1
+        }
+    }
+}
@@ -0,0 +1,567 @@
+//! `nix_evaluator lsp`: a language server for Nix files, speaking the
+//! Language Server Protocol over standard input and output.
+//!
+//! Diagnostics and go-to-definition only look at syntax. Hover and
+//! completion evaluate the expression in question, within limits that keep
+//! them responsive; the `let`s and `with`s around it are evaluated too, but
+//! function arguments aren't known, so expressions that need them show
+//! nothing. Evaluation may only read files in the workspace, or next to the
+//! file without one, and sees no environment variables.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Exit, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, Request as _},
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, InitializeParams, Location, MarkupContent,
+    MarkupKind, OneOf, Position, PublishDiagnosticsParams, Range, ServerCapabilities,
+    TextDocumentPositionParams, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+use nix_evaluator::{
+    diagnostics::{Diagnostics, MemorySink},
+    embed::{Evaluator, IoPolicy},
+    evaluator::{EvalError, EvaluationContext, Session},
+    limits::Limits,
+    string::NixString,
+    sync::Rc,
+    value::Value,
+};
+use rnix::{
+    parser::ParseError,
+    types::{Apply, KeyValue, Select, TypedNode},
+    value::Anchor,
+    NixValue,
+    SyntaxKind::*,
+    SyntaxNode, SyntaxToken, TextRange, TextSize,
+};
+
+use crate::print;
+
+mod analysis;
+
+/// Inserted where completion is asked for, so that the file parses even
+/// after a trailing `.`.
+const PLACEHOLDER: &str = "__nix_evaluator_completion";
+
+/// How much of a value hover shows, in bytes.
+const MAX_HOVER_LEN: usize = 1000;
+
+/// How much hover and completion may evaluate.
+fn limits() -> Limits {
+    Limits {
+        max_steps: Some(100_000),
+        timeout: Some(Duration::from_millis(200)),
+        max_depth: Some(500),
+        ..Limits::default()
+    }
+}
+
+/// Converts between byte offsets and the positions of the protocol, whose
+/// columns count UTF-16 code units.
+struct LineIndex<'a> {
+    text: &'a str,
+    /// The offset of the start of each line.
+    lines: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> Self {
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { text, lines }
+    }
+
+    fn position(&self, offset: usize) -> Position {
+        let line = self.lines.partition_point(|x| *x <= offset) - 1;
+        let character = self.text[self.lines[line]..offset].encode_utf16().count();
+        Position::new(line as u32, character as u32)
+    }
+
+    fn offset(&self, position: Position) -> usize {
+        let start = match self.lines.get(position.line as usize) {
+            Some(x) => *x,
+            None => return self.text.len(),
+        };
+        let mut character = 0;
+        for (i, c) in self.text[start..].char_indices() {
+            if character >= position.character as usize || c == '\n' {
+                return start + i;
+            }
+            character += c.len_utf16();
+        }
+        self.text.len()
+    }
+
+    fn range(&self, range: TextRange) -> Range {
+        Range::new(
+            self.position(range.start().into()),
+            self.position(range.end().into()),
+        )
+    }
+}
+
+/// Describe a parse error without the offsets the parser includes, since
+/// editors show where it is.
+fn parse_error(err: &ParseError, len: usize) -> (TextRange, String) {
+    let end = TextRange::empty(TextSize::from(len as u32));
+    match err {
+        ParseError::Unexpected(range) => (*range, "unexpected input".into()),
+        ParseError::UnexpectedExtra(range) => {
+            (*range, "unexpected input after the expression".into())
+        }
+        ParseError::UnexpectedWanted(got, range, wanted) => (
+            *range,
+            format!("unexpected {:?}, wanted any of {:?}", got, wanted),
+        ),
+        ParseError::UnexpectedDoubleBind(range) => {
+            (*range, "the argument set is bound twice".into())
+        }
+        ParseError::UnexpectedEOF => (end, "unexpected end of file".into()),
+        ParseError::UnexpectedEOFWanted(wanted) => (
+            end,
+            format!("unexpected end of file, wanted any of {:?}", wanted),
+        ),
+        err => (end, err.to_string()),
+    }
+}
+
+/// The token at `offset`, preferring an identifier next to it.
+fn token_at(root: &SyntaxNode, offset: usize) -> Option<SyntaxToken> {
+    let tokens = root.token_at_offset(TextSize::from(offset as u32));
+    tokens
+        .clone()
+        .find(|x| x.kind() == TOKEN_IDENT)
+        .or_else(|| tokens.right_biased())
+}
+
+/// The expression to show the value of when hovering over `token`.
+fn hover_target(token: &SyntaxToken) -> Option<SyntaxNode> {
+    let parent = token.parent();
+    if parent.kind() != NODE_IDENT {
+        return match parent.kind() {
+            NODE_LITERAL if token.kind() == TOKEN_PATH => Some(parent),
+            NODE_STRING | NODE_BIN_OP | NODE_UNARY_OP => Some(parent),
+            _ => None,
+        };
+    }
+    let ident = parent;
+    let context = ident.parent()?;
+    match context.kind() {
+        // The attribute named, as selected so far.
+        NODE_SELECT if context.first_child() != Some(ident.clone()) => {
+            Some(context).filter(|x| !analysis::in_has_attr_path(x))
+        }
+        // The value of the attribute being defined.
+        NODE_KEY => {
+            if context.children().last() != Some(ident) {
+                return None;
+            }
+            KeyValue::cast(context.parent()?)?.value()
+        }
+        _ if analysis::is_reference(&ident) => Some(ident),
+        _ => None,
+    }
+}
+
+/// The file `literal` names, if it's the path that `import` is called with.
+fn import_target(literal: &SyntaxNode, dir: Option<PathBuf>) -> Option<PathBuf> {
+    let apply = Apply::cast(literal.parent()?)?;
+    let function = apply.lambda()?;
+    if apply.value().as_ref() != Some(literal)
+        || function.kind() != NODE_IDENT
+        || function.text() != "import"
+    {
+        return None;
+    }
+    let mut path = match rnix::types::Value::cast(literal.clone())?.to_value().ok()? {
+        NixValue::Path(Anchor::Relative, x) => dir?.join(x),
+        NixValue::Path(Anchor::Absolute, x) => PathBuf::from(x),
+        _ => return None,
+    };
+    if path.is_dir() {
+        path.push("default.nix");
+    }
+    Some(path).filter(|x| x.is_file())
+}
+
+/// Truncate `s` to at most `len` bytes, marking where it was cut.
+fn truncate(mut s: String, len: usize) -> String {
+    if s.len() > len {
+        let mut end = len;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+        s.push('…');
+    }
+    s
+}
+
+struct Server {
+    connection: Connection,
+    /// The text of the open documents.
+    documents: HashMap<Url, String>,
+    /// The variables every file has.
+    globals: EvaluationContext,
+    /// The directories of the workspace.
+    roots: Vec<PathBuf>,
+}
+
+impl Server {
+    /// Evaluate `source` as if it were in the document at `uri`, within the
+    /// limits, and pass its value to `f`. Errors are ignored, since there's
+    /// no good way to show them.
+    fn with_value<T>(&self, uri: &Url, source: &str, f: impl FnOnce(Value) -> T) -> Option<T> {
+        let session = Session::new()
+            .with_limits(limits())
+            .with_diagnostics(Diagnostics::new(Rc::new(MemorySink::new())));
+        let path = uri.to_file_path().ok();
+        let mut readable = self.roots.clone();
+        if readable.is_empty() {
+            readable.extend(path.as_deref().and_then(Path::parent).map(Path::to_owned));
+        }
+        let evaluator = Evaluator::builder()
+            .session(session)
+            .io_policy(IoPolicy::Restricted(readable))
+            .build();
+        let value = match &path {
+            Some(path) => evaluator.eval_str_as(source, path),
+            None => evaluator.eval_str(source),
+        };
+        // Values can't outlive the evaluator, so they're used here.
+        value.and_then(Value::materialize).ok().map(f)
+    }
+
+    /// The names of the attributes of `node`, evaluated where it is.
+    fn attr_names(&self, uri: &Url, node: &SyntaxNode) -> Vec<String> {
+        let source = analysis::closed_source(node, &self.globals);
+        self.with_value(uri, &source, |value| match value {
+            Value::AttrSet(attrs) => attrs.keys().map(|x| x.as_str().to_owned()).collect(),
+            _ => Vec::new(),
+        })
+        .unwrap_or_default()
+    }
+
+    fn publish_diagnostics(&self, uri: Url, diagnostics: Vec<Diagnostic>) -> Result<()> {
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+        let notification = Notification::new(PublishDiagnostics::METHOD.into(), params);
+        self.connection
+            .sender
+            .send(Message::Notification(notification))?;
+        Ok(())
+    }
+
+    /// The parse errors and unbound variables in `text`.
+    fn diagnostics(&self, text: &str) -> Vec<Diagnostic> {
+        let index = LineIndex::new(text);
+        let ast = rnix::parse(text);
+        let diagnostic = |range, message| Diagnostic {
+            range: index.range(range),
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("nix_evaluator".into()),
+            message,
+            ..Diagnostic::default()
+        };
+        let mut errors: Vec<_> = ast
+            .errors()
+            .iter()
+            .map(|x| parse_error(x, text.len()))
+            .collect();
+        // The parser can report the end of the file more than once.
+        errors.dedup();
+        let mut res: Vec<_> = errors
+            .into_iter()
+            .map(|(range, message)| diagnostic(range, message))
+            .collect();
+        for ident in analysis::unbound(&ast.node(), &self.globals) {
+            let err = EvalError::UnresolvedIdent(ident.text().to_string().into());
+            res.push(diagnostic(ident.text_range(), err.to_string()));
+        }
+        res
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position_params;
+        let text = self.documents.get(&text_document.uri)?;
+        let index = LineIndex::new(text);
+        let root = rnix::parse(text).node();
+        let token = token_at(&root, index.offset(position))?;
+        let node = hover_target(&token)?;
+        let source = analysis::closed_source(&node, &self.globals);
+        let (value, type_name) = self.with_value(&text_document.uri, &source, |x| {
+            (print::to_string_forced(&x, 1), x.human_readable_type())
+        })?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!(
+                    "```nix\n{}\n```\n*{}*",
+                    truncate(value, MAX_HOVER_LEN),
+                    type_name
+                ),
+            }),
+            range: Some(index.range(node.text_range())),
+        })
+    }
+
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position_params;
+        let uri = text_document.uri;
+        let text = self.documents.get(&uri)?;
+        let index = LineIndex::new(text);
+        let root = rnix::parse(text).node();
+        let token = token_at(&root, index.offset(position))?;
+        let dir = || Some(uri.to_file_path().ok()?.parent()?.to_owned());
+        let parent = token.parent();
+        let file = match token.kind() {
+            TOKEN_PATH => import_target(&parent, dir()),
+            // `import` itself leads to the file too.
+            TOKEN_IDENT if token.text() == "import" => Apply::cast(parent.parent()?)
+                .filter(|x| x.lambda().as_ref() == Some(&parent))
+                .and_then(|x| import_target(&x.value()?, dir())),
+            _ => None,
+        };
+        if let Some(file) = file {
+            let uri = Url::from_file_path(file).ok()?;
+            return Some(GotoDefinitionResponse::Scalar(Location::new(
+                uri,
+                Range::default(),
+            )));
+        }
+        if parent.kind() != NODE_IDENT || !analysis::is_reference(&parent) {
+            return None;
+        }
+        match analysis::resolve(&parent, &self.globals) {
+            analysis::Resolution::Local(binding) => Some(GotoDefinitionResponse::Scalar(
+                Location::new(uri, index.range(binding.range)),
+            )),
+            _ => None,
+        }
+    }
+
+    fn completion(&self, params: CompletionParams) -> Option<CompletionResponse> {
+        let TextDocumentPositionParams {
+            text_document,
+            position,
+        } = params.text_document_position;
+        let uri = text_document.uri;
+        let text = self.documents.get(&uri)?;
+        let offset = LineIndex::new(text).offset(position);
+        let text = format!("{}{}{}", &text[..offset], PLACEHOLDER, &text[offset..]);
+        let root = rnix::parse(&text).node();
+        let ident = root
+            .descendants()
+            .find(|x| x.kind() == NODE_IDENT && x.text().to_string().contains(PLACEHOLDER))?;
+        let parent = ident.parent()?;
+        let items = if parent.kind() == NODE_SELECT && parent.first_child() != Some(ident.clone()) {
+            let set = Select::cast(parent)?.set()?;
+            self.attr_names(&uri, &set)
+                .into_iter()
+                .map(|name| CompletionItem {
+                    insert_text: Some(quote(&name)).filter(|x| *x != name),
+                    label: name,
+                    kind: Some(CompletionItemKind::FIELD),
+                    ..CompletionItem::default()
+                })
+                .collect()
+        } else if analysis::is_reference(&ident) {
+            let mut items = Vec::new();
+            let mut add = |name: &str, kind, detail: &str| {
+                if name != PLACEHOLDER && !name.starts_with("__") {
+                    items.push(CompletionItem {
+                        label: name.to_owned(),
+                        kind: Some(kind),
+                        detail: Some(detail.to_owned()),
+                        ..CompletionItem::default()
+                    });
+                }
+            };
+            let mut namespaces = Vec::new();
+            for scope in analysis::scopes(&ident) {
+                match scope {
+                    analysis::Scope::Bindings(_, bindings) => {
+                        for binding in bindings {
+                            add(&binding.name, CompletionItemKind::VARIABLE, "local");
+                        }
+                    }
+                    analysis::Scope::With(namespace) => namespaces.push(namespace),
+                }
+            }
+            for name in self.globals.names() {
+                add(name, CompletionItemKind::VARIABLE, "global");
+            }
+            for namespace in namespaces {
+                for name in self.attr_names(&uri, &namespace) {
+                    add(&name, CompletionItemKind::FIELD, "from with");
+                }
+            }
+            // Variables shadow those after them, as in the compiler.
+            let mut seen = HashSet::new();
+            items.retain(|x| seen.insert(x.label.clone()));
+            items
+        } else {
+            return None;
+        };
+        Some(CompletionResponse::Array(items))
+    }
+
+    fn handle_request(&self, request: Request) -> Response {
+        match request.method.as_str() {
+            HoverRequest::METHOD => respond::<HoverRequest>(request, |x| self.hover(x)),
+            GotoDefinition::METHOD => respond::<GotoDefinition>(request, |x| self.definition(x)),
+            Completion::METHOD => respond::<Completion>(request, |x| self.completion(x)),
+            _ => Response::new_err(
+                request.id,
+                ErrorCode::MethodNotFound as i32,
+                format!("unsupported request {}", request.method),
+            ),
+        }
+    }
+
+    fn handle_notification(&mut self, notification: Notification) -> Result<()> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = params::<DidOpenTextDocument>(notification)?;
+                self.update(params.text_document.uri, params.text_document.text)?;
+            }
+            DidChangeTextDocument::METHOD => {
+                let mut params = params::<DidChangeTextDocument>(notification)?;
+                // Documents are synced in full, so the last change has all
+                // of the text.
+                if let Some(change) = params.content_changes.pop() {
+                    self.update(params.text_document.uri, change.text)?;
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = params::<DidCloseTextDocument>(notification)?;
+                self.documents.remove(&params.text_document.uri);
+                self.publish_diagnostics(params.text_document.uri, Vec::new())?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, uri: Url, text: String) -> Result<()> {
+        let diagnostics = self.diagnostics(&text);
+        self.documents.insert(uri.clone(), text);
+        self.publish_diagnostics(uri, diagnostics)
+    }
+
+    fn run(&mut self) -> Result<()> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        break;
+                    }
+                    let response = self.handle_request(request);
+                    self.connection.sender.send(Message::Response(response))?;
+                }
+                Message::Notification(x) if x.method == Exit::METHOD => break,
+                Message::Notification(x) => self.handle_notification(x)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `name` as it's written after a `.`: quoted unless it's an identifier.
+fn quote(name: &str) -> String {
+    if print::is_identifier(name) {
+        return name.to_owned();
+    }
+    let mut res = String::new();
+    NixString::from(name)
+        .write_quoted(&mut res)
+        .expect("writing to a string");
+    res
+}
+
+fn params<N: lsp_types::notification::Notification>(
+    notification: Notification,
+) -> Result<N::Params> {
+    Ok(notification.extract(N::METHOD)?)
+}
+
+/// Answer `request` with `f`, or with an error if its parameters are
+/// invalid.
+fn respond<R: lsp_types::request::Request>(
+    request: Request,
+    f: impl FnOnce(R::Params) -> R::Result,
+) -> Response {
+    let id = request.id.clone();
+    match request.extract::<R::Params>(R::METHOD) {
+        Ok((id, params)) => Response::new_ok(id, f(params)),
+        Err(e) => Response::new_err(id, ErrorCode::InvalidParams as i32, e.to_string()),
+    }
+}
+
+/// The directories of the workspace the client opened, if it's on disk.
+fn workspace_roots(params: &InitializeParams) -> Vec<PathBuf> {
+    let mut uris: Vec<&Url> = params
+        .workspace_folders
+        .iter()
+        .flatten()
+        .map(|x| &x.uri)
+        .collect();
+    // Clients that predate workspace folders only send a root.
+    #[allow(deprecated)]
+    if uris.is_empty() {
+        uris.extend(&params.root_uri);
+    }
+    uris.into_iter()
+        .filter_map(|x| x.to_file_path().ok())
+        .collect()
+}
+
+pub fn main(args: Vec<String>) -> Result<()> {
+    // Editors pass `--stdio` to choose standard input and output, which is
+    // the only transport there is.
+    if let Some(arg) = args.iter().find(|x| *x != "--stdio") {
+        return Err(eyre!("unrecognised argument '{}'", arg));
+    }
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![".".into()]),
+            ..CompletionOptions::default()
+        }),
+        ..ServerCapabilities::default()
+    };
+    let params: InitializeParams =
+        serde_json::from_value(connection.initialize(serde_json::to_value(capabilities)?)?)?;
+    let mut server = Server {
+        connection,
+        documents: HashMap::new(),
+        globals: Evaluator::new().globals().to_owned(),
+        roots: workspace_roots(&params),
+    };
+    server.run()?;
+    // The connection has to be closed for the threads to finish.
+    drop(server);
+    io_threads.join()?;
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! What can be known about a file from its syntax alone: which variables are
+//! in scope where, and what they refer to. Scoping follows the compiler.
+
+use nix_evaluator::evaluator::EvaluationContext;
+use rnix::{
+    types::{
+        AttrSet, BinOp, BinOpKind, Inherit, KeyValue, Lambda, Pattern, Str, TokenWrapper,
+        TypedNode, With,
+    },
+    SyntaxKind::*,
+    SyntaxNode, TextRange,
+};
+
+/// A variable bound by a `let`, a recursive set or a function.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    pub name: String,
+    /// Where the name is bound.
+    pub range: TextRange,
+}
+
+/// A scope that an expression is in.
+pub enum Scope {
+    /// The variables of a `let`, a recursive set or a function, which is the
+    /// node.
+    Bindings(SyntaxNode, Vec<Binding>),
+    /// The attributes of the namespace of a `with`.
+    With(SyntaxNode),
+}
+
+/// What a variable refers to.
+pub enum Resolution {
+    Local(Binding),
+    Global,
+    /// Maybe an attribute of the namespace of a `with`.
+    With,
+    Unbound,
+}
+
+/// Whether `node` binds its attributes as variables, as a `let` does.
+fn is_recursive_block(node: &SyntaxNode) -> bool {
+    match node.kind() {
+        NODE_LET_IN | NODE_LEGACY_LET => true,
+        NODE_ATTR_SET => AttrSet::cast(node.clone()).map_or(false, |x| x.recursive()),
+        _ => false,
+    }
+}
+
+/// The variables a `let` or recursive set binds: its static attribute names,
+/// each where it's first defined.
+fn block_bindings(node: &SyntaxNode) -> Vec<Binding> {
+    let mut res: Vec<Binding> = Vec::new();
+    for child in node.children() {
+        let mut found = Vec::new();
+        if let Some(inherit) = Inherit::cast(child.clone()) {
+            found.extend(
+                inherit
+                    .idents()
+                    .map(|x| (x.as_str().to_owned(), x.node().text_range())),
+            );
+        } else if let Some(first) = KeyValue::cast(child)
+            .and_then(|x| x.key())
+            .and_then(|x| x.path().next())
+        {
+            match first.kind() {
+                NODE_IDENT => found.push((first.text().to_string(), first.text_range())),
+                NODE_STRING => {
+                    if let Some([rnix::StrPart::Literal(x)]) = Str::cast(first.clone())
+                        .as_ref()
+                        .map(|x| x.parts())
+                        .as_deref()
+                    {
+                        found.push((x.to_owned(), first.text_range()));
+                    }
+                }
+                _ => {}
+            }
+        }
+        for (name, range) in found {
+            if !res.iter().any(|x| x.name == name) {
+                res.push(Binding { name, range });
+            }
+        }
+    }
+    res
+}
+
+/// The arguments of a function.
+fn lambda_bindings(lambda: &Lambda) -> Vec<Binding> {
+    let binding = |node: &SyntaxNode| Binding {
+        name: node.text().to_string(),
+        range: node.text_range(),
+    };
+    let arg = match lambda.arg() {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    match Pattern::cast(arg.clone()) {
+        Some(pattern) => pattern
+            .entries()
+            .filter_map(|x| x.name())
+            .chain(pattern.at())
+            .map(|x| binding(x.node()))
+            .collect(),
+        None => vec![binding(&arg)],
+    }
+}
+
+/// The scopes `node` is in, innermost first.
+pub fn scopes(node: &SyntaxNode) -> Vec<Scope> {
+    let mut res = Vec::new();
+    let mut child = node.clone();
+    while let Some(parent) = child.parent() {
+        if is_recursive_block(&parent) {
+            res.push(Scope::Bindings(parent.clone(), block_bindings(&parent)));
+        } else if let Some(lambda) = Lambda::cast(parent.clone()) {
+            res.push(Scope::Bindings(parent.clone(), lambda_bindings(&lambda)));
+        } else if let Some(with) = With::cast(parent.clone()) {
+            // The namespace itself is outside of the `with`.
+            if let (Some(namespace), Some(body)) = (with.namespace(), with.body()) {
+                if body == child {
+                    res.push(Scope::With(namespace));
+                }
+            }
+        }
+        child = parent;
+    }
+    res
+}
+
+/// Whether `node` is the attribute path on the right of `?`, or part of it.
+pub fn in_has_attr_path(node: &SyntaxNode) -> bool {
+    let mut node = node.clone();
+    while let Some(parent) = node.parent() {
+        match parent.kind() {
+            NODE_SELECT => node = parent,
+            NODE_BIN_OP => {
+                let op = BinOp::cast(parent).expect("is a binary operator");
+                return op.operator() == BinOpKind::IsSet && op.rhs() == Some(node);
+            }
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Whether the identifier `ident` refers to a variable, rather than naming an
+/// attribute or binding a variable.
+pub fn is_reference(ident: &SyntaxNode) -> bool {
+    let parent = match ident.parent() {
+        Some(x) => x,
+        None => return true,
+    };
+    let is_first = parent.first_child().as_ref() == Some(ident);
+    match parent.kind() {
+        NODE_KEY | NODE_PAT_BIND => false,
+        NODE_PAT_ENTRY => !is_first,
+        NODE_LAMBDA => !is_first,
+        NODE_SELECT => is_first && !in_has_attr_path(ident),
+        NODE_BIN_OP => !in_has_attr_path(ident),
+        // `inherit (set) name;` names an attribute of the set.
+        NODE_INHERIT => Inherit::cast(parent).map_or(false, |x| x.from().is_none()),
+        _ => true,
+    }
+}
+
+/// What the identifier `ident` refers to, given the variables every file
+/// has.
+pub fn resolve(ident: &SyntaxNode, globals: &EvaluationContext) -> Resolution {
+    let name = ident.text().to_string();
+    let mut scopes = scopes(ident);
+    // `inherit name;` in a `let` or recursive set takes the variable from
+    // outside of it.
+    let inherit_block = ident
+        .parent()
+        .filter(|x| x.kind() == NODE_INHERIT)
+        .and_then(|x| x.parent());
+    if let (Some(block), Some(Scope::Bindings(scope, _))) = (&inherit_block, scopes.first()) {
+        if block == scope {
+            scopes.remove(0);
+        }
+    }
+    let mut with = false;
+    for scope in scopes {
+        match scope {
+            Scope::Bindings(_, bindings) => {
+                if let Some(x) = bindings.into_iter().find(|x| x.name == name) {
+                    return Resolution::Local(x);
+                }
+            }
+            Scope::With(_) => with = true,
+        }
+    }
+    // Globals take precedence over `with`, as in the compiler.
+    if globals.get(&name).is_some() {
+        Resolution::Global
+    } else if with {
+        Resolution::With
+    } else {
+        Resolution::Unbound
+    }
+}
+
+/// The identifiers in `root` that don't refer to any variable. Those in
+/// syntax errors are left to the parser's errors.
+pub fn unbound(root: &SyntaxNode, globals: &EvaluationContext) -> Vec<SyntaxNode> {
+    root.descendants()
+        .filter(|x| x.kind() == NODE_IDENT && is_reference(x))
+        .filter(|x| !x.ancestors().any(|x| x.kind() == NODE_ERROR))
+        .filter(|x| matches!(resolve(x, globals), Resolution::Unbound))
+        .collect()
+}
+
+/// The entries of a `let` or recursive set that can be written in a `let`:
+/// those that aren't dynamic attributes, leaving out any with syntax errors.
+fn let_entries(node: &SyntaxNode) -> Vec<String> {
+    node.children()
+        .filter(|x| !x.descendants().any(|x| x.kind() == NODE_ERROR))
+        .filter(|x| match KeyValue::cast(x.clone()) {
+            Some(kv) => {
+                let first = kv.key().and_then(|x| x.path().next());
+                !matches!(first, Some(x) if x.kind() == NODE_DYNAMIC)
+            }
+            None => x.kind() == NODE_INHERIT,
+        })
+        .map(|x| x.text().to_string())
+        .collect()
+}
+
+/// The start of a `let` binding `entries`, if there are any.
+fn let_block(entries: Vec<String>) -> Option<String> {
+    if entries.is_empty() {
+        None
+    } else {
+        Some(format!("let\n{}\nin (\n", entries.join("\n")))
+    }
+}
+
+fn throw(name: &str, message: &str) -> String {
+    format!("{} = throw \"{}\";", name, message)
+}
+
+/// Source that evaluates the expression `node` on its own: the `let`s,
+/// recursive sets and `with`s around it are written around it too. Function
+/// arguments aren't known, so using them is an error.
+pub fn closed_source(node: &SyntaxNode, globals: &EvaluationContext) -> String {
+    // An undefined variable anywhere would stop everything from compiling,
+    // rather than only what uses it.
+    let root = node.ancestors().last().unwrap_or_else(|| node.clone());
+    let mut undefined: Vec<_> = unbound(&root, globals)
+        .iter()
+        .map(|x| x.text().to_string())
+        .collect();
+    undefined.sort();
+    undefined.dedup();
+    let mut blocks = vec![let_block(
+        undefined
+            .iter()
+            .map(|x| throw(x, "undefined variable"))
+            .collect(),
+    )];
+    for scope in scopes(node).into_iter().rev() {
+        blocks.push(match scope {
+            Scope::Bindings(node, bindings) if node.kind() == NODE_LAMBDA => let_block(
+                bindings
+                    .iter()
+                    .map(|x| throw(&x.name, "the arguments of functions aren't known"))
+                    .collect(),
+            ),
+            Scope::Bindings(node, _) => let_block(let_entries(&node)),
+            Scope::With(namespace) => Some(format!("with (\n{}\n);\n(\n", namespace.text())),
+        });
+    }
+    // Newlines keep comments at the ends of lines from hiding what follows.
+    let blocks: Vec<_> = blocks.into_iter().flatten().collect();
+    format!(
+        "{}{}{}",
+        blocks.concat(),
+        node.text(),
+        "\n)".repeat(blocks.len())
+    )
+}
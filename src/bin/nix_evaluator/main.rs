@@ -0,0 +1,44 @@
+mod eval;
+mod lsp;
+mod print;
+mod repl;
+
+use color_eyre::eyre::{Report, Result};
+
+const USAGE: &str = "\
+usage: nix_evaluator [repl] [--debugger] [--profile FILE] [--stats]
+       nix_evaluator eval [options]
+       nix_evaluator lsp
+
+Run `nix_evaluator eval --help` for the options of `eval`.
+";
+
+/// Print `err` and its causes to stderr, on one line.
+fn report(err: &Report) {
+    let causes: Vec<String> = err.chain().map(ToString::to_string).collect();
+    eprintln!("error: {}", causes.join(": "));
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("eval") => {
+            args.remove(0);
+            std::process::exit(eval::main(args))
+        }
+        Some("lsp") => {
+            args.remove(0);
+            lsp::main(args)
+        }
+        Some("repl") => {
+            args.remove(0);
+            repl::main(args)
+        }
+        Some("-h") | Some("--help") => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        _ => repl::main(args),
+    }
+}
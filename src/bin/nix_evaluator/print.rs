@@ -0,0 +1,92 @@
+//! Printing values as Nix expressions, the way `nix-instantiate --eval` and
+//! `nix repl` do.
+
+use std::{
+    error::Error,
+    fmt::{self, Write},
+};
+
+use nix_evaluator::{string::NixString, value::Value};
+
+/// Whether `name` can be written as an attribute name without quotes.
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !matches!(
+            name,
+            "assert" | "else" | "if" | "in" | "inherit" | "let" | "or" | "rec" | "then" | "with"
+        )
+}
+
+/// `err` followed by its causes, on one line.
+fn error_message(err: &dyn Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        write!(message, ": {}", err).expect("writing to a string");
+        source = err.source();
+    }
+    message
+}
+
+/// Write `value` on a single line. If `force` is `None`, thunks that haven't
+/// been forced yet are written as `<CODE>`. Otherwise they're forced, and
+/// sets and lists nested deeper than `force` are elided.
+fn write_value(f: &mut impl Write, value: &Value, force: Option<usize>) -> fmt::Result {
+    let inner = force.map(|x| x.saturating_sub(1));
+    match value {
+        Value::Thunk(thunk) => match (thunk.evaluated(), force) {
+            (Some(x), _) => write_value(f, &x, force),
+            (None, None) => f.write_str("<CODE>"),
+            (None, Some(_)) => match value.to_owned().materialize() {
+                Ok(x) => write_value(f, &x, force),
+                Err(e) => write!(f, "«error: {}»", error_message(&e)),
+            },
+        },
+        Value::AttrSet(attrs) if force == Some(0) && !attrs.is_empty() => f.write_str("{ ... }"),
+        Value::AttrSet(attrs) => {
+            f.write_char('{')?;
+            for (name, value) in attrs.iter() {
+                f.write_char(' ')?;
+                if is_identifier(name.as_str()) {
+                    f.write_str(name.as_str())?;
+                } else {
+                    NixString::from(name.as_str()).write_quoted(f)?;
+                }
+                f.write_str(" = ")?;
+                write_value(f, value, inner)?;
+                f.write_char(';')?;
+            }
+            f.write_str(" }")
+        }
+        Value::List(list) if force == Some(0) && !list.is_empty() => f.write_str("[ ... ]"),
+        Value::List(list) => {
+            f.write_char('[')?;
+            for value in list.iter() {
+                f.write_char(' ')?;
+                write_value(f, value, inner)?;
+            }
+            f.write_str(" ]")
+        }
+        Value::Function(_, _) => f.write_str("<LAMBDA>"),
+        Value::PrimOp(_) => f.write_str("<PRIMOP>"),
+        Value::PrimOpApp(_, _) => f.write_str("<PRIMOP-APP>"),
+        value => write!(f, "{}", value),
+    }
+}
+
+/// Write `value` without forcing anything in it.
+pub fn to_string(value: &Value) -> String {
+    let mut res = String::new();
+    write_value(&mut res, value, None).expect("writing to a string");
+    res
+}
+
+/// Write `value`, forcing the sets and lists in it down to `depth` levels.
+/// Errors in them are written in place.
+pub fn to_string_forced(value: &Value, depth: usize) -> String {
+    let mut res = String::new();
+    write_value(&mut res, value, Some(depth)).expect("writing to a string");
+    res
+}
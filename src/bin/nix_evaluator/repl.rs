@@ -0,0 +1,494 @@
+use std::{
+    fmt::{self, Write},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use nix_evaluator::{
+    attrs::Attrs,
+    debugger::{Debugger, Frame, FrameKind, PauseReason, Paused, Resume},
+    embed::Evaluator,
+    evaluator::{EvaluationContext, Session},
+    limits::CancellationToken,
+    profiler::Profiler,
+    sync::{Lock, Rc},
+    value::Value,
+};
+use rnix::{parse, TextRange};
+use rustyline::{error::ReadlineError, Editor};
+
+use crate::{print, report};
+
+mod helper;
+
+/// Describe a value without forcing it, since that could fail or pause again.
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Thunk(thunk) => match thunk.evaluated() {
+            Some(x) => describe(&x),
+            None => "«thunk»".into(),
+        },
+        value => {
+            let mut res = String::new();
+            match write!(res, "{}", value) {
+                Ok(()) => res,
+                Err(_) => "«error»".into(),
+            }
+        }
+    }
+}
+
+/// The debugger's prompt, used when the REPL is started with `--debugger`.
+struct ReplDebugger {
+    context: EvaluationContext,
+    /// The line being evaluated, which frame spans refer to.
+    source: Lock<String>,
+}
+
+impl ReplDebugger {
+    fn location(&self, span: Option<TextRange>) -> String {
+        let source = self.source.lock();
+        match span.and_then(|x| source.get(std::ops::Range::<usize>::from(x))) {
+            Some(text) => format!("{:?}: {}", span.unwrap(), text),
+            None => "unknown location".into(),
+        }
+    }
+
+    fn show_frame(&self, index: usize, frame: &Frame) {
+        let kind = match frame.kind() {
+            FrameKind::Apply => "apply",
+            FrameKind::Force => "force",
+        };
+        println!("#{} {} at {}", index, kind, self.location(frame.span()));
+    }
+}
+
+impl Debugger for ReplDebugger {
+    fn pause(&self, paused: &Paused<'_>) -> Resume {
+        match &paused.reason {
+            PauseReason::Break => println!("breakpoint reached"),
+            PauseReason::Error(err) => println!("error: {}", err),
+            PauseReason::Step => {}
+        }
+        let mut selected = 0;
+        if let Some(frame) = paused.frames.first() {
+            self.show_frame(0, frame);
+        }
+        let mut rl = Editor::<()>::new();
+        loop {
+            let line = match rl.readline("debug> ") {
+                Ok(x) => x,
+                Err(_) => return Resume::Continue,
+            };
+            let line = line.trim();
+            let frame = paused.frames.get(selected);
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [] => {}
+                [":c"] | [":continue"] => return Resume::Continue,
+                [":s"] | [":step"] => return Resume::Step,
+                [":bt"] | [":backtrace"] => {
+                    for (i, frame) in paused.frames.iter().enumerate() {
+                        self.show_frame(i, frame);
+                    }
+                }
+                [":st", n] => match n.parse::<usize>() {
+                    Ok(n) if n < paused.frames.len() => {
+                        selected = n;
+                        self.show_frame(n, &paused.frames[n]);
+                    }
+                    _ => println!("no such frame"),
+                },
+                [":env"] => {
+                    for (name, value) in frame.map(Frame::variables).unwrap_or_default() {
+                        println!("{} = {}", name, describe(&value));
+                    }
+                }
+                [cmd, ..] if cmd.starts_with(':') => println!(
+                    ":c  continue\n\
+                     :s  step to the next frame\n\
+                     :bt  show the frames being evaluated\n\
+                     :st <n>  select frame n\n\
+                     :env  show the variables in the selected frame\n\
+                     <expr>  evaluate an expression in the selected frame"
+                ),
+                _ => {
+                    let ast = parse(line);
+                    let res = match (ast.as_result(), frame) {
+                        (Err(err), _) => Err(err.to_string()),
+                        (Ok(ast), Some(frame)) => frame
+                            .eval(ast.node(), &self.context)
+                            .and_then(Value::materialize_deep)
+                            .map_err(|x| x.to_string()),
+                        (Ok(_), None) => Err("no frame to evaluate in".into()),
+                    };
+                    match res {
+                        Ok(x) => println!("{}", describe(&x)),
+                        Err(x) => println!("error: {}", x),
+                    }
+                }
+            }
+        }
+    }
+}
+
+const HELP: &str = "\
+The following commands are available:
+
+  <expr>                Evaluate and print an expression
+  <x> = <expr>          Bind a variable to an expression
+  :a <expr>             Add the attributes of a set to scope
+  :doc <expr>           Show the documentation of a builtin
+  :l <path>             Load the attribute set in a Nix file into scope
+  :load-session <path>  Redo the definitions saved in a file by :save
+  :p <expr>             Evaluate and print an expression deeply
+  :q                    Exit
+  :r                    Reload all files and redo every definition
+  :save <path>          Save the definitions made so far to a file
+  :t <expr>             Describe the type of an expression
+  :?                    Show this help
+
+Results are bound to _1, _2 and so on, and the last one to _.";
+
+/// Split a line into its first word, the command, and the rest, its
+/// argument.
+fn split_command(line: &str) -> (&str, &str) {
+    match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Split a line of the form `name = expr` into the name and the expression.
+fn split_binding(line: &str) -> Option<(&str, &str)> {
+    let end = line.find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-')))?;
+    let (name, rest) = line.split_at(end);
+    let expr = rest.trim_start().strip_prefix('=')?;
+    if expr.starts_with('=') || !print::is_identifier(name) {
+        return None;
+    }
+    Some((name, expr.trim_start()))
+}
+
+/// Where the REPL's history is kept between runs.
+fn history_path() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("nix_evaluator").join("history"))
+}
+
+/// What to do after a line of input.
+enum Control {
+    Continue,
+    Quit,
+}
+
+/// A change to the scope, which `:r` redoes and `:save` writes out.
+enum Definition {
+    /// `name = expr`
+    Bind(String, String),
+    /// `:a expr`
+    Add(String),
+    /// `:l path`
+    Load(PathBuf),
+}
+
+impl Definition {
+    /// Read a definition written by [`Repl::save`].
+    fn from_value(value: &Value) -> Option<Self> {
+        let attrs = match value {
+            Value::AttrSet(x) => x,
+            _ => return None,
+        };
+        let string = |name: &str| match attrs.get(name)? {
+            Value::String(x) => Some(x.to_string_lossy().into_owned()),
+            _ => None,
+        };
+        if let Some(path) = string("load") {
+            Some(Definition::Load(path.into()))
+        } else if let Some(expr) = string("add") {
+            Some(Definition::Add(expr))
+        } else {
+            Some(Definition::Bind(string("name")?, string("value")?))
+        }
+    }
+
+    fn write(&self, out: &mut String) -> fmt::Result {
+        let quote = |x: &str| print::to_string(&Value::String(x.into()));
+        match self {
+            Definition::Bind(name, expr) => writeln!(
+                out,
+                "  {{ name = {}; value = {}; }}",
+                quote(name),
+                quote(expr)
+            ),
+            Definition::Add(expr) => writeln!(out, "  {{ add = {}; }}", quote(expr)),
+            Definition::Load(path) => {
+                writeln!(out, "  {{ load = {}; }}", quote(&path.to_string_lossy()))
+            }
+        }
+    }
+}
+
+struct Repl {
+    evaluator: Evaluator,
+    /// The variables defined so far, and the results.
+    scope: Attrs,
+    /// What defined the variables in scope, in order.
+    definitions: Vec<Definition>,
+    /// How many results there have been.
+    results: usize,
+}
+
+impl Repl {
+    fn eval(&self, source: &str) -> Result<Value> {
+        Ok(self
+            .evaluator
+            .eval_str_in(source, &self.scope)?
+            .materialize()?)
+    }
+
+    /// Add the attributes of `value` to the scope.
+    fn add(&mut self, value: Value) -> Result<()> {
+        match value.materialize()? {
+            Value::AttrSet(attrs) => {
+                println!("Added {} variables.", attrs.len());
+                self.scope = self.scope.update(&attrs);
+                Ok(())
+            }
+            x => Err(eyre!(
+                "expected an attribute set, but found {}",
+                x.human_readable_type()
+            )),
+        }
+    }
+
+    /// Load the file at `path` into scope, calling it first if it's a
+    /// function taking a set, like `nix repl` does.
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let value = self.evaluator.eval_file(path)?;
+        let value = self.evaluator.auto_call(value, &Attrs::new())?;
+        self.add(value)
+    }
+
+    fn define(&mut self, definition: Definition) -> Result<()> {
+        self.apply(&definition)?;
+        self.definitions.push(definition);
+        Ok(())
+    }
+
+    fn apply(&mut self, definition: &Definition) -> Result<()> {
+        match definition {
+            // Bindings are lazy, like those of a `let`.
+            Definition::Bind(name, expr) => {
+                let value = self.evaluator.defer_str_in(expr, &self.scope)?;
+                self.scope.insert_mut(name.as_str().into(), value);
+            }
+            Definition::Add(expr) => {
+                let value = self.eval(expr)?;
+                self.add(value)?;
+            }
+            Definition::Load(path) => self.load(path)?,
+        }
+        Ok(())
+    }
+
+    /// Write the definitions made so far to `path`, as a list that
+    /// `:load-session` reads back.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut out = String::from(
+            "# Definitions from a nix_evaluator REPL session, in the order they were\n\
+             # made. Redo them with `:load-session`.\n[\n",
+        );
+        for definition in &self.definitions {
+            definition.write(&mut out)?;
+        }
+        out.push_str("]\n");
+        fs::write(path, out).wrap_err_with(|| format!("failed to write {}", path.display()))
+    }
+
+    fn load_session(&mut self, path: &Path) -> Result<()> {
+        let source = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read {}", path.display()))?;
+        let definitions = match self.evaluator.eval_str(&source)?.materialize_deep()? {
+            Value::List(x) => x,
+            x => {
+                return Err(eyre!(
+                    "expected a list of definitions, but found {}",
+                    x.human_readable_type()
+                ))
+            }
+        };
+        for value in definitions.iter() {
+            let definition = Definition::from_value(value)
+                .ok_or_else(|| eyre!("invalid definition in {}", path.display()))?;
+            self.define(definition)?;
+        }
+        Ok(())
+    }
+
+    fn doc(&self, value: Value) -> Result<()> {
+        let op = match value {
+            Value::PrimOp(op) | Value::PrimOpApp(op, _) => op,
+            x => {
+                return Err(eyre!(
+                    "only builtins have documentation, not a {}",
+                    x.human_readable_type()
+                ))
+            }
+        };
+        let args: Vec<_> = op.args().iter().map(|x| x.name).collect();
+        println!("Synopsis: builtins.{} {}\n", op.name(), args.join(" "));
+        for line in op.doc().lines() {
+            if line.is_empty() {
+                println!();
+            } else {
+                println!("    {}", line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a line of input: a command, a binding, or an expression to
+    /// evaluate.
+    fn run(&mut self, line: &str) -> Result<Control> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(Control::Continue);
+        }
+        if let Some((name, expr)) = split_binding(line) {
+            self.define(Definition::Bind(name.into(), expr.into()))?;
+            return Ok(Control::Continue);
+        }
+        if !line.starts_with(':') {
+            let value = self.eval(line)?;
+            println!("{}", print::to_string_forced(&value, 1));
+            self.results += 1;
+            let name = format!("_{}", self.results);
+            self.scope.insert_mut(name.as_str().into(), value.clone());
+            self.scope.insert_mut("_".into(), value);
+            return Ok(Control::Continue);
+        }
+        let (command, arg) = split_command(line);
+        match command {
+            ":a" | ":add" => self.define(Definition::Add(arg.into()))?,
+            ":doc" => self.doc(self.eval(arg)?)?,
+            ":l" | ":load" => {
+                // Saved sessions can be loaded from anywhere.
+                let path = fs::canonicalize(arg).unwrap_or_else(|_| arg.into());
+                self.define(Definition::Load(path))?
+            }
+            ":load-session" => self.load_session(Path::new(arg))?,
+            ":p" | ":print" => {
+                println!("{}", print::to_string_forced(&self.eval(arg)?, usize::MAX));
+            }
+            ":q" | ":quit" => return Ok(Control::Quit),
+            ":r" | ":reload" => {
+                self.evaluator.forget_files();
+                self.scope = Attrs::new();
+                // Keep going after errors, and keep the definitions that
+                // failed, so that fixing a file and reloading again works.
+                let definitions = std::mem::take(&mut self.definitions);
+                for definition in &definitions {
+                    if let Err(e) = self.apply(definition) {
+                        report(&e);
+                    }
+                }
+                self.definitions = definitions;
+            }
+            ":save" => self.save(Path::new(arg))?,
+            ":t" | ":type" => println!("{}", self.eval(arg)?.human_readable_type()),
+            ":?" | ":help" => println!("{}", HELP),
+            _ => return Err(eyre!("unknown command '{}', see :? for help", command)),
+        }
+        Ok(Control::Continue)
+    }
+}
+
+/// Read and evaluate expressions interactively.
+pub fn main(args: Vec<String>) -> Result<()> {
+    let debug = args.iter().any(|x| x == "--debugger");
+    // Collapsed stacks are written to this file after each evaluation.
+    let profile = args
+        .iter()
+        .position(|x| x == "--profile")
+        .and_then(|i| args.get(i + 1));
+    let profiler = Rc::new(Profiler::new());
+    // Like `NIX_SHOW_STATS`, print statistics to stderr after each evaluation.
+    let show_stats = args.iter().any(|x| x == "--stats");
+    println!("nix_evaluator version 0.0.0");
+    println!("enter Nix expressions, and the evaluation result will be printed");
+    println!("type :? for help");
+    let debugger = Rc::new(ReplDebugger {
+        context: EvaluationContext::new(),
+        source: Lock::default(),
+    });
+    // Ctrl-C at the prompt is handled by the editor, so this only stops
+    // evaluation.
+    let cancellation = CancellationToken::new();
+    let token = cancellation.clone();
+    ctrlc::set_handler(move || token.cancel())?;
+    // Everything is evaluated in one session, so that what's added to scope
+    // stays usable.
+    let mut session = Session::new().with_cancellation(cancellation.clone());
+    if debug {
+        session = session.with_debugger(debugger.clone());
+    }
+    if profile.is_some() {
+        session = session.with_profiler(profiler.clone());
+    }
+    if show_stats {
+        session = session.with_stats();
+    }
+    let mut rl = Editor::<Repl>::new();
+    rl.set_helper(Some(Repl {
+        evaluator: Evaluator::builder().session(session).build(),
+        scope: Attrs::new(),
+        definitions: Vec::new(),
+        results: 0,
+    }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There's no history the first time.
+        let _ = rl.load_history(path);
+    }
+    loop {
+        let line = match rl.readline("> ") {
+            Ok(x) => x,
+            // Ctrl-C discards the line being typed, and Ctrl-D exits.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        rl.add_history_entry(line.as_str());
+        if let Some(path) = &history {
+            // Losing the history isn't worth interrupting the session for.
+            let _ = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|()| {
+                    rl.save_history(path)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                });
+        }
+        *debugger.source.lock() = line.clone();
+        let repl = rl.helper_mut().expect("the REPL is the editor's helper");
+        cancellation.reset();
+        let control = match repl.run(&line) {
+            Ok(x) => x,
+            Err(e) => {
+                report(&e);
+                Control::Continue
+            }
+        };
+        if let Some(path) = profile {
+            profiler.write_collapsed(File::create(path)?)?;
+            profiler.write_summary(std::io::stderr(), 10)?;
+        }
+        if let Some(stats) = repl.evaluator.session().stats() {
+            eprintln!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        if let Control::Quit = control {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,408 @@
+//! Completion, highlighting, hints and multi-line input for the REPL.
+
+use std::borrow::Cow;
+
+use nix_evaluator::value::Value;
+use rnix::{parser::ParseError, tokenizer::Tokenizer, SyntaxKind, SyntaxKind::*};
+use rustyline::{
+    completion::{Completer, FilenameCompleter, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::{ValidationContext, ValidationResult, Validator},
+    Context, Helper,
+};
+
+use super::{split_binding, split_command, Repl};
+
+const COMMANDS: &[&str] = &[
+    ":a",
+    ":add",
+    ":doc",
+    ":l",
+    ":load",
+    ":load-session",
+    ":p",
+    ":print",
+    ":q",
+    ":quit",
+    ":r",
+    ":reload",
+    ":save",
+    ":t",
+    ":type",
+    ":?",
+    ":help",
+];
+
+/// The commands whose argument is an expression.
+const EXPR_COMMANDS: &[&str] = &[":a", ":add", ":doc", ":p", ":print", ":t", ":type"];
+
+const KEYWORDS: &[&str] = &[
+    "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+];
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const GREY: &str = "\x1b[90m";
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-' | '.')
+}
+
+/// Where the identifier or attribute path that ends at `pos` starts.
+fn word_start(line: &str, pos: usize) -> usize {
+    line[..pos]
+        .char_indices()
+        .rev()
+        .find(|(_, c)| !is_word_char(*c))
+        .map_or(0, |(i, c)| i + c.len_utf8())
+}
+
+fn color(kind: SyntaxKind, text: &str) -> Option<&'static str> {
+    match kind {
+        TOKEN_ASSERT | TOKEN_ELSE | TOKEN_IF | TOKEN_IN | TOKEN_INHERIT | TOKEN_LET | TOKEN_OR
+        | TOKEN_REC | TOKEN_THEN | TOKEN_WITH => Some("\x1b[35m"),
+        TOKEN_INTEGER | TOKEN_FLOAT => Some("\x1b[36m"),
+        TOKEN_IDENT if matches!(text, "true" | "false" | "null") => Some("\x1b[36m"),
+        TOKEN_STRING_START | TOKEN_STRING_CONTENT | TOKEN_STRING_END => Some("\x1b[32m"),
+        TOKEN_PATH | TOKEN_URI => Some("\x1b[33m"),
+        TOKEN_COMMENT => Some(GREY),
+        _ => None,
+    }
+}
+
+/// Color the tokens of the Nix code in `source`.
+fn highlight_nix(source: &str) -> Option<String> {
+    let mut res = String::with_capacity(source.len());
+    let mut len = 0;
+    for (kind, text) in Tokenizer::new(source) {
+        len += text.len();
+        match color(kind, &text) {
+            Some(color) => {
+                res.push_str(color);
+                res.push_str(&text);
+                res.push_str(RESET);
+            }
+            None => res.push_str(&text),
+        }
+    }
+    // The tokens should cover the whole input, but leave it alone if not.
+    if len == source.len() {
+        Some(res)
+    } else {
+        None
+    }
+}
+
+impl Repl {
+    /// The completions of `word`, an identifier or an attribute path whose
+    /// last name is partial.
+    fn completions(&self, word: &str) -> Vec<String> {
+        let mut res: Vec<String> = match word.rfind('.') {
+            Some(i) => {
+                let (path, partial) = (&word[..i], &word[i + 1..]);
+                let attrs = match self.lookup(path) {
+                    Some(Value::AttrSet(x)) => x,
+                    _ => return Vec::new(),
+                };
+                attrs
+                    .keys()
+                    .map(|x| x.as_str())
+                    .filter(|x| x.starts_with(partial) && x.chars().all(is_word_char))
+                    .map(|x| format!("{}.{}", path, x))
+                    .collect()
+            }
+            None => self
+                .scope
+                .keys()
+                .map(|x| x.as_str())
+                .chain(self.evaluator.globals().names())
+                .chain(KEYWORDS.iter().copied())
+                .filter(|x| x.starts_with(word) && !x.starts_with("__"))
+                .map(ToOwned::to_owned)
+                .collect(),
+        };
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    /// The value at an attribute path like `pkgs.lib` in scope, forcing only
+    /// the sets along the way.
+    fn lookup(&self, path: &str) -> Option<Value> {
+        let mut names = path.split('.');
+        let first = names.next()?;
+        let mut value = self
+            .scope
+            .get(first)
+            .or_else(|| self.evaluator.globals().get(first))?
+            .to_owned();
+        for name in names {
+            value = match value.materialize().ok()? {
+                Value::AttrSet(attrs) => attrs.get(name)?.to_owned(),
+                _ => return None,
+            };
+        }
+        value.materialize().ok()
+    }
+}
+
+impl Completer for Repl {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (command, _) = split_command(line);
+        if line.starts_with(':') && pos <= command.len() {
+            let pairs = COMMANDS
+                .iter()
+                .filter(|x| x.starts_with(&line[..pos]))
+                .map(|x| Pair {
+                    display: x.to_string(),
+                    replacement: x.to_string(),
+                })
+                .collect();
+            return Ok((0, pairs));
+        }
+        if matches!(command, ":l" | ":load" | ":load-session" | ":save") {
+            return FilenameCompleter::new().complete(line, pos, ctx);
+        }
+        let start = word_start(line, pos);
+        let pairs = self
+            .completions(&line[start..pos])
+            .into_iter()
+            .map(|x| Pair {
+                display: x.rsplit('.').next().unwrap_or_default().to_owned(),
+                replacement: x,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for Repl {
+    type Hint = String;
+
+    /// Hint the rest of the identifier being typed when only one variable
+    /// completes it. Attribute paths aren't hinted, since that would mean
+    /// evaluating them as they're typed.
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() || line.starts_with(':') {
+            return None;
+        }
+        let word = &line[word_start(line, pos)..pos];
+        if word.is_empty() || word.contains('.') {
+            return None;
+        }
+        match self.completions(word).as_slice() {
+            [name] => Some(name[word.len()..].to_owned()),
+            _ => None,
+        }
+    }
+}
+
+impl Highlighter for Repl {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let (command, arg) = split_command(line);
+        let res = if !line.starts_with(':') {
+            highlight_nix(line)
+        } else if EXPR_COMMANDS.contains(&command) {
+            let space = &line[command.len()..line.len() - arg.len()];
+            highlight_nix(arg).map(|arg| format!("{}{}{}{}{}", BOLD, command, RESET, space, arg))
+        } else {
+            Some(format!(
+                "{}{}{}{}",
+                BOLD,
+                command,
+                RESET,
+                &line[command.len()..]
+            ))
+        };
+        res.map_or(Cow::Borrowed(line), Cow::Owned)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("{}{}{}", GREY, hint, RESET))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Validator for Repl {
+    /// Continue on the next line while the expression is unfinished, such as
+    /// when a bracket or string hasn't been closed yet.
+    fn validate(&self, ctx: &mut ValidationContext<'_>) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        let (command, arg) = split_command(input);
+        let source = if let Some((_, expr)) = split_binding(input) {
+            expr
+        } else if !input.starts_with(':') {
+            input
+        } else if EXPR_COMMANDS.contains(&command) {
+            arg
+        } else {
+            return Ok(ValidationResult::Valid(None));
+        };
+        let incomplete = !source.trim().is_empty()
+            && rnix::parse(source).errors().iter().any(|x| {
+                matches!(
+                    x,
+                    ParseError::UnexpectedEOF | ParseError::UnexpectedEOFWanted(_)
+                )
+            });
+        if incomplete {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for Repl {}
+
+#[cfg(test)]
+mod tests {
+    use nix_evaluator::embed::Evaluator;
+    use rustyline::history::History;
+
+    use super::*;
+
+    fn repl() -> Repl {
+        let evaluator = Evaluator::new();
+        let scope = match evaluator
+            .eval_str(
+                r#"{
+                  pkgs = {
+                    hello = 1; help = 2; "has space" = 3;
+                    broken = throw "not forced";
+                    lib = { id = x: x; };
+                  };
+                  pkgx = 1;
+                  __secret = 2;
+                }"#,
+            )
+            .and_then(Value::materialize)
+            .unwrap()
+        {
+            Value::AttrSet(x) => x,
+            x => panic!("not a set: {}", x),
+        };
+        Repl {
+            evaluator,
+            scope,
+            definitions: Vec::new(),
+            results: 0,
+        }
+    }
+
+    fn complete(repl: &Repl, line: &str) -> (usize, Vec<(String, String)>) {
+        let history = History::new();
+        let (start, pairs) = repl
+            .complete(line, line.len(), &Context::new(&history))
+            .unwrap();
+        let pairs = pairs
+            .into_iter()
+            .map(|x| (x.display, x.replacement))
+            .collect();
+        (start, pairs)
+    }
+
+    fn hint(repl: &Repl, line: &str) -> Option<String> {
+        let history = History::new();
+        repl.hint(line, line.len(), &Context::new(&history))
+    }
+
+    #[test]
+    fn names_complete_from_scope_globals_and_keywords() {
+        let repl = repl();
+        assert_eq!(repl.completions("pk"), ["pkgs", "pkgx"]);
+        assert_eq!(repl.completions("builtins"), ["builtins"]);
+        assert_eq!(repl.completions("th"), ["then", "throw"]);
+        assert_eq!(repl.completions("__"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn attribute_paths_complete_without_forcing_other_attributes() {
+        let repl = repl();
+        assert_eq!(repl.completions("pkgs.he"), ["pkgs.hello", "pkgs.help"]);
+        // `broken` isn't forced, and names that can't be typed are left out.
+        assert_eq!(
+            repl.completions("pkgs."),
+            ["pkgs.broken", "pkgs.hello", "pkgs.help", "pkgs.lib"]
+        );
+        assert_eq!(repl.completions("pkgs.lib.i"), ["pkgs.lib.id"]);
+        assert!(repl
+            .completions("builtins.ma")
+            .contains(&"builtins.map".into()));
+        assert_eq!(repl.completions("pkgs.hello."), Vec::<String>::new());
+        assert_eq!(repl.completions("pkgs.broken."), Vec::<String>::new());
+        assert_eq!(repl.completions("nothing."), Vec::<String>::new());
+    }
+
+    #[test]
+    fn completion_replaces_the_word_being_typed() {
+        let repl = repl();
+        assert_eq!(
+            complete(&repl, ":lo"),
+            (
+                0,
+                vec![
+                    (":load".into(), ":load".into()),
+                    (":load-session".into(), ":load-session".into()),
+                ]
+            )
+        );
+        assert_eq!(
+            complete(&repl, "1 + pkgs.hel"),
+            (
+                4,
+                vec![
+                    ("hello".into(), "pkgs.hello".into()),
+                    ("help".into(), "pkgs.help".into()),
+                ]
+            )
+        );
+        assert_eq!(
+            complete(&repl, ":p pkgx"),
+            (3, vec![("pkgx".into(), "pkgx".into())])
+        );
+    }
+
+    #[test]
+    fn unique_names_are_hinted() {
+        let repl = repl();
+        assert_eq!(hint(&repl, "builti"), Some("ns".into()));
+        assert_eq!(hint(&repl, "1 + __sec"), None);
+        assert_eq!(hint(&repl, "pk"), None);
+        // Paths would have to be evaluated, and commands aren't Nix.
+        assert_eq!(hint(&repl, "pkgs.lib.i"), None);
+        assert_eq!(hint(&repl, ":p builti"), None);
+        assert_eq!(
+            repl.hint("builti x", 6, &Context::new(&History::new())),
+            None
+        );
+    }
+
+    #[test]
+    fn input_is_highlighted() {
+        let repl = repl();
+        assert_eq!(
+            repl.highlight(r#"if true then 1 else "a""#, 0),
+            "\x1b[35mif\x1b[0m \x1b[36mtrue\x1b[0m \x1b[35mthen\x1b[0m \
+             \x1b[36m1\x1b[0m \x1b[35melse\x1b[0m \
+             \x1b[32m\"\x1b[0m\x1b[32ma\x1b[0m\x1b[32m\"\x1b[0m"
+        );
+        assert_eq!(
+            repl.highlight(":t  ./a # b", 0),
+            "\x1b[1m:t\x1b[0m  \x1b[33m./a\x1b[0m \x1b[90m# b\x1b[0m"
+        );
+        assert_eq!(repl.highlight(":l ./a", 0), "\x1b[1m:l\x1b[0m ./a");
+        assert_eq!(repl.highlight_hint("ns"), "\x1b[90mns\x1b[0m");
+    }
+}
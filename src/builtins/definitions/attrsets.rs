@@ -1,17 +1,15 @@
-use std::rc::Rc;
-
-use rpds::{HashTrieMap, Vector};
-
 use crate::{
+    attrs::Attrs,
     builtins::{mismatch, BuiltinError, Result},
+    symbol::Symbol,
+    sync::Vector,
     value::Value,
 };
 
 pub fn attr_names(set: Value) -> Result {
-    let set = set.materialize()?;
     if let Value::AttrSet(set) = set {
         Ok(Value::List(
-            set.keys().map(|k| Value::String(k.to_owned())).collect(),
+            set.keys().map(|k| Value::from(k.to_owned())).collect(),
         ))
     } else {
         mismatch("attribute set", set)
@@ -19,7 +17,6 @@ pub fn attr_names(set: Value) -> Result {
 }
 
 pub fn attr_values(set: Value) -> Result {
-    let set = set.materialize()?;
     if let Value::AttrSet(set) = set {
         Ok(Value::List(set.values().map(|v| v.to_owned()).collect()))
     } else {
@@ -27,91 +24,64 @@ pub fn attr_values(set: Value) -> Result {
     }
 }
 
-pub fn cat_attrs(attr: Value) -> Result {
-    let attr = attr.materialize()?;
-    if let Value::String(attr) = attr {
-        Ok(Value::BuiltinFunction(Rc::new(move |list| {
-            let list = list.materialize()?;
-            if let Value::List(list) = list {
-                let mut result = Vector::new();
-                for x in list.iter() {
-                    let x = x.to_owned().materialize()?;
-                    if let Value::AttrSet(set) = x {
-                        if let Some(v) = set.get(&attr) {
-                            result.push_back_mut(v.to_owned());
-                        }
-                    } else {
-                        return mismatch("attribute set", x.to_owned());
+pub fn cat_attrs(attr: Value, list: Value) -> Result {
+    match (attr, list) {
+        (Value::String(attr), Value::List(list)) => {
+            let mut result = Vector::default();
+            for x in list.iter() {
+                let x = x.to_owned().materialize()?;
+                if let Value::AttrSet(set) = x {
+                    if let Some(v) = set.get(attr.as_bytes()) {
+                        result.push_back_mut(v.to_owned());
                     }
+                } else {
+                    return mismatch("attribute set", x.to_owned());
                 }
-                Ok(Value::List(result))
-            } else {
-                mismatch("list", list)
             }
-        })))
-    } else {
-        mismatch("string", attr)
+            Ok(Value::List(result))
+        }
+        (Value::String(_), list) => mismatch("list", list),
+        (attr, _) => mismatch("string", attr),
     }
 }
 
-pub fn get_attr(s: Value) -> Result {
-    let s = s.materialize()?;
-    if let Value::String(s) = s {
-        Ok(Value::BuiltinFunction(Rc::new(move |set| {
-            let set = set.materialize()?;
-            if let Value::AttrSet(set) = set {
-                Ok(set.get(&s).map_or(Value::Null, ToOwned::to_owned))
-            } else {
-                mismatch("attribute set", set)
-            }
-        })))
-    } else {
-        mismatch("string", s)
+pub fn get_attr(s: Value, set: Value) -> Result {
+    match (s, set) {
+        (Value::String(s), Value::AttrSet(set)) => {
+            Ok(set.get(s.as_bytes()).map_or(Value::Null, ToOwned::to_owned))
+        }
+        (Value::String(_), set) => mismatch("attribute set", set),
+        (s, _) => mismatch("string", s),
     }
 }
 
-pub fn has_attr(s: Value) -> Result {
-    let s = s.materialize()?;
-    if let Value::String(s) = s {
-        Ok(Value::BuiltinFunction(Rc::new(move |set| {
-            let set = set.materialize()?;
-            if let Value::AttrSet(set) = set {
-                Ok(set.contains_key(&s).into())
-            } else {
-                mismatch("attribute set", set)
-            }
-        })))
-    } else {
-        mismatch("string", s)
+pub fn has_attr(s: Value, set: Value) -> Result {
+    match (s, set) {
+        (Value::String(s), Value::AttrSet(set)) => Ok(set.contains_key(s.as_bytes()).into()),
+        (Value::String(_), set) => mismatch("attribute set", set),
+        (s, _) => mismatch("string", s),
     }
 }
 
-pub fn intersect_attrs(e1: Value) -> Result {
-    let e1 = e1.materialize()?;
-    if let Value::AttrSet(e1) = e1 {
-        Ok(Value::BuiltinFunction(Rc::new(move |e2| {
-            let e2 = e2.materialize()?;
-            if let Value::AttrSet(e2) = e2 {
-                let mut res = e2.clone();
-                for (k, _) in e2.iter() {
-                    if !e1.contains_key(k) {
-                        res.remove_mut(k);
-                    }
+pub fn intersect_attrs(e1: Value, e2: Value) -> Result {
+    match (e1, e2) {
+        (Value::AttrSet(e1), Value::AttrSet(e2)) => {
+            let mut res = e2.clone();
+            for (k, _) in e2.iter() {
+                if !e1.contains_key(k) {
+                    res.remove_mut(k);
                 }
-                Ok(Value::AttrSet(res))
-            } else {
-                mismatch("attribute set", e2)
             }
-        })))
-    } else {
-        mismatch("attribute set", e1)
+            Ok(Value::AttrSet(res))
+        }
+        (Value::AttrSet(_), e2) => mismatch("attribute set", e2),
+        (e1, _) => mismatch("attribute set", e1),
     }
 }
 
 pub fn list_to_attrs(e: Value) -> Result {
-    let e = e.materialize()?;
     if let Value::List(e) = e {
-        let mut attrs = HashTrieMap::new();
+        let mut attrs = Attrs::new();
         for v in e.iter() {
             let v = v.to_owned().materialize()?;
             if let Value::AttrSet(v) = v {
@@ -119,7 +89,7 @@ pub fn list_to_attrs(e: Value) -> Result {
                     let name = name.to_owned().materialize()?;
                     if let Value::String(name) = name {
                         if let Some(value) = v.get("value") {
-                            attrs.insert_mut(name.to_string(), value.to_owned());
+                            attrs.insert_mut(Symbol::from_bytes(&name), value.to_owned());
                         } else {
                             return Err(BuiltinError::MissingAttr("value".into()).into());
                         }
@@ -139,29 +109,21 @@ pub fn list_to_attrs(e: Value) -> Result {
     }
 }
 
-pub fn remove_attrs(set: Value) -> Result {
-    let set = set.materialize()?;
-    if let Value::AttrSet(set) = set {
-        Ok(Value::BuiltinFunction(Rc::new(move |list| {
-            let list = list.materialize()?;
-            if let Value::List(list) = list {
-                let mut new_set = set.clone();
-                for remove in list.iter() {
-                    let remove = remove.to_owned().materialize()?;
-                    if let Value::String(remove) = remove {
-                        if new_set.contains_key(&remove) {
-                            new_set.remove_mut(&remove);
-                        }
-                    } else {
-                        return mismatch("string", remove.to_owned());
-                    }
+pub fn remove_attrs(set: Value, list: Value) -> Result {
+    match (set, list) {
+        (Value::AttrSet(set), Value::List(list)) => {
+            let mut new_set = set;
+            for remove in list.iter() {
+                let remove = remove.to_owned().materialize()?;
+                if let Value::String(remove) = remove {
+                    new_set.remove_mut(remove.as_bytes());
+                } else {
+                    return mismatch("string", remove.to_owned());
                 }
-                Ok(Value::AttrSet(new_set))
-            } else {
-                mismatch("list", list)
             }
-        })))
-    } else {
-        mismatch("attribute set", set)
+            Ok(Value::AttrSet(new_set))
+        }
+        (Value::AttrSet(_), list) => mismatch("list", list),
+        (set, _) => mismatch("attribute set", set),
     }
 }
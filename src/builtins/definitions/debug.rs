@@ -1,23 +1,88 @@
-use std::rc::Rc;
-
 use crate::{
-    builtins::{nyi, BuiltinError, Result},
+    attrs::Attrs,
+    builtins::{mismatch, BuiltinError, Result},
+    debugger,
+    diagnostics::{Diagnostic, DiagnosticKind},
+    evaluator::{self, EvalError},
     value::Value,
 };
 
+fn report(kind: DiagnosticKind, message: String) -> std::result::Result<(), EvalError> {
+    let site = evaluator::call_site();
+    evaluator::diagnostics().report(Diagnostic {
+        kind,
+        message,
+        span: site.as_ref().map(|x| x.span),
+        location: site.map(|x| x.location),
+    })
+}
+
+/// Strings are traced as they are, and anything else as Nix code. The value
+/// is forced deeply first, so that an error in it is returned rather than
+/// failing to format it.
+fn trace_message(e: Value) -> std::result::Result<String, EvalError> {
+    Ok(match e.materialize_deep()? {
+        Value::String(s) => s.to_string_lossy().into_owned(),
+        e => e.to_string(),
+    })
+}
+
 pub fn abort(s: Value) -> Result {
-    Err(BuiltinError::Aborted(s.materialize()?.to_string()).into())
+    let s = s.coerce_to_string()?;
+    Err(BuiltinError::Aborted(s.to_string_lossy().into_owned()).into())
+}
+
+pub fn add_error_context(ctx: Value, e: Value) -> Result {
+    e.materialize().map_err(|err| match ctx.materialize() {
+        Ok(Value::String(ctx)) => {
+            EvalError::Context(ctx.to_string_lossy().into_owned().into(), Box::new(err))
+        }
+        // A context that can't be shown shouldn't hide the original error.
+        _ => err,
+    })
+}
+
+pub fn breakpoint(v: Value) -> Result {
+    debugger::breakpoint();
+    Ok(v)
 }
 
 pub fn throw(s: Value) -> Result {
-    Err(BuiltinError::Thrown(s.materialize()?.to_string()).into())
+    let s = s.coerce_to_string()?;
+    Err(BuiltinError::Thrown(s.to_string_lossy().into_owned()).into())
+}
+
+pub fn trace(e1: Value, e2: Value) -> Result {
+    report(DiagnosticKind::Trace, trace_message(e1)?)?;
+    Ok(e2)
+}
+
+pub fn trace_verbose(e1: Value, e2: Value) -> Result {
+    if evaluator::diagnostics().trace_verbose_enabled() {
+        report(DiagnosticKind::VerboseTrace, trace_message(e1)?)?;
+    }
+    Ok(e2)
 }
 
-pub fn trace(e1: Value) -> Result {
-    eprintln!("trace: {:?}", e1.materialize()?);
-    Ok(Value::BuiltinFunction(Rc::new(Ok)))
+/// Only forces `e` shallowly, so errors inside lists and attribute sets are
+/// not caught.
+pub fn try_eval(e: Value) -> Result {
+    let (success, value) = match debugger::catching(|| e.materialize()) {
+        Ok(value) => (true, value),
+        Err(err) if err.is_catchable() => (false, false.into()),
+        Err(err) => return Err(err),
+    };
+    let mut res = Attrs::new();
+    res.insert_mut("success".into(), success.into());
+    res.insert_mut("value".into(), value);
+    Ok(Value::AttrSet(res))
 }
 
-pub fn try_eval(_: Value) -> Result {
-    nyi("tryEval")
+pub fn warn(msg: Value, e: Value) -> Result {
+    if let Value::String(msg) = msg {
+        report(DiagnosticKind::Warning, msg.to_string_lossy().into_owned())?;
+        Ok(e)
+    } else {
+        mismatch("string", msg)
+    }
 }
@@ -1,4 +1,11 @@
-use crate::{builtins::Result, evaluator::nyi, value::Value};
+use std::{fs, path::Path};
+
+use crate::{
+    builtins::{mismatch, BuiltinError, Result},
+    evaluator::{self, nyi},
+    string::{Context, NixString},
+    value::Value,
+};
 
 pub fn derivation(_: Value) -> Result {
     nyi("derivations")
@@ -16,12 +23,13 @@ pub fn fetchurl(_: Value) -> Result {
     nyi("derivations")
 }
 
-pub fn filter_source(_: Value) -> Result {
+pub fn filter_source(_: Value, _: Value) -> Result {
     nyi("derivations")
 }
 
-pub fn import(_: Value) -> Result {
-    nyi("importing")
+pub fn import(path: Value) -> Result {
+    let path = path.coerce_to_string()?;
+    evaluator::import(Path::new(&*path.to_string_lossy()))
 }
 
 pub fn path(_: Value) -> Result {
@@ -40,16 +48,42 @@ pub fn read_dir(_: Value) -> Result {
     nyi("derivations")
 }
 
-pub fn read_file(_: Value) -> Result {
-    nyi("derivations")
+pub fn read_file(path: Value) -> Result {
+    let path = match path {
+        Value::Path(x) => x,
+        Value::String(x) => x.to_string_lossy().into_owned(),
+        x => return mismatch("path", x),
+    };
+    if let Some(host) = evaluator::host() {
+        host.check_read(Path::new(&path))?;
+    }
+    match fs::read(&path) {
+        Ok(contents) => Ok(Value::String(contents.into())),
+        Err(e) => Err(BuiltinError::Io(path.into(), e).into()),
+    }
 }
 
 pub fn store_path(_: Value) -> Result {
     nyi("derivations")
 }
 
-pub fn to_file(_: Value) -> Result {
-    nyi("derivations")
+pub fn to_file(name: Value, s: Value) -> Result {
+    let (name, s) = match (name, s) {
+        (Value::String(name), Value::String(s)) => (name, s),
+        (Value::String(_), x) | (x, _) => return mismatch("string", x),
+    };
+    let store = evaluator::host()
+        .and_then(|x| x.store.clone())
+        .ok_or(BuiltinError::NoStore)?;
+    let name = name.to_string_lossy();
+    match store.add_text(&name, s.as_bytes()) {
+        Ok(path) => {
+            let mut context = Context::new();
+            context.insert(path.as_str());
+            Ok(Value::String(NixString::from(path).with_context(&context)))
+        }
+        Err(e) => Err(BuiltinError::Io(name.into_owned().into(), e).into()),
+    }
 }
 
 pub fn to_path(_: Value) -> Result {
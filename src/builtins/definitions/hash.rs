@@ -1,5 +1,3 @@
-use std::rc::Rc;
-
 use crate::{
     builtins::{mismatch, BuiltinError, Result},
     value::Value,
@@ -7,7 +5,6 @@ use crate::{
 
 #[cfg(feature = "md5")]
 fn hash_string_md5(s: Value) -> Result {
-    let s = s.materialize()?;
     if let Value::String(s) = s {
         let digest = md5::compute(s.as_bytes());
         Ok(format!("{:x}", digest).into())
@@ -26,7 +23,6 @@ fn hash_string_md5(_: Value) -> Result {
 #[cfg(feature = "sha1")]
 fn hash_string_sha1(s: Value) -> Result {
     use sha1::{Digest, Sha1};
-    let s = s.materialize()?;
     if let Value::String(s) = s {
         let mut hasher = Sha1::new();
         hasher.update(s.as_bytes());
@@ -47,7 +43,6 @@ fn hash_string_sha1(_: Value) -> Result {
 #[cfg(feature = "sha256")]
 fn hash_string_sha256(s: Value) -> Result {
     use sha2::{Digest, Sha256};
-    let s = s.materialize()?;
     if let Value::String(s) = s {
         let mut hasher = Sha256::new();
         hasher.update(s.as_bytes());
@@ -68,7 +63,6 @@ fn hash_string_sha256(_: Value) -> Result {
 #[cfg(feature = "sha512")]
 fn hash_string_sha512(s: Value) -> Result {
     use sha2::{Digest, Sha512};
-    let s = s.materialize()?;
     if let Value::String(s) = s {
         let mut hasher = Sha512::new();
         hasher.update(s.as_bytes());
@@ -86,25 +80,24 @@ fn hash_string_sha512(_: Value) -> Result {
     Err(EvalError::NotEnabled("sha512".into()))
 }
 
-pub fn hash_string(t: Value) -> Result {
-    let t = t.materialize()?;
+pub fn hash_string(t: Value, s: Value) -> Result {
     if let Value::String(t) = t {
         if t == "md5" {
-            Ok(Value::BuiltinFunction(Rc::new(hash_string_md5)))
+            hash_string_md5(s)
         } else if t == "sha1" {
-            Ok(Value::BuiltinFunction(Rc::new(hash_string_sha1)))
+            hash_string_sha1(s)
         } else if t == "sha256" {
-            Ok(Value::BuiltinFunction(Rc::new(hash_string_sha256)))
+            hash_string_sha256(s)
         } else if t == "sha512" {
-            Ok(Value::BuiltinFunction(Rc::new(hash_string_sha512)))
+            hash_string_sha512(s)
         } else {
-            Err(BuiltinError::UnknownHash(t.into()).into())
+            Err(BuiltinError::UnknownHash(t.to_string().into()).into())
         }
     } else {
         mismatch("string", t)
     }
 }
 
-pub fn hash_file(_: Value) -> Result {
+pub fn hash_file(_: Value, _: Value) -> Result {
     crate::evaluator::nyi("derivations")
 }
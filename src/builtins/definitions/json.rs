@@ -0,0 +1,199 @@
+use std::{fmt::Write, path::Path};
+
+use crate::{
+    builtins::{BuiltinError, Result},
+    evaluator::{self, EvalError},
+    string::{Context, NixString},
+    value::Value,
+};
+
+/// Where a value is in the one being converted, like `.a.b[2]`.
+#[derive(Default)]
+struct JsonPath(String);
+
+impl JsonPath {
+    fn attr(&self, name: &str) -> Self {
+        let simple = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''));
+        if simple {
+            Self(format!("{}.{}", self.0, name))
+        } else {
+            let mut path = format!("{}.", self.0);
+            write_string(&mut path, name);
+            Self(path)
+        }
+    }
+
+    fn index(&self, i: usize) -> Self {
+        Self(format!("{}[{}]", self.0, i))
+    }
+
+    fn error(&self, what: &str) -> EvalError {
+        let path = if self.0.is_empty() { "." } else { &self.0 };
+        BuiltinError::ToJson(what.to_owned().into(), path.to_owned().into()).into()
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).expect("writing to a string"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Format a float like the JSON library Nix uses: the shortest digits that
+/// round-trip, with `.0` added to whole numbers and an exponent outside of
+/// `1e-5..1e15`.
+fn format_float(x: f64) -> String {
+    if !x.is_finite() {
+        return "null".into();
+    }
+    if x == 0.0 {
+        return if x.is_sign_negative() { "-0.0" } else { "0.0" }.into();
+    }
+    let scientific = format!("{:e}", x.abs());
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').expect("has exponent"));
+    let digits = mantissa.replace('.', "");
+    let exponent: i32 = exponent[1..].parse().expect("valid exponent");
+    // The value is 0.digits * 10^n.
+    let k = digits.len() as i32;
+    let n = exponent + 1;
+    let sign = if x < 0.0 { "-" } else { "" };
+    let formatted = if k <= n && n <= 15 {
+        format!("{}{}.0", digits, "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 15 {
+        let (whole, fraction) = digits.split_at(n as usize);
+        format!("{}.{}", whole, fraction)
+    } else if -4 < n && n <= 0 {
+        format!("0.{}{}", "0".repeat(-n as usize), digits)
+    } else {
+        let (first, rest) = digits.split_at(1);
+        let exponent = n - 1;
+        format!(
+            "{}{}{}e{}{:02}",
+            first,
+            if rest.is_empty() { "" } else { "." },
+            rest,
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    };
+    format!("{}{}", sign, formatted)
+}
+
+/// Copy the file at `path` to the store, as Nix does with paths it converts
+/// to strings, returning the store path with itself as context. Without a
+/// store, the path is left as it is.
+fn copy_to_store(path: &str) -> std::result::Result<NixString, EvalError> {
+    let host = match evaluator::host() {
+        Some(host) => host,
+        None => return Ok(path.into()),
+    };
+    let store = match &host.store {
+        Some(store) => store,
+        None => return Ok(path.into()),
+    };
+    host.check_read(Path::new(path))?;
+    let store_path = store
+        .add_path(Path::new(path))
+        .map_err(|e| BuiltinError::Io(path.to_owned().into(), e))?;
+    let mut context = Context::new();
+    context.insert(store_path.as_str());
+    Ok(NixString::from(store_path).with_context(&context))
+}
+
+/// Write a string, adding its context to that of the result.
+fn write_nix_string(
+    out: &mut String,
+    context: &mut Context,
+    s: &NixString,
+    path: &JsonPath,
+) -> std::result::Result<(), EvalError> {
+    match s.to_str() {
+        Ok(x) => write_string(out, x),
+        Err(_) => return Err(path.error("a string that isn't valid UTF-8")),
+    }
+    context.extend(s.context());
+    Ok(())
+}
+
+fn write_value(
+    out: &mut String,
+    context: &mut Context,
+    value: Value,
+    path: &JsonPath,
+) -> std::result::Result<(), EvalError> {
+    match value.materialize()? {
+        Value::Null => out.push_str("null"),
+        Value::Boolean(x) => out.push_str(if x { "true" } else { "false" }),
+        Value::Integer(x) => write!(out, "{}", x).expect("writing to a string"),
+        Value::Floating(x) => out.push_str(&format_float(x)),
+        Value::String(x) => write_nix_string(out, context, &x, path)?,
+        Value::Path(x) => write_nix_string(out, context, &copy_to_store(&x)?, path)?,
+        Value::AttrSet(attrs) if attrs.contains_key("__toString") => {
+            let s = Value::AttrSet(attrs).coerce_to_string()?;
+            write_nix_string(out, context, &s, path)?
+        }
+        Value::AttrSet(attrs) => match attrs.get("outPath") {
+            Some(out_path) => write_value(out, context, out_path.to_owned(), path)?,
+            None => {
+                out.push('{');
+                for (i, (name, value)) in attrs.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    if std::str::from_utf8(name.as_bytes()).is_err() {
+                        return Err(path.error("an attribute name that isn't valid UTF-8"));
+                    }
+                    write_string(out, name.as_str());
+                    out.push(':');
+                    write_value(out, context, value.to_owned(), &path.attr(name.as_str()))?;
+                }
+                out.push('}');
+            }
+        },
+        Value::List(list) => {
+            out.push('[');
+            for (i, value) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, context, value.to_owned(), &path.index(i))?;
+            }
+            out.push(']');
+        }
+        x => {
+            let what = match x {
+                Value::Function(_, _) => "a function",
+                _ => "a built-in function",
+            };
+            return Err(path.error(what));
+        }
+    }
+    Ok(())
+}
+
+/// Convert `e` to JSON like Nix: attribute sets that can be converted to
+/// strings become strings, those with an `outPath` become that, paths are
+/// copied to the store if there is one, and attributes are sorted by name.
+/// The result has the context of every string in `e`, and the store paths of
+/// those copied.
+pub fn to_json(e: Value) -> Result {
+    let mut out = String::new();
+    let mut context = Context::new();
+    write_value(&mut out, &mut context, e, &JsonPath::default())?;
+    Ok(Value::String(NixString::from(out).with_context(&context)))
+}
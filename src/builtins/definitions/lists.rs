@@ -1,16 +1,12 @@
-use std::rc::Rc;
-
-use rpds::Vector;
-
 use crate::{
     builtins::{mismatch, BuiltinError, Result},
+    sync::Vector,
     value::Value,
 };
 
 pub fn concat_lists(lists: Value) -> Result {
-    let lists = lists.materialize()?;
     if let Value::List(lists) = lists {
-        let mut res = Vector::new();
+        let mut res = Vector::default();
         for list in lists.iter() {
             let list = list.to_owned().materialize()?;
             if let Value::List(list) = list {
@@ -27,42 +23,31 @@ pub fn concat_lists(lists: Value) -> Result {
     }
 }
 
-pub fn elem(x: Value) -> Result {
-    let x = x.materialize()?;
-    Ok(Value::BuiltinFunction(Rc::new(move |xs| {
-        let xs = xs.materialize()?;
-        if let Value::List(xs) = xs {
-            Ok(xs.iter().any(|y| x.eq(y)).into())
-        } else {
-            mismatch("list", xs)
-        }
-    })))
+pub fn elem(x: Value, xs: Value) -> Result {
+    if let Value::List(xs) = xs {
+        Ok(xs.iter().any(|y| x.eq(y)).into())
+    } else {
+        mismatch("list", xs)
+    }
 }
 
-pub fn elem_at(xs: Value) -> Result {
-    let xs = xs.materialize()?;
-    if let Value::List(xs) = xs {
-        Ok(Value::BuiltinFunction(Rc::new(move |n| {
-            let n = n.materialize()?;
-            if let Value::Integer(n) = n {
-                if n < 0 || (usize::BITS < (i64::BITS - 1) && n > (usize::MAX as i64)) {
-                    Err(BuiltinError::OutOfBounds(n).into())
-                } else if let Some(x) = xs.get(n as usize) {
-                    Ok(x.to_owned())
-                } else {
-                    Err(BuiltinError::OutOfBounds(n).into())
-                }
+pub fn elem_at(xs: Value, n: Value) -> Result {
+    match (xs, n) {
+        (Value::List(xs), Value::Integer(n)) => {
+            if n < 0 || (usize::BITS < (i64::BITS - 1) && n > (usize::MAX as i64)) {
+                Err(BuiltinError::OutOfBounds(n).into())
+            } else if let Some(x) = xs.get(n as usize) {
+                Ok(x.to_owned())
             } else {
-                mismatch("integer", n)
+                Err(BuiltinError::OutOfBounds(n).into())
             }
-        })))
-    } else {
-        mismatch("list", xs)
+        }
+        (Value::List(_), n) => mismatch("integer", n),
+        (xs, _) => mismatch("list", xs),
     }
 }
 
 pub fn head(list: Value) -> Result {
-    let list = list.materialize()?;
     if let Value::List(list) = list {
         if let Some(x) = list.first() {
             Ok(x.to_owned())
@@ -75,7 +60,6 @@ pub fn head(list: Value) -> Result {
 }
 
 pub fn length(e: Value) -> Result {
-    let e = e.materialize()?;
     if let Value::List(e) = e {
         Ok((e.len() as i64).into())
     } else {
@@ -84,9 +68,8 @@ pub fn length(e: Value) -> Result {
 }
 
 pub fn tail(list: Value) -> Result {
-    let list = list.materialize()?;
     if let Value::List(list) = list {
-        let mut ret = Vector::new();
+        let mut ret = Vector::default();
         for v in list.iter().skip(1) {
             ret.push_back_mut(v.to_owned());
         }
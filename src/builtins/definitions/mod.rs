@@ -16,6 +16,9 @@ pub use hash::*;
 mod higher_order;
 pub use higher_order::*;
 
+mod json;
+pub use json::*;
+
 mod lists;
 pub use lists::*;
 
@@ -30,3 +33,6 @@ pub use strings::*;
 
 mod types;
 pub use types::*;
+
+mod xml;
+pub use xml::*;
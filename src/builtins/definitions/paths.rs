@@ -1,7 +1,9 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::{
-    builtins::{mismatch, nyi, Result},
+    builtins::{mismatch, nyi, BuiltinError, Result},
+    evaluator::{self, EvalError},
+    string::{Context, NixString},
     value::Value,
 };
 
@@ -10,13 +12,64 @@ pub fn base_name_of(_: Value) -> Result {
 }
 
 pub fn dir_of(s: Value) -> Result {
-    let s = s.materialize()?;
     if let Value::String(s) = s {
-        let path = Path::new(&s);
-        Ok(path
-            .parent()
-            .map_or(s.clone().into(), |x| x.to_string_lossy().to_string().into()))
+        let context: Context = s.context().collect();
+        let s = s.to_string_lossy();
+        let path = Path::new(&*s);
+        let dir = path.parent().and_then(Path::to_str).unwrap_or(&s);
+        Ok(Value::String(NixString::from(dir).with_context(&context)))
     } else {
         mismatch("string", s)
     }
 }
+
+pub fn find_file(path: Value, name: Value) -> Result {
+    let name = match name {
+        Value::String(x) => x.to_string_lossy().into_owned(),
+        x => return mismatch("string", x),
+    };
+    let entries = match path {
+        Value::List(x) => x,
+        x => return mismatch("list", x),
+    };
+    let host = evaluator::host();
+    for entry in entries.iter() {
+        let entry = match entry.to_owned().materialize()? {
+            Value::AttrSet(x) => x,
+            x => return mismatch("set", x),
+        };
+        let field = |field: &'static str| -> std::result::Result<String, EvalError> {
+            match entry.get(field) {
+                Some(x) => Ok(x
+                    .to_owned()
+                    .coerce_to_string()?
+                    .to_string_lossy()
+                    .into_owned()),
+                None => Err(BuiltinError::MissingAttr(field.into()).into()),
+            }
+        };
+        let prefix = field("prefix")?;
+        // A prefix only matches whole components of the name.
+        let rest = if prefix.is_empty() {
+            Some(name.as_str())
+        } else if name == prefix {
+            Some("")
+        } else {
+            name.strip_prefix(&prefix).and_then(|x| x.strip_prefix('/'))
+        };
+        if let Some(rest) = rest {
+            let mut candidate = PathBuf::from(field("path")?);
+            if !rest.is_empty() {
+                candidate.push(rest);
+            }
+            // Whether a file exists is only known if it may be read.
+            if let Some(host) = &host {
+                host.check_read(&candidate)?;
+            }
+            if candidate.exists() {
+                return Ok(Value::Path(candidate.to_string_lossy().into_owned()));
+            }
+        }
+    }
+    Err(BuiltinError::NotInSearchPath(name.into()).into())
+}
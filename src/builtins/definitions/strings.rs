@@ -1,76 +1,76 @@
-use std::{env, rc::Rc};
-
-use rpds::HashTrieMap;
+use std::env;
 
 use crate::{
+    attrs::Attrs,
     builtins::{mismatch, nyi, BuiltinError, Result},
+    embed::IoPolicy,
+    evaluator::{self, EvalError},
+    string::{Context, NixString},
     value::Value,
 };
 
 #[cfg(feature = "compare_versions")]
-pub fn compare_versions(s1: Value) -> Result {
-    let s1 = s1.materialize()?;
-    if let Value::String(s1) = s1 {
-        Ok(Value::BuiltinFunction(Rc::new(move |s2| {
-            let s2 = s2.materialize()?;
-            if let Value::String(s2) = s2 {
-                use version_compare::*;
-                match compare(s1.clone(), s2.clone()) {
-                    Ok(Cmp::Eq) => Ok(0.into()),
-                    Ok(Cmp::Lt) => Ok((-1).into()),
-                    Ok(Cmp::Gt) => Ok(1.into()),
-                    Ok(_) => Err(BuiltinError::UnexpectedVersionOutput.into()),
-                    Err(_) => Err(BuiltinError::VersionParse(s1.clone().into(), s2.into()).into()),
-                }
-            } else {
-                mismatch("string", s2)
+pub fn compare_versions(s1: Value, s2: Value) -> Result {
+    use version_compare::*;
+    match (s1, s2) {
+        (Value::String(s1), Value::String(s2)) => {
+            let s1 = s1.to_string_lossy();
+            let s2 = s2.to_string_lossy();
+            match compare(&s1, &s2) {
+                Ok(Cmp::Eq) => Ok(0.into()),
+                Ok(Cmp::Lt) => Ok((-1).into()),
+                Ok(Cmp::Gt) => Ok(1.into()),
+                Ok(_) => Err(BuiltinError::UnexpectedVersionOutput.into()),
+                Err(_) => Err(BuiltinError::VersionParse(
+                    s1.into_owned().into(),
+                    s2.into_owned().into(),
+                )
+                .into()),
             }
-        })))
-    } else {
-        mismatch("string", s1)
+        }
+        (Value::String(_), s2) => mismatch("string", s2),
+        (s1, _) => mismatch("string", s1),
     }
 }
 
 #[cfg(not(feature = "compare_versions"))]
-pub fn compare_versions(_: Value) -> Result {
-    use crate::evaluator::EvalError;
-
+pub fn compare_versions(_: Value, _: Value) -> Result {
     Err(EvalError::NotEnabled("compare_versions".into()))
 }
 
-pub fn concat_strings_sep(separator: Value) -> Result {
-    let separator = separator.materialize()?;
-    if let Value::String(separator) = separator {
-        Ok(Value::BuiltinFunction(Rc::new(move |list| {
-            let list = list.materialize()?;
-            if let Value::List(list) = list {
-                Ok(list
-                    .iter()
-                    .map(|v| {
-                        let v = v.to_owned().materialize()?;
-                        if let Value::String(v) = v {
-                            Ok(v)
-                        } else {
-                            mismatch("string", v.to_owned())
-                        }
-                    })
-                    .collect::<std::result::Result<Vec<_>, _>>()?
-                    .join(&separator)
-                    .into())
-            } else {
-                mismatch("list", list)
-            }
-        })))
-    } else {
-        mismatch("string", separator)
+pub fn concat_strings_sep(separator: Value, list: Value) -> Result {
+    match (separator, list) {
+        (Value::String(separator), Value::List(list)) => {
+            let strings = list
+                .iter()
+                .map(|v| {
+                    let v = v.to_owned().materialize()?;
+                    if let Value::String(v) = v {
+                        Ok(v)
+                    } else {
+                        mismatch("string", v.to_owned())
+                    }
+                })
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let mut context: Context = separator.context().collect();
+            context.extend(strings.iter().flat_map(|x| x.context()));
+            let joined: NixString = strings
+                .iter()
+                .map(|x| x.as_bytes())
+                .collect::<Vec<_>>()
+                .join(separator.as_bytes())
+                .into();
+            Ok(Value::String(joined.with_context(&context)))
+        }
+        (Value::String(_), list) => mismatch("list", list),
+        (separator, _) => mismatch("string", separator),
     }
 }
 
 #[cfg(feature = "json")]
 pub fn from_json(e: Value) -> Result {
-    let e = e.materialize()?;
     if let Value::String(e) = e {
-        Ok(serde_json::from_str(&e).map_err(BuiltinError::from)?)
+        Ok(serde_json::from_slice(&e).map_err(BuiltinError::from)?)
     } else {
         mismatch("string", e)
     }
@@ -78,168 +78,200 @@ pub fn from_json(e: Value) -> Result {
 
 #[cfg(not(feature = "json"))]
 pub fn from_json(_: Value) -> Result {
-    use crate::evaluator::EvalError;
-
     Err(EvalError::NotEnabled("json".into()))
 }
 
-#[cfg(feature = "json")]
-pub fn to_json(e: Value) -> Result {
-    let e = e.materialize_deep()?;
-    Ok(serde_json::to_string(&e)
-        .map_err(BuiltinError::from)?
-        .into())
+#[cfg(feature = "toml")]
+pub fn from_toml(e: Value) -> Result {
+    /// Convert like upstream Nix, which doesn't support dates and times.
+    fn convert(value: toml::Value) -> std::result::Result<Value, BuiltinError> {
+        Ok(match value {
+            toml::Value::String(x) => x.into(),
+            toml::Value::Integer(x) => x.into(),
+            toml::Value::Float(x) => x.into(),
+            toml::Value::Boolean(x) => x.into(),
+            toml::Value::Datetime(_) => return Err(BuiltinError::TomlDatetime),
+            toml::Value::Array(x) => Value::List(
+                x.into_iter()
+                    .map(convert)
+                    .collect::<std::result::Result<_, _>>()?,
+            ),
+            toml::Value::Table(x) => {
+                let mut attrs = Attrs::new();
+                for (k, v) in x {
+                    attrs.insert_mut(k.into(), convert(v)?);
+                }
+                Value::AttrSet(attrs)
+            }
+        })
+    }
+
+    if let Value::String(e) = e {
+        let value = toml::from_slice(e.as_bytes()).map_err(BuiltinError::from)?;
+        Ok(convert(value)?)
+    } else {
+        mismatch("string", e)
+    }
 }
 
-#[cfg(not(feature = "json"))]
-pub fn to_json(_: Value) -> Result {
-    use crate::evaluator::EvalError;
+#[cfg(not(feature = "toml"))]
+pub fn from_toml(_: Value) -> Result {
+    Err(EvalError::NotEnabled("toml".into()))
+}
 
-    Err(EvalError::NotEnabled("json".into()))
+pub fn get_context(s: Value) -> Result {
+    if let Value::String(s) = s {
+        let mut res = Attrs::new();
+        for path in s.context() {
+            let mut entry = Attrs::new();
+            entry.insert_mut("path".into(), true.into());
+            res.insert_mut(path.into(), Value::AttrSet(entry));
+        }
+        Ok(Value::AttrSet(res))
+    } else {
+        mismatch("string", s)
+    }
 }
 
 pub fn get_env(s: Value) -> Result {
-    let s = s.materialize()?;
     if let Value::String(s) = s {
-        match env::var(s.clone()) {
+        let s = s.to_string_lossy();
+        let restricted = evaluator::host().map_or(false, |x| x.io != IoPolicy::Unrestricted);
+        if restricted {
+            return Ok("".into());
+        }
+        match env::var(&*s) {
             Ok(x) => Ok(x.into()),
-            Err(env::VarError::NotPresent) => Ok(Value::Null),
-            Err(e) => Err(BuiltinError::Environment(s.into(), e).into()),
+            Err(env::VarError::NotPresent) => Ok("".into()),
+            Err(e) => Err(BuiltinError::Environment(s.into_owned().into(), e).into()),
         }
     } else {
         mismatch("string", s)
     }
 }
 
+pub fn has_context(s: Value) -> Result {
+    if let Value::String(s) = s {
+        Ok(s.has_context().into())
+    } else {
+        mismatch("string", s)
+    }
+}
+
 #[cfg(feature = "regex")]
-pub fn f_match(regex: Value) -> Result {
-    use regex::Regex;
-    let regex = regex.materialize()?;
-    if let Value::String(regex) = regex {
-        let re = Regex::new(&regex).map_err(BuiltinError::from)?;
-        Ok(Value::BuiltinFunction(Rc::new(move |str| {
-            let str = str.materialize()?;
-            if let Value::String(str) = str {
-                if let Some(captures) = re.captures(&str) {
-                    Ok(Value::List(
-                        captures
-                            .iter()
-                            .filter_map(|x| x.map(|x| Value::String(x.as_str().to_string())))
-                            .collect(),
-                    ))
-                } else {
-                    Ok(Value::Null)
-                }
+pub fn f_match(regex: Value, str: Value) -> Result {
+    use regex::bytes::Regex;
+    match (regex, str) {
+        (Value::String(regex), Value::String(str)) => {
+            let re = Regex::new(&regex.to_string_lossy()).map_err(BuiltinError::from)?;
+            if let Some(captures) = re.captures(&str) {
+                Ok(Value::List(
+                    captures
+                        .iter()
+                        .filter_map(|x| x.map(|x| Value::String(x.as_bytes().into())))
+                        .collect(),
+                ))
             } else {
-                mismatch("string", str)
+                Ok(Value::Null)
             }
-        })))
-    } else {
-        mismatch("string", regex)
+        }
+        (Value::String(_), str) => mismatch("string", str),
+        (regex, _) => mismatch("string", regex),
     }
 }
 
-#[cfg(not(feature = "json"))]
-pub fn f_match(_: Value) -> Result {
-    use crate::evaluator::EvalError;
-
+#[cfg(not(feature = "regex"))]
+pub fn f_match(_: Value, _: Value) -> Result {
     Err(EvalError::NotEnabled("regex".into()))
 }
 
 pub fn parse_drv_name(s: Value) -> Result {
-    fn name_version_pair(name: String, version: Option<String>) -> Value {
-        let mut attrset = HashTrieMap::new();
-        attrset.insert_mut("name".into(), name.into());
-        attrset.insert_mut("version".into(), version.map_or(Value::Null, Value::String));
+    fn name_version_pair(name: &[u8], version: Option<&[u8]>) -> Value {
+        let mut attrset = Attrs::new();
+        attrset.insert_mut("name".into(), Value::String(name.into()));
+        attrset.insert_mut(
+            "version".into(),
+            version.map_or(Value::Null, |x| Value::String(x.into())),
+        );
         Value::AttrSet(attrset)
     }
-    let s = s.materialize()?;
     if let Value::String(s) = s {
-        Ok(s.clone().split_once('-').map_or_else(
-            || name_version_pair(s, None),
-            |(name, version)| name_version_pair(name.to_string(), Some(version.to_string())),
+        Ok(s.iter().position(|&c| c == b'-').map_or_else(
+            || name_version_pair(&s, None),
+            |i| name_version_pair(&s[..i], Some(&s[i + 1..])),
         ))
     } else {
         mismatch("string", s)
     }
 }
 
-pub fn replace_strings(from: Value) -> Result {
-    let from = from.materialize_deep()?;
-    if let Value::List(from) = from {
-        Ok(Value::BuiltinFunction(Rc::new(move |to| {
-            let to = to.materialize_deep()?;
-            if let Value::List(to) = to {
-                if from.len() == to.len() {
-                    let l = from.len();
-                    let from = from.clone();
-                    Ok(Value::BuiltinFunction(Rc::new(move |s| {
-                        let s = s.materialize()?;
-                        if let Value::String(s) = s {
-                            let mut res = s.clone();
-                            for i in 0..l {
-                                let from = if let Some(from) = from.get(i) {
-                                    if let Value::String(from) = from {
-                                        from
-                                    } else {
-                                        return mismatch("string", from.to_owned());
-                                    }
-                                } else {
-                                    panic!("BUG: from.len != to.len");
-                                };
-                                let to = if let Some(to) = to.get(i) {
-                                    if let Value::String(to) = to {
-                                        to
-                                    } else {
-                                        return mismatch("string", to.to_owned());
-                                    }
-                                } else {
-                                    panic!("BUG: from.len != to.len");
-                                };
-                                res = res.replace(from, to);
-                            }
-                            Ok(s.into())
-                        } else {
-                            mismatch("string", s)
-                        }
-                    })))
+pub fn replace_strings(from: Value, to: Value, s: Value) -> Result {
+    fn strings(list: Value) -> std::result::Result<Vec<NixString>, EvalError> {
+        if let Value::List(list) = list {
+            list.iter()
+                .map(|x| match x.to_owned().materialize()? {
+                    Value::String(x) => Ok(x),
+                    x => mismatch("string", x),
+                })
+                .collect()
+        } else {
+            mismatch("list", list)
+        }
+    }
+    let from = strings(from)?;
+    let to = strings(to)?;
+    if from.len() != to.len() {
+        return Err(BuiltinError::ReplaceStringsArgLength.into());
+    }
+    if let Value::String(s) = s {
+        // Scan left to right, replacing the first pattern that matches at each
+        // byte offset. An empty pattern matches between every pair of bytes.
+        // The result keeps the context of `s`, and of the replacements used.
+        let mut res = Vec::new();
+        let mut context: Context = s.context().collect();
+        let mut p = 0;
+        while p <= s.len() {
+            if let Some((from, to)) = from
+                .iter()
+                .zip(&to)
+                .find(|(from, _)| s[p..].starts_with(from))
+            {
+                res.extend_from_slice(to);
+                context.extend(to.context());
+                if from.is_empty() {
+                    res.extend_from_slice(s.get(p..p + 1).unwrap_or_default());
+                    p += 1;
                 } else {
-                    Err(BuiltinError::ReplaceStringsArgLength.into())
+                    p += from.len();
                 }
             } else {
-                mismatch("list", to)
+                res.extend_from_slice(s.get(p..p + 1).unwrap_or_default());
+                p += 1;
             }
-        })))
+        }
+        Ok(NixString::from(res).with_context(&context).into())
     } else {
-        mismatch("list", from)
+        mismatch("string", s)
     }
 }
 
 #[cfg(feature = "regex")]
-pub fn split(regex: Value) -> Result {
-    use regex::Regex;
-    let regex = regex.materialize()?;
-    if let Value::String(regex) = regex {
-        let re = Regex::new(&regex).map_err(BuiltinError::from)?;
-        Ok(Value::BuiltinFunction(Rc::new(move |str| {
-            let str = str.materialize()?;
-            if let Value::String(str) = str {
-                Ok(Value::List(
-                    re.split(&str).map(|x| x.to_string().into()).collect(),
-                ))
-            } else {
-                mismatch("string", str)
-            }
-        })))
-    } else {
-        mismatch("string", regex)
+pub fn split(regex: Value, str: Value) -> Result {
+    use regex::bytes::Regex;
+    match (regex, str) {
+        (Value::String(regex), Value::String(str)) => {
+            let re = Regex::new(&regex.to_string_lossy()).map_err(BuiltinError::from)?;
+            Ok(Value::List(
+                re.split(&str).map(|x| Value::String(x.into())).collect(),
+            ))
+        }
+        (Value::String(_), str) => mismatch("string", str),
+        (regex, _) => mismatch("string", regex),
     }
 }
 
-#[cfg(not(feature = "json"))]
-pub fn split(_: Value) -> Result {
-    use crate::evaluator::EvalError;
-
+#[cfg(not(feature = "regex"))]
+pub fn split(_: Value, _: Value) -> Result {
     Err(EvalError::NotEnabled("regex".into()))
 }
 
@@ -248,7 +280,6 @@ pub fn split_version(_: Value) -> Result {
 }
 
 pub fn string_length(e: Value) -> Result {
-    let e = e.materialize()?;
     if let Value::String(e) = e {
         Ok(Value::Integer(e.len() as i64))
     } else {
@@ -256,26 +287,25 @@ pub fn string_length(e: Value) -> Result {
     }
 }
 
-pub fn substring(start: Value) -> Result {
-    let start = start.materialize()?;
-    if let Value::Integer(start) = start {
-        Ok(Value::BuiltinFunction(Rc::new(move |len| {
-            if let Value::Integer(len) = len {
-                Ok(Value::BuiltinFunction(Rc::new(move |s| {
-                    if let Value::String(s) = s {
-                        Ok(s[(start as usize)..((start + len) as usize)]
-                            .to_string()
-                            .into())
-                    } else {
-                        mismatch("string", s)
-                    }
-                })))
+pub fn substring(start: Value, len: Value, s: Value) -> Result {
+    match (start, len, s) {
+        (Value::Integer(start), _, _) if start < 0 => Err(BuiltinError::OutOfBounds(start).into()),
+        (Value::Integer(start), Value::Integer(len), Value::String(s)) => {
+            // Offsets are in bytes; a negative length means "to the end".
+            let start = (start as usize).min(s.len());
+            let end = if len < 0 {
+                s.len()
             } else {
-                mismatch("integer", len)
-            }
-        })))
-    } else {
-        mismatch("integer", start)
+                start.saturating_add(len as usize).min(s.len())
+            };
+            let context: Context = s.context().collect();
+            Ok(Value::String(
+                NixString::from(&s[start..end]).with_context(&context),
+            ))
+        }
+        (Value::Integer(_), Value::Integer(_), s) => mismatch("string", s),
+        (Value::Integer(_), len, _) => mismatch("integer", len),
+        (start, _, _) => mismatch("integer", start),
     }
 }
 
@@ -287,13 +317,13 @@ pub fn to_string(e: Value) -> Result {
         Value::Path(_) => todo!(),
         Value::Boolean(x) => {
             if *x {
-                Ok("1".to_string().into())
+                Ok("1".into())
             } else {
-                Ok("".to_string().into())
+                Ok("".into())
             }
         }
-        Value::Null => Ok("".to_string().into()),
-        Value::Function(_, _, _) => Err(BuiltinError::CannotSerialize("function".into()).into()),
+        Value::Null => Ok("".into()),
+        Value::Function(_, _) => Err(BuiltinError::CannotSerialize("function".into()).into()),
         Value::AttrSet(set) => {
             if let Some(to_string) = set.get("__toString") {
                 if to_string.callable() {
@@ -317,12 +347,14 @@ pub fn to_string(e: Value) -> Result {
             }
         }
         Value::List(l) => {
-            let mut ret = String::new();
+            let mut ret = Vec::new();
+            let mut context = Context::new();
             for v in l.iter() {
                 let str = to_string(v.to_owned())?;
                 if let Value::String(str) = str {
-                    ret += &str;
-                    ret += " ";
+                    ret.extend_from_slice(&str);
+                    ret.push(b' ');
+                    context.extend(str.context());
                 } else {
                     panic!(
                         "BUG: toString returned something other than a string: {}",
@@ -330,14 +362,20 @@ pub fn to_string(e: Value) -> Result {
                     );
                 }
             }
-            ret.remove(ret.len() - 1);
-            Ok(ret.into())
+            ret.pop();
+            Ok(Value::String(NixString::from(ret).with_context(&context)))
+        }
+        Value::Thunk(_) => to_string(e.materialize()?),
+        Value::PrimOp(_) | Value::PrimOpApp(_, _) => {
+            Err(BuiltinError::CannotSerialize("function".into()).into())
         }
-        Value::Thunk(_, _) => to_string(e.materialize()?),
-        Value::BuiltinFunction(_) => Err(BuiltinError::CannotSerialize("function".into()).into()),
     }
 }
 
-pub fn to_xml(_: Value) -> Result {
-    nyi("toXML")
+pub fn unsafe_discard_string_context(s: Value) -> Result {
+    if let Value::String(s) = s {
+        Ok(Value::String(s.without_context()))
+    } else {
+        mismatch("string", s)
+    }
 }
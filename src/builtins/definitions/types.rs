@@ -4,7 +4,7 @@ const T: Value = Value::Boolean(true);
 const F: Value = Value::Boolean(false);
 
 pub fn is_attrs(e: Value) -> Result {
-    if let Value::AttrSet(_) = e.materialize()? {
+    if let Value::AttrSet(_) = e {
         Ok(T)
     } else {
         Ok(F)
@@ -12,7 +12,7 @@ pub fn is_attrs(e: Value) -> Result {
 }
 
 pub fn is_bool(e: Value) -> Result {
-    if let Value::Boolean(_) = e.materialize()? {
+    if let Value::Boolean(_) = e {
         Ok(T)
     } else {
         Ok(F)
@@ -20,26 +20,24 @@ pub fn is_bool(e: Value) -> Result {
 }
 
 pub fn is_float(e: Value) -> Result {
-    if let Value::Floating(_) = e.materialize()? {
+    if let Value::Floating(_) = e {
         Ok(T)
     } else {
         Ok(F)
     }
 }
 
+/// Unlike [`Value::callable`], this is false for attribute sets with a
+/// `__functor`, as in upstream Nix.
 pub fn is_function(e: Value) -> Result {
-    let e = e.materialize()?;
-    if let Value::Function(_, _, _) = e {
-        Ok(T)
-    } else if let Value::BuiltinFunction(_) = e {
-        Ok(T)
-    } else {
-        Ok(F)
+    match e {
+        Value::Function(_, _) | Value::PrimOp(_) | Value::PrimOpApp(_, _) => Ok(T),
+        _ => Ok(F),
     }
 }
 
 pub fn is_int(e: Value) -> Result {
-    if let Value::Integer(_) = e.materialize()? {
+    if let Value::Integer(_) = e {
         Ok(T)
     } else {
         Ok(F)
@@ -47,7 +45,7 @@ pub fn is_int(e: Value) -> Result {
 }
 
 pub fn is_list(e: Value) -> Result {
-    if let Value::List(_) = e.materialize()? {
+    if let Value::List(_) = e {
         Ok(T)
     } else {
         Ok(F)
@@ -55,11 +53,11 @@ pub fn is_list(e: Value) -> Result {
 }
 
 pub fn is_null(e: Value) -> Result {
-    Ok((e.materialize()? == Value::Null).into())
+    Ok((e == Value::Null).into())
 }
 
 pub fn is_path(e: Value) -> Result {
-    if let Value::Path(_) = e.materialize()? {
+    if let Value::Path(_) = e {
         Ok(T)
     } else {
         Ok(F)
@@ -67,7 +65,7 @@ pub fn is_path(e: Value) -> Result {
 }
 
 pub fn is_string(e: Value) -> Result {
-    if let Value::String(_) = e.materialize()? {
+    if let Value::String(_) = e {
         Ok(T)
     } else {
         Ok(F)
@@ -76,7 +74,7 @@ pub fn is_string(e: Value) -> Result {
 
 pub fn type_of(e: Value) -> Result {
     fn s(s: &'static str) -> Result {
-        Ok(Value::String(s.to_string()))
+        Ok(s.into())
     }
     match e {
         Value::String(_) => s("string"),
@@ -85,10 +83,10 @@ pub fn type_of(e: Value) -> Result {
         Value::Path(_) => s("path"),
         Value::Boolean(_) => s("bool"),
         Value::Null => s("null"),
-        Value::Function(_, _, _) => s("lambda"),
+        Value::Function(_, _) => s("lambda"),
         Value::AttrSet(_) => s("set"),
         Value::List(_) => s("list"),
-        Value::Thunk(_, _) => type_of(e.materialize()?),
-        Value::BuiltinFunction(_) => s("lambda"),
+        Value::Thunk(_) => type_of(e.materialize()?),
+        Value::PrimOp(_) | Value::PrimOpApp(_, _) => s("lambda"),
     }
 }
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+
+use crate::{attrs::Attrs, builtins::Result, evaluator::EvalError, ir::Param, value::Value};
+
+/// Writes indented XML the way Nix's `XMLWriter` does, attributes sorted by
+/// name.
+struct XmlWriter {
+    out: String,
+    open: Vec<&'static str>,
+}
+
+impl XmlWriter {
+    fn new() -> Self {
+        Self {
+            out: "<?xml version='1.0' encoding='utf-8'?>\n".into(),
+            open: Vec::new(),
+        }
+    }
+
+    fn start(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.out.push_str(&"  ".repeat(self.open.len()));
+        self.out.push('<');
+        self.out.push_str(name);
+        let mut attrs = attrs.to_vec();
+        attrs.sort();
+        for (key, value) in attrs {
+            self.out.push(' ');
+            self.out.push_str(key);
+            self.out.push_str("=\"");
+            for c in value.chars() {
+                match c {
+                    '"' => self.out.push_str("&quot;"),
+                    '<' => self.out.push_str("&lt;"),
+                    '>' => self.out.push_str("&gt;"),
+                    '&' => self.out.push_str("&amp;"),
+                    // Stops newlines from being normalised to spaces.
+                    '\n' => self.out.push_str("&#xA;"),
+                    c => self.out.push(c),
+                }
+            }
+            self.out.push('"');
+        }
+    }
+
+    fn open(&mut self, name: &'static str, attrs: &[(&str, &str)]) {
+        self.start(name, attrs);
+        self.out.push_str(">\n");
+        self.open.push(name);
+    }
+
+    fn close(&mut self) {
+        let name = self
+            .open
+            .pop()
+            .expect("BUG: closed an element that isn't open");
+        self.out.push_str(&"  ".repeat(self.open.len()));
+        self.out.push_str("</");
+        self.out.push_str(name);
+        self.out.push_str(">\n");
+    }
+
+    fn empty(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.start(name, attrs);
+        self.out.push_str(" />\n");
+    }
+}
+
+/// Format a float like C++ streams do by default, which is `%g`: six
+/// significant digits, without trailing zeros.
+fn format_float(x: f64) -> String {
+    if !x.is_finite() {
+        return if x.is_nan() {
+            "nan".into()
+        } else if x > 0.0 {
+            "inf".into()
+        } else {
+            "-inf".into()
+        };
+    }
+    if x == 0.0 {
+        return if x.is_sign_negative() { "-0" } else { "0" }.into();
+    }
+    let trim = |s: String| {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_owned()
+        } else {
+            s
+        }
+    };
+    let scientific = format!("{:.5e}", x);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').expect("has exponent"));
+    let exponent: i32 = exponent[1..].parse().expect("valid exponent");
+    if !(-4..6).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa.into()), sign, exponent.abs())
+    } else {
+        trim(format!("{:.*}", (5 - exponent) as usize, x))
+    }
+}
+
+fn write_attrs(
+    doc: &mut XmlWriter,
+    attrs: &Attrs,
+    drvs_seen: &mut HashSet<String>,
+) -> std::result::Result<(), EvalError> {
+    for (name, value) in attrs {
+        doc.open("attr", &[("name", name.as_str())]);
+        write_value(doc, value.to_owned(), drvs_seen)?;
+        doc.close();
+    }
+    Ok(())
+}
+
+/// The string value of the attribute `name`, if it has one.
+fn string_attr(attrs: &Attrs, name: &str) -> std::result::Result<Option<String>, EvalError> {
+    match attrs.get(name) {
+        Some(x) => match x.to_owned().materialize()? {
+            Value::String(x) => Ok(Some(x.to_string_lossy().into_owned())),
+            _ => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+fn is_derivation(attrs: &Attrs) -> std::result::Result<bool, EvalError> {
+    Ok(string_attr(attrs, "type")?.as_deref() == Some("derivation"))
+}
+
+fn write_value(
+    doc: &mut XmlWriter,
+    value: Value,
+    drvs_seen: &mut HashSet<String>,
+) -> std::result::Result<(), EvalError> {
+    match value.materialize()? {
+        Value::Integer(x) => doc.empty("int", &[("value", &x.to_string())]),
+        Value::Floating(x) => doc.empty("float", &[("value", &format_float(x))]),
+        Value::Boolean(x) => doc.empty("bool", &[("value", if x { "true" } else { "false" })]),
+        Value::String(x) => doc.empty("string", &[("value", &x.to_string_lossy())]),
+        Value::Path(x) => doc.empty("path", &[("value", &x)]),
+        Value::Null => doc.empty("null", &[]),
+        Value::AttrSet(attrs) if is_derivation(&attrs)? => {
+            let drv_path = string_attr(&attrs, "drvPath")?;
+            let out_path = string_attr(&attrs, "outPath")?;
+            let mut xml_attrs = Vec::new();
+            if let Some(x) = &drv_path {
+                xml_attrs.push(("drvPath", x.as_str()));
+            }
+            if let Some(x) = &out_path {
+                xml_attrs.push(("outPath", x.as_str()));
+            }
+            doc.open("derivation", &xml_attrs);
+            // Each derivation is only written out in full once.
+            let first = match &drv_path {
+                Some(x) if !x.is_empty() => drvs_seen.insert(x.to_owned()),
+                _ => false,
+            };
+            if first {
+                write_attrs(doc, &attrs, drvs_seen)?;
+            } else {
+                doc.empty("repeated", &[]);
+            }
+            doc.close();
+        }
+        Value::AttrSet(attrs) => {
+            doc.open("attrs", &[]);
+            write_attrs(doc, &attrs, drvs_seen)?;
+            doc.close();
+        }
+        Value::List(list) => {
+            doc.open("list", &[]);
+            for x in list.iter() {
+                write_value(doc, x.to_owned(), drvs_seen)?;
+            }
+            doc.close();
+        }
+        Value::Function(lambda, _) => {
+            doc.open("function", &[]);
+            match &lambda.param {
+                Param::Ident(name) => doc.empty("varpat", &[("name", name.as_str())]),
+                Param::Pattern {
+                    formals,
+                    ellipsis,
+                    bind,
+                } => {
+                    let mut attrs = Vec::new();
+                    if let Some(bind) = bind {
+                        attrs.push(("name", bind.as_str()));
+                    }
+                    if *ellipsis {
+                        attrs.push(("ellipsis", "1"));
+                    }
+                    doc.open("attrspat", &attrs);
+                    let mut names: Vec<_> = formals.iter().map(|x| x.name.as_str()).collect();
+                    names.sort_unstable();
+                    for name in names {
+                        doc.empty("attr", &[("name", name)]);
+                    }
+                    doc.close();
+                }
+            }
+            doc.close();
+        }
+        // Nix only describes lambdas.
+        _ => doc.empty("unevaluated", &[]),
+    }
+    Ok(())
+}
+
+pub fn to_xml(e: Value) -> Result {
+    let mut doc = XmlWriter::new();
+    doc.open("expr", &[]);
+    write_value(&mut doc, e, &mut HashSet::new())?;
+    doc.close();
+    Ok(doc.out.into())
+}
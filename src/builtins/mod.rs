@@ -1,9 +1,12 @@
-use std::{env::VarError, rc::Rc};
+use std::env::VarError;
 
-use rpds::HashTrieMap;
 use thiserror::Error;
 
-use crate::{evaluator::EvalError, value::Value, ErrorString};
+use crate::{attrs::Attrs, evaluator::EvalError, sync::Rc, value::Value, ErrorString};
+
+#[macro_use]
+mod primop;
+pub use primop::{Arg, PrimOp, PrimOpFn};
 
 mod definitions;
 
@@ -13,6 +16,8 @@ pub enum BuiltinError {
     Aborted(String),
     #[error("Error thrown: {0}")]
     Thrown(String),
+    #[error("Evaluation aborted on warning: {0}")]
+    AbortedOnWarning(ErrorString),
     #[error("Type mismatch - expected {0}, found {1}")]
     TypeMismatch(ErrorString, ErrorString),
     #[error("Builtin not yet implemented: {0}")]
@@ -31,19 +36,72 @@ pub enum BuiltinError {
     ReplaceStringsArgLength,
     #[error("Cannot serialize {0} to string")]
     CannotSerialize(ErrorString),
+    #[error("Cannot convert {0} at {1} to JSON")]
+    ToJson(ErrorString, ErrorString),
+
+    #[error("File {0} was not found in the Nix search path")]
+    NotInSearchPath(ErrorString),
+    #[error("Access to {0} is not allowed")]
+    AccessDenied(ErrorString),
+    #[error("No store was configured")]
+    NoStore,
+    #[error("Builtin {0} takes no arguments")]
+    NoArguments(ErrorString),
 
     #[error("An error occurred fetching the environment variable {0}")]
     Environment(ErrorString, #[source] VarError),
+    #[error("An error occurred reading {0}")]
+    Io(ErrorString, #[source] std::io::Error),
 
     #[cfg(feature = "json")]
     #[error(transparent)]
     JSON(#[from] serde_json::Error),
 
+    #[cfg(feature = "toml")]
+    #[error("An error occurred parsing TOML")]
+    Toml(#[from] toml::de::Error),
+    #[cfg(feature = "toml")]
+    #[error("Dates and times are not supported in TOML")]
+    TomlDatetime,
+
     #[cfg(feature = "regex")]
     #[error(transparent)]
     Regex(#[from] regex::Error),
 }
 
+impl BuiltinError {
+    /// Whether `builtins.tryEval` can catch the error. Only `throw` is.
+    pub fn is_catchable(&self) -> bool {
+        match self {
+            BuiltinError::Thrown(_) => true,
+            BuiltinError::Aborted(_)
+            | BuiltinError::AbortedOnWarning(_)
+            | BuiltinError::TypeMismatch(_, _)
+            | BuiltinError::NotYetImplemented(_)
+            | BuiltinError::VersionParse(_, _)
+            | BuiltinError::UnexpectedVersionOutput
+            | BuiltinError::OutOfBounds(_)
+            | BuiltinError::UnknownHash(_)
+            | BuiltinError::MissingAttr(_)
+            | BuiltinError::ReplaceStringsArgLength
+            | BuiltinError::CannotSerialize(_)
+            | BuiltinError::ToJson(_, _)
+            | BuiltinError::NotInSearchPath(_)
+            | BuiltinError::AccessDenied(_)
+            | BuiltinError::NoStore
+            | BuiltinError::NoArguments(_)
+            | BuiltinError::Environment(_, _)
+            | BuiltinError::Io(_, _) => false,
+            #[cfg(feature = "json")]
+            BuiltinError::JSON(_) => false,
+            #[cfg(feature = "toml")]
+            BuiltinError::Toml(_) | BuiltinError::TomlDatetime => false,
+            #[cfg(feature = "regex")]
+            BuiltinError::Regex(_) => false,
+        }
+    }
+}
+
 type Result = std::result::Result<Value, EvalError>;
 
 fn nyi<S: Into<ErrorString>>(builtin: S) -> Result {
@@ -54,117 +112,259 @@ fn mismatch<T>(expected: &'static str, received: Value) -> std::result::Result<T
     Err(BuiltinError::TypeMismatch(expected.into(), received.human_readable_type().into()).into())
 }
 
-pub fn builtins_set() -> Value {
-    let mut s = HashTrieMap::new();
-    fn add<F: 'static + Fn(Value) -> Result>(
-        s: &mut HashTrieMap<String, Value>,
-        name: &'static str,
-        f: F,
-    ) {
-        s.insert_mut(name.to_string(), Value::BuiltinFunction(Rc::new(f)));
+/// Every builtin, in the order they appear in `builtins`.
+pub fn primops() -> Vec<PrimOp> {
+    use definitions::*;
+
+    primops! {
+        /// Abort evaluation with the message `s`, which must be coercible
+        /// to a string. This can't be caught by `tryEval`.
+        "abort" => abort(strict s);
+        /// Return the sum of the numbers `e1` and `e2`.
+        "add" => add(strict e1, strict e2);
+        /// Evaluate `e`, annotating any error with the message `ctx`.
+        "addErrorContext" => add_error_context(lazy ctx, lazy e);
+        /// Return true if `pred` returns true for every element of `list`.
+        "all" => all(strict pred, strict list);
+        /// Return true if `pred` returns true for any element of `list`.
+        "any" => any(strict pred, strict list);
+        /// Return the names of the attributes of `set`, sorted.
+        "attrNames" => attr_names(strict set);
+        /// Return the values of the attributes of `set`, in the order of
+        /// their sorted names.
+        "attrValues" => attr_values(strict set);
+        /// Return the last component of the path `s`.
+        "baseNameOf" => base_name_of(strict s);
+        /// Return the bitwise AND of the integers `e1` and `e2`.
+        "bitAnd" => bit_and(strict e1, strict e2);
+        /// Return the bitwise OR of the integers `e1` and `e2`.
+        "bitOr" => bit_or(strict e1, strict e2);
+        /// Return the bitwise XOR of the integers `e1` and `e2`.
+        "bitXor" => bit_xor(strict e1, strict e2);
+        /// Pause evaluation in the debugger, if one is attached, then
+        /// return `v`.
+        "break" => breakpoint(lazy v);
+        /// Collect the attribute `attr` from every set in `list` that has it.
+        "catAttrs" => cat_attrs(strict attr, strict list);
+        /// Round the float `double` up to the nearest integer.
+        "ceil" => ceil(strict double);
+        /// Compare the version strings `s1` and `s2`, returning -1, 0 or 1.
+        "compareVersions" => compare_versions(strict s1, strict s2);
+        /// Concatenate a list of lists.
+        "concatLists" => concat_lists(strict lists);
+        /// Map `f` over `list` and concatenate the resulting lists.
+        "concatMap" => concat_map(strict f, strict list);
+        /// Join the strings in `list`, with `separator` between each.
+        "concatStringsSep" => concat_strings_sep(strict separator, strict list);
+        /// Evaluate `e1` deeply, then return `e2`.
+        "deepSeq" => deep_seq(lazy e1, lazy e2);
+        /// Build a derivation from the attribute set `attrs`.
+        "derivation" => derivation(strict attrs);
+        /// Return the directory part of the path `s`.
+        "dirOf" => dir_of(strict s);
+        /// Return the quotient of the numbers `e1` and `e2`.
+        "div" => div(strict e1, strict e2);
+        /// Return true if `x` is an element of the list `xs`.
+        "elem" => elem(strict x, strict xs);
+        /// Return element `n` of the list `xs`, counting from zero.
+        "elemAt" => elem_at(strict xs, strict n);
+        /// Fetch a Git repository.
+        "fetchGit" => fetch_git(strict args);
+        /// Download and unpack a tarball.
+        "fetchTarball" => fetch_tarball(strict args);
+        /// Download a file.
+        "fetchurl" => fetchurl(strict url);
+        /// Return the elements of `list` for which `f` returns true.
+        "filter" => filter(strict f, strict list);
+        /// Copy the path `e2` to the store, keeping only the files for
+        /// which `e1` returns true.
+        "filterSource" => filter_source(strict e1, strict e2);
+        /// Find `name` in the search path `path`, a list of
+        /// `{ prefix, path }` sets. `<name>` is shorthand for
+        /// `__findFile __nixPath "name"`.
+        "findFile" => find_file(strict path, strict name);
+        /// Round the float `double` down to the nearest integer.
+        "floor" => floor(strict double);
+        /// Reduce `list` from the left with `op`, starting from `nul` and
+        /// forcing the accumulator at each step.
+        "foldl'" => foldl(strict op, strict nul, strict list);
+        /// Parse the JSON string `e` into a Nix value.
+        "fromJSON" => from_json(strict e);
+        /// Parse the TOML string `e` into a Nix value.
+        "fromTOML" => from_toml(strict e);
+        /// Return the formal arguments of the function `f`, mapped to
+        /// whether they have a default.
+        "functionArgs" => function_args(strict f);
+        /// Build a list of `length` elements, where element `i` is
+        /// `generator i`.
+        "genList" => gen_list(strict generator, strict length);
+        /// Return the attribute `s` of `set`.
+        "getAttr" => get_attr(strict s, strict set);
+        /// Return the store paths the string `s` refers to, as an attribute
+        /// set from each path to `{ path = true; }`.
+        "getContext" => get_context(strict s);
+        /// Return the value of the environment variable `s`, or an empty
+        /// string if it's unset.
+        "getEnv" => get_env(strict s);
+        /// Return true if `set` has an attribute named `s`.
+        "hasAttr" => has_attr(strict s, strict set);
+        /// Return true if the string `s` refers to any store paths.
+        "hasContext" => has_context(strict s);
+        /// Return the base-16 hash of the file `p`, using the hash algorithm
+        /// `algo`.
+        "hashFile" => hash_file(strict algo, strict p);
+        /// Return the base-16 hash of the string `s`, using the hash
+        /// algorithm `algo`.
+        "hashString" => hash_string(strict algo, strict s);
+        /// Return the first element of `list`.
+        "head" => head(strict list);
+        /// Load and evaluate the Nix file at `path`.
+        "import" => import(strict path);
+        /// Return the attributes of `e2` whose names are also in `e1`.
+        "intersectAttrs" => intersect_attrs(strict e1, strict e2);
+        /// Return true if `e` is an attribute set.
+        "isAttrs" => is_attrs(strict e);
+        /// Return true if `e` is a boolean.
+        "isBool" => is_bool(strict e);
+        /// Return true if `e` is a float.
+        "isFloat" => is_float(strict e);
+        /// Return true if `e` is a function.
+        "isFunction" => is_function(strict e);
+        /// Return true if `e` is an integer.
+        "isInt" => is_int(strict e);
+        /// Return true if `e` is a list.
+        "isList" => is_list(strict e);
+        /// Return true if `e` is null.
+        "isNull" => is_null(strict e);
+        /// Return true if `e` is a path.
+        "isPath" => is_path(strict e);
+        /// Return true if `e` is a string.
+        "isString" => is_string(strict e);
+        /// Return the number of elements in the list `e`.
+        "length" => length(strict e);
+        /// Return true if the number `e1` is less than `e2`.
+        "lessThan" => less_than(strict e1, strict e2);
+        /// Build an attribute set from a list of `{ name, value }` sets.
+        "listToAttrs" => list_to_attrs(strict e);
+        /// Apply `f` to every element of `list`.
+        "map" => map(strict f, strict list);
+        /// Apply `f` to the name and value of every attribute of `attrset`.
+        "mapAttrs" => map_attrs(strict f, strict attrset);
+        /// Match `str` against the regular expression `regex`, returning the
+        /// capture groups, or null if it doesn't match.
+        "match" => f_match(strict regex, strict str);
+        /// Return the product of the numbers `e1` and `e2`.
+        "mul" => mul(strict e1, strict e2);
+        /// Split the package name `s` into `{ name, version }`.
+        "parseDrvName" => parse_drv_name(strict s);
+        /// Split `list` into the elements for which `pred` returns true
+        /// (`right`) and false (`wrong`).
+        "partition" => partition(strict pred, strict list);
+        /// Copy a path to the store, with more control than `filterSource`.
+        "path" => path(strict args);
+        /// Return true if `path` exists.
+        "pathExists" => path_exists(strict path);
+        /// Return a placeholder for the output `output` of the derivation
+        /// being built.
+        "placeholder" => placeholder(strict output);
+        /// Return the entries of the directory `path`, mapped to their types.
+        "readDir" => read_dir(strict path);
+        /// Return the contents of the file `path` as a string.
+        "readFile" => read_file(strict path);
+        /// Remove the attributes named in `list` from `set`.
+        "removeAttrs" => remove_attrs(strict set, strict list);
+        /// Replace every occurrence of the strings in `from` in `s` with the
+        /// corresponding string in `to`.
+        "replaceStrings" => replace_strings(strict from, strict to, strict s);
+        /// Evaluate `e1`, then return `e2`.
+        "seq" => seq(strict e1, lazy e2);
+        /// Sort `list` using `comparator`, which returns true if its first
+        /// argument is less than its second.
+        "sort" => sort(strict comparator, strict list);
+        /// Split `str` on the matches of the regular expression `regex`.
+        "split" => split(strict regex, strict str);
+        /// Split the version string `s` into its components.
+        "splitVersion" => split_version(strict s);
+        /// Declare that `path` is in the store.
+        "storePath" => store_path(strict path);
+        /// Return the length of the string `e`, in bytes.
+        "stringLength" => string_length(strict e);
+        /// Return the difference of the numbers `e1` and `e2`.
+        "sub" => sub(strict e1, strict e2);
+        /// Return the `len` bytes of `s` starting at byte `start`.
+        "substring" => substring(strict start, strict len, strict s);
+        /// Return all but the first element of `list`.
+        "tail" => tail(strict list);
+        /// Throw an error with the message `s`, which must be coercible to
+        /// a string. `tryEval` can catch it.
+        "throw" => throw(strict s);
+        /// Write the string `s` to a store file called `name`.
+        "toFile" => to_file(strict name, strict s);
+        /// Serialise `e` as JSON, copying the paths in it to the store.
+        "toJSON" => to_json(strict e);
+        /// Convert the string `path` to a path.
+        "toPath" => to_path(strict path);
+        /// Convert `e` to a string.
+        "toString" => to_string(strict e);
+        /// Serialise `e` as XML.
+        "toXML" => to_xml(strict e);
+        /// Report `e1` as a trace, then return `e2`.
+        "trace" => trace(strict e1, lazy e2);
+        /// Like `trace`, but only reports `e1` if verbose tracing is enabled.
+        "traceVerbose" => trace_verbose(lazy e1, lazy e2);
+        /// Evaluate `e`, returning `{ success, value }` rather than failing.
+        "tryEval" => try_eval(lazy e);
+        /// Return the name of the type of `e`.
+        "typeOf" => type_of(strict e);
+        /// Return the string `s` without the store paths it refers to.
+        "unsafeDiscardStringContext" => unsafe_discard_string_context(strict s);
+        /// Report the string `msg` as a warning, then return `e`, unless
+        /// warnings abort evaluation.
+        "warn" => warn(strict msg, lazy e);
     }
+}
 
-    add(&mut s, "derivation", definitions::derivation);
-    add(&mut s, "abort", definitions::abort);
-    add(&mut s, "add", definitions::add);
-    add(&mut s, "all", definitions::all);
-    add(&mut s, "any", definitions::any);
-    add(&mut s, "attrNames", definitions::attr_names);
-    add(&mut s, "attrValues", definitions::attr_values);
-    add(&mut s, "baseNameOf", definitions::base_name_of);
-    add(&mut s, "bitAnd", definitions::bit_and);
-    add(&mut s, "bitOr", definitions::bit_or);
-    add(&mut s, "bitXor", definitions::bit_xor);
-    add(&mut s, "catAttrs", definitions::cat_attrs);
-    add(&mut s, "ceil", definitions::ceil);
-    add(&mut s, "compareVersions", definitions::compare_versions);
-    add(&mut s, "concatLists", definitions::concat_lists);
-    add(&mut s, "concatMap", definitions::concat_map);
-    add(&mut s, "concatStringsSep", definitions::concat_strings_sep);
-    add(&mut s, "deepSeq", definitions::deep_seq);
-    add(&mut s, "dirOf", definitions::dir_of);
-    add(&mut s, "div", definitions::div);
-    add(&mut s, "elem", definitions::elem);
-    add(&mut s, "elemAt", definitions::elem_at);
-    add(&mut s, "fetchGit", definitions::fetch_git);
-    add(&mut s, "fetchTarball", definitions::fetch_tarball);
-    add(&mut s, "fetchurl", definitions::fetchurl);
-    add(&mut s, "filter", definitions::filter);
-    add(&mut s, "filterSource", definitions::filter_source);
-    add(&mut s, "floor", definitions::floor);
-    add(&mut s, "foldl'", definitions::foldl);
-    add(&mut s, "fromJSON", definitions::from_json);
-    add(&mut s, "functionArgs", definitions::function_args);
-    add(&mut s, "genList", definitions::gen_list);
-    add(&mut s, "getAttr", definitions::get_attr);
-    add(&mut s, "getEnv", definitions::get_env);
-    add(&mut s, "hasAttr", definitions::has_attr);
-    add(&mut s, "hashFile", definitions::hash_file);
-    add(&mut s, "hashString", definitions::hash_string);
-    add(&mut s, "head", definitions::head);
-    add(&mut s, "import", definitions::import);
-    add(&mut s, "intersectAttrs", definitions::intersect_attrs);
-    add(&mut s, "isAttrs", definitions::is_attrs);
-    add(&mut s, "isBool", definitions::is_bool);
-    add(&mut s, "isFloat", definitions::is_float);
-    add(&mut s, "isFunction", definitions::is_function);
-    add(&mut s, "isInt", definitions::is_int);
-    add(&mut s, "isList", definitions::is_list);
-    add(&mut s, "isNull", definitions::is_null);
-    add(&mut s, "isPath", definitions::is_path);
-    add(&mut s, "isString", definitions::is_string);
-    add(&mut s, "length", definitions::length);
-    add(&mut s, "lessThan", definitions::less_than);
-    add(&mut s, "listToAttrs", definitions::list_to_attrs);
-    add(&mut s, "map", definitions::map);
-    add(&mut s, "mapAttrs", definitions::map_attrs);
-    add(&mut s, "match", definitions::f_match);
-    add(&mut s, "mul", definitions::mul);
-    add(&mut s, "parseDrvName", definitions::parse_drv_name);
-    add(&mut s, "partition", definitions::partition);
-    add(&mut s, "path", definitions::path);
-    add(&mut s, "pathExists", definitions::path_exists);
-    add(&mut s, "placeholder", definitions::placeholder);
-    add(&mut s, "readDir", definitions::read_dir);
-    add(&mut s, "readFile", definitions::read_file);
-    add(&mut s, "removeAttrs", definitions::remove_attrs);
-    add(&mut s, "replaceStrings", definitions::replace_strings);
-    add(&mut s, "seq", definitions::seq);
-    add(&mut s, "sort", definitions::sort);
-    add(&mut s, "split", definitions::split);
-    add(&mut s, "splitVersion", definitions::split_version);
-    add(&mut s, "storePath", definitions::store_path);
-    add(&mut s, "stringLength", definitions::string_length);
-    add(&mut s, "sub", definitions::sub);
-    add(&mut s, "substring", definitions::substring);
-    add(&mut s, "tail", definitions::tail);
-    add(&mut s, "throw", definitions::throw);
-    add(&mut s, "toFile", definitions::to_file);
-    add(&mut s, "toJSON", definitions::to_json);
-    add(&mut s, "toPath", definitions::to_path);
-    add(&mut s, "toString", definitions::to_string);
-    add(&mut s, "toXML", definitions::to_xml);
-    add(&mut s, "trace", definitions::trace);
-    add(&mut s, "tryEval", definitions::try_eval);
-    add(&mut s, "typeOf", definitions::type_of);
-
-    Value::AttrSet(s)
+pub fn builtins_set() -> Value {
+    let mut set: Attrs = primops()
+        .into_iter()
+        .map(|op| (op.name().into(), Value::PrimOp(Rc::new(op))))
+        .collect();
+    set.insert_mut("nixPath".into(), Value::List(Default::default()));
+    Value::AttrSet(set)
 }
 
-pub fn base_context() -> HashTrieMap<String, Value> {
-    let mut s = HashTrieMap::new();
+/// Builtins that are also available without the `builtins.` prefix.
+pub(crate) const GLOBAL_BUILTINS: &[&str] = &[
+    "abort",
+    "baseNameOf",
+    "derivation",
+    "dirOf",
+    "fetchTarball",
+    "import",
+    "isNull",
+    "map",
+    "removeAttrs",
+    "throw",
+    "toString",
+];
+
+pub fn base_context() -> Attrs {
     let builtins = builtins_set();
-    fn add<F: 'static + Fn(Value) -> Result>(
-        s: &mut HashTrieMap<String, Value>,
-        name: &'static str,
-        f: F,
-    ) {
-        s.insert_mut(name.to_string(), Value::BuiltinFunction(Rc::new(f)));
+    let mut s = Attrs::new();
+    if let Value::AttrSet(set) = &builtins {
+        for name in GLOBAL_BUILTINS {
+            if let Some(op) = set.get(*name) {
+                s.insert_mut((*name).into(), op.to_owned());
+            }
+        }
+        if let (Some(find_file), Some(nix_path)) = (set.get("findFile"), set.get("nixPath")) {
+            s.insert_mut("__findFile".into(), find_file.to_owned());
+            s.insert_mut("__nixPath".into(), nix_path.to_owned());
+        }
     }
-
-    s.insert_mut("builtins".to_string(), builtins);
-    add(&mut s, "derivation", definitions::derivation);
-    add(&mut s, "import", definitions::import);
-
+    s.insert_mut("builtins".into(), builtins);
+    s.insert_mut("true".into(), Value::Boolean(true));
+    s.insert_mut("false".into(), Value::Boolean(false));
+    s.insert_mut("null".into(), Value::Null);
     s
 }
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::{
+    builtins::{BuiltinError, Result},
+    evaluator::{self, at_call_site},
+    ir::CallSite,
+    profiler::{self, Entry},
+    stats,
+    sync::{MaybeSend, Rc},
+    value::Value,
+};
+
+#[cfg(not(feature = "sync"))]
+pub type PrimOpFn = dyn Fn(Vec<Value>) -> Result;
+#[cfg(feature = "sync")]
+pub type PrimOpFn = dyn Fn(Vec<Value>) -> Result + Send + Sync;
+
+/// An argument of a [`PrimOp`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arg {
+    pub name: &'static str,
+    /// Whether the argument is forced before the primop is called. Lazy
+    /// arguments are passed through as they are, possibly as thunks.
+    pub strict: bool,
+}
+
+/// A built-in function implemented in Rust.
+///
+/// A primop is only called once all of its arguments have been supplied.
+/// Until then, applying it builds up a [`Value::PrimOpApp`].
+pub struct PrimOp {
+    name: &'static str,
+    args: &'static [Arg],
+    doc: &'static [&'static str],
+    func: Box<PrimOpFn>,
+}
+
+impl PrimOp {
+    /// Define a primop. `func` is passed exactly one value per entry in
+    /// `args`, with the strict ones already forced. `doc` is a list of lines,
+    /// as produced by doc comments.
+    ///
+    /// Fails if `args` is empty, since a primop is only called when applied
+    /// to its last argument. Constants are better bound as globals.
+    pub fn new<F: 'static + Fn(Vec<Value>) -> Result + MaybeSend>(
+        name: &'static str,
+        args: &'static [Arg],
+        doc: &'static [&'static str],
+        func: F,
+    ) -> std::result::Result<Self, BuiltinError> {
+        if args.is_empty() {
+            return Err(BuiltinError::NoArguments(name.into()));
+        }
+        Ok(Self {
+            name,
+            args,
+            doc,
+            func: Box::new(func),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.args.len()
+    }
+
+    pub fn args(&self) -> &'static [Arg] {
+        self.args
+    }
+
+    pub fn doc(&self) -> String {
+        self.doc
+            .iter()
+            .map(|x| x.strip_prefix(' ').unwrap_or(x))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Apply the primop to `arg`, after the arguments in `applied`. Calls the
+    /// primop if that was its last argument, with `site` as its call site.
+    pub(crate) fn apply(
+        self: &Rc<Self>,
+        applied: &[Value],
+        arg: Value,
+        site: Option<&CallSite>,
+    ) -> Result {
+        let mut args = Vec::with_capacity(self.arity());
+        args.extend_from_slice(applied);
+        args.push(arg);
+        if args.len() < self.arity() {
+            return Ok(Value::PrimOpApp(self.to_owned(), args.into()));
+        }
+        for (x, arg) in args.iter_mut().zip(self.args) {
+            if arg.strict {
+                *x = x.to_owned().materialize()?;
+            }
+        }
+        evaluator::record(|x| *x.primop_calls.entry(self.name).or_default() += 1);
+        evaluator::limit(|x| x.step(stats::depth() + 1))?;
+        let res = at_call_site(site, || {
+            profiler::frame(|| Entry::PrimOp(self.name), || (self.func)(args))
+        })?;
+        evaluator::limit(|x| x.check(&res))?;
+        Ok(res)
+    }
+}
+
+impl fmt::Debug for PrimOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrimOp")
+            .field("name", &self.name)
+            .field("args", &self.args)
+            .finish()
+    }
+}
+
+/// Build a list of [`PrimOp`]s from declarations of the form
+///
+/// ```text
+/// /// Documentation.
+/// "name" => function(strict a, lazy b);
+/// ```
+///
+/// where `function` takes one `Value` per argument. Every primop takes at
+/// least one.
+macro_rules! primops {
+    ($($(#[doc = $doc:literal])* $name:literal => $f:ident ($($strictness:ident $arg:ident),+);)*) => {
+        vec![$(
+            $crate::builtins::PrimOp::new(
+                $name,
+                &[$($crate::builtins::Arg {
+                    name: stringify!($arg),
+                    strict: primops!(@strict $strictness),
+                }),+],
+                &[$($doc),*],
+                |args| {
+                    let mut args = args.into_iter();
+                    $f($({
+                        let $arg = args.next().expect("BUG: primop called with too few arguments");
+                        $arg
+                    }),+)
+                },
+            )
+            .expect("BUG: primop declared without arguments"),
+        )*]
+    };
+    (@strict strict) => { true };
+    (@strict lazy) => { false };
+}
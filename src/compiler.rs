@@ -0,0 +1,661 @@
+use std::path::{Component, Path, PathBuf};
+
+use rnix::{types::*, value::Anchor, NixValue, SyntaxKind, SyntaxNode, TextRange};
+
+use crate::{
+    evaluator::{EvalError, EvaluationContext},
+    ir::{self, AttrKey, Expr, ExprRef, Formal, Param, StrPart, Var},
+    symbol::Symbol,
+    sync::Rc,
+    value::Value,
+};
+
+type Result<T> = std::result::Result<T, EvalError>;
+
+/// A scope known at compile time, mirroring the scope that will exist at
+/// runtime.
+enum Scope {
+    Bindings(Vec<Symbol>),
+    With,
+}
+
+/// The attributes of a set, `let` or `rec` block while they're being
+/// collected, so that `a.b = 1; a.c = 2;` can be merged into a single
+/// nested set.
+#[derive(Default)]
+struct AttrBuilder {
+    attrs: Vec<(Symbol, Binding)>,
+    dynamic: Vec<(ExprRef, Binding)>,
+}
+
+enum Binding {
+    Expr(ExprRef),
+    Nested(AttrBuilder),
+}
+
+impl AttrBuilder {
+    fn finish(self) -> ir::AttrSet {
+        ir::AttrSet {
+            attrs: self
+                .attrs
+                .into_iter()
+                .map(|(k, v)| (k, v.finish()))
+                .collect(),
+            dynamic: self
+                .dynamic
+                .into_iter()
+                .map(|(k, v)| (k, v.finish()))
+                .collect(),
+        }
+    }
+}
+
+impl Binding {
+    fn finish(self) -> ExprRef {
+        match self {
+            Binding::Expr(x) => x,
+            Binding::Nested(x) => Rc::new(Expr::AttrSet(Rc::new(x.finish()))),
+        }
+    }
+}
+
+struct Compiler<'a> {
+    globals: &'a EvaluationContext,
+    scopes: Vec<Scope>,
+    file: Rc<str>,
+    /// The directory relative paths are resolved against, if any. Without
+    /// one they're left as they are.
+    dir: Option<PathBuf>,
+    /// The offset of the start of each line of the source.
+    lines: Vec<usize>,
+    source: String,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(node: &SyntaxNode, globals: &'a EvaluationContext, scopes: Vec<Scope>) -> Self {
+        let source = node.text().to_string();
+        let lines = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self {
+            globals,
+            scopes,
+            file: "«string»".into(),
+            dir: None,
+            lines,
+            source,
+        }
+    }
+
+    fn location(&self, offset: usize) -> ir::Location {
+        let line = self.lines.partition_point(|x| *x <= offset) - 1;
+        let column = self.source[self.lines[line]..offset].chars().count();
+        ir::Location {
+            file: self.file.clone(),
+            line: line as u32 + 1,
+            column: column as u32 + 1,
+        }
+    }
+
+    fn call_site(&self, span: TextRange) -> ir::CallSite {
+        ir::CallSite {
+            span,
+            location: self.location(span.start().into()),
+        }
+    }
+}
+
+/// Lower a syntax tree into IR, resolving free variables against `globals`.
+pub fn compile(node: SyntaxNode, globals: &EvaluationContext) -> Result<ExprRef> {
+    Compiler::new(&node, globals, Vec::new()).compile(node)
+}
+
+/// Lower the syntax tree of `file`, resolving relative paths against `dir`.
+pub(crate) fn compile_file(
+    node: SyntaxNode,
+    globals: &EvaluationContext,
+    file: Rc<str>,
+    dir: Option<&Path>,
+) -> Result<ExprRef> {
+    let mut compiler = Compiler::new(&node, globals, Vec::new());
+    compiler.file = file;
+    compiler.dir = dir.map(ToOwned::to_owned);
+    compiler.compile(node)
+}
+
+/// Lower a syntax tree into IR to be evaluated inside existing scopes, given
+/// the names of their slots outermost first, with `None` for `with` scopes.
+pub(crate) fn compile_in(
+    node: SyntaxNode,
+    globals: &EvaluationContext,
+    scopes: Vec<Option<Rc<[Symbol]>>>,
+) -> Result<ExprRef> {
+    let scopes = scopes
+        .into_iter()
+        .map(|x| match x {
+            Some(names) => Scope::Bindings(names.to_vec()),
+            None => Scope::With,
+        })
+        .collect();
+    Compiler::new(&node, globals, scopes).compile(node)
+}
+
+/// Remove `.` and `..` from a path without looking at the file system, as
+/// Nix does.
+fn normalize(path: &Path) -> String {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                res.pop();
+            }
+            x => res.push(x),
+        }
+    }
+    res.to_string_lossy().into_owned()
+}
+
+fn cast<T: TypedNode>(from: SyntaxNode) -> Result<T> {
+    T::cast(from).ok_or(EvalError::Mismatch)
+}
+
+fn expect_child<T>(x: Option<T>) -> Result<T> {
+    x.ok_or(EvalError::MissingChildren)
+}
+
+/// A non-recursive attribute set literal, which can be merged with other
+/// definitions of the same attribute.
+fn mergeable(node: &SyntaxNode) -> Option<AttrSet> {
+    AttrSet::cast(node.clone()).filter(|x| !x.recursive())
+}
+
+/// Flatten `a.b.c` into `a` and the path `[b, c]`.
+fn flatten_select(node: Select) -> Result<(SyntaxNode, Vec<SyntaxNode>)> {
+    let set = expect_child(node.set())?;
+    let index = expect_child(node.index())?;
+    let (base, mut path) = match Select::cast(set.clone()) {
+        Some(inner) => flatten_select(inner)?,
+        None => (set, Vec::new()),
+    };
+    path.push(index);
+    Ok((base, path))
+}
+
+impl<'a> Compiler<'a> {
+    fn compile(&mut self, node: SyntaxNode) -> Result<ExprRef> {
+        Ok(Rc::new(match node.kind() {
+            SyntaxKind::NODE_ROOT => {
+                return self.compile(expect_child(cast::<Root>(node)?.inner())?)
+            }
+            SyntaxKind::NODE_PAREN => {
+                return self.compile(expect_child(cast::<Paren>(node)?.inner())?)
+            }
+            SyntaxKind::NODE_LITERAL => self.compile_literal(cast(node)?)?,
+            SyntaxKind::NODE_IDENT => {
+                return self.resolve(&Symbol::new(cast::<Ident>(node)?.as_str()), 0)
+            }
+            SyntaxKind::NODE_STRING => self.compile_string(cast(node)?)?,
+            SyntaxKind::NODE_LAMBDA => self.compile_lambda(cast(node)?)?,
+            SyntaxKind::NODE_APPLY => {
+                let apply: Apply = cast(node)?;
+                Expr::Apply {
+                    function: self.compile(expect_child(apply.lambda())?)?,
+                    argument: self.compile(expect_child(apply.value())?)?,
+                    site: self.call_site(apply.node().text_range()),
+                }
+            }
+            SyntaxKind::NODE_LIST => Expr::List(
+                cast::<List>(node)?
+                    .items()
+                    .map(|x| self.compile(x))
+                    .collect::<Result<_>>()?,
+            ),
+            SyntaxKind::NODE_ATTR_SET => self.compile_attr_set(cast(node)?)?,
+            SyntaxKind::NODE_LET_IN => self.compile_let_in(cast(node)?)?,
+            SyntaxKind::NODE_LEGACY_LET => {
+                // `let { ...; body = ...; }` is a recursive set projected
+                // onto its `body` attribute.
+                let node: LegacyLet = cast(node)?;
+                let set = Rc::new(self.compile_recursive(&node, true)?);
+                Expr::Select {
+                    set,
+                    path: vec![AttrKey::Static("body".into())],
+                    default: None,
+                    span: node.node().text_range(),
+                }
+            }
+            SyntaxKind::NODE_WITH => {
+                let with: With = cast(node)?;
+                let namespace = self.compile(expect_child(with.namespace())?)?;
+                self.scopes.push(Scope::With);
+                let body = self.compile(expect_child(with.body())?);
+                self.scopes.pop();
+                Expr::With {
+                    namespace,
+                    body: body?,
+                }
+            }
+            SyntaxKind::NODE_SELECT => {
+                let span = node.text_range();
+                let (set, path) = flatten_select(cast(node)?)?;
+                Expr::Select {
+                    set: self.compile(set)?,
+                    path: self.compile_attr_path(path)?,
+                    default: None,
+                    span,
+                }
+            }
+            SyntaxKind::NODE_OR_DEFAULT => {
+                let span = node.text_range();
+                let node: OrDefault = cast(node)?;
+                let (set, path) = flatten_select(expect_child(node.index())?)?;
+                Expr::Select {
+                    set: self.compile(set)?,
+                    path: self.compile_attr_path(path)?,
+                    default: Some(self.compile(expect_child(node.default())?)?),
+                    span,
+                }
+            }
+            SyntaxKind::NODE_BIN_OP => {
+                let op: BinOp = cast(node)?;
+                let lhs = self.compile(expect_child(op.lhs())?)?;
+                let rhs = expect_child(op.rhs())?;
+                match op.operator() {
+                    BinOpKind::IsSet => {
+                        // The right-hand side is an attribute path, which rnix
+                        // parses as a select expression.
+                        let path = match Select::cast(rhs.clone()) {
+                            Some(select) => {
+                                let (base, mut path) = flatten_select(select)?;
+                                path.insert(0, base);
+                                path
+                            }
+                            None => vec![rhs],
+                        };
+                        Expr::HasAttr {
+                            set: lhs,
+                            path: self.compile_attr_path(path)?,
+                        }
+                    }
+                    operator => Expr::BinOp {
+                        operator,
+                        lhs,
+                        rhs: self.compile(rhs)?,
+                    },
+                }
+            }
+            SyntaxKind::NODE_UNARY_OP => {
+                let op: UnaryOp = cast(node)?;
+                Expr::UnaryOp {
+                    operator: op.operator(),
+                    value: self.compile(expect_child(op.value())?)?,
+                }
+            }
+            SyntaxKind::NODE_IF_ELSE => {
+                let node: IfElse = cast(node)?;
+                Expr::If {
+                    condition: self.compile(expect_child(node.condition())?)?,
+                    body: self.compile(expect_child(node.body())?)?,
+                    else_body: self.compile(expect_child(node.else_body())?)?,
+                }
+            }
+            SyntaxKind::NODE_ASSERT => {
+                let node: Assert = cast(node)?;
+                Expr::Assert {
+                    condition: self.compile(expect_child(node.condition())?)?,
+                    body: self.compile(expect_child(node.body())?)?,
+                    span: node.node().text_range(),
+                }
+            }
+            _ => return Err(EvalError::UnexpectedNode),
+        }))
+    }
+
+    /// Resolve a variable, ignoring the innermost `skip` scopes. The result is
+    /// still relative to the innermost scope.
+    fn resolve(&self, name: &Symbol, skip: usize) -> Result<ExprRef> {
+        let mut withs = Vec::new();
+        for (depth, scope) in self.scopes.iter().rev().enumerate().skip(skip) {
+            match scope {
+                Scope::Bindings(names) => {
+                    if let Some(slot) = names.iter().position(|x| x == name) {
+                        return Ok(Rc::new(Expr::Var(Var::Local { depth, slot })));
+                    }
+                }
+                Scope::With => withs.push(depth),
+            }
+        }
+        // Globals are lexically scoped outside everything else, so they take
+        // precedence over `with`.
+        if let Some(v) = self.globals.get(name.as_str()) {
+            Ok(Rc::new(Expr::Constant(v.to_owned())))
+        } else if !withs.is_empty() {
+            Ok(Rc::new(Expr::Var(Var::Dynamic {
+                name: name.to_owned(),
+                withs,
+            })))
+        } else {
+            Err(EvalError::UnresolvedIdent(name.to_string().into()))
+        }
+    }
+
+    fn compile_literal(&mut self, node: rnix::types::Value) -> Result<Expr> {
+        let span = node.node().text_range();
+        Ok(Expr::Constant(match node.to_value()? {
+            NixValue::Float(x) => Value::Floating(x),
+            NixValue::Integer(x) => Value::Integer(x),
+            NixValue::String(x) => Value::String(x.into()),
+            NixValue::Path(Anchor::Relative, x) => match &self.dir {
+                Some(dir) => Value::Path(normalize(&dir.join(x))),
+                None => Value::Path(x),
+            },
+            // `<name>` is looked up in the search path, through whatever
+            // `__findFile` and `__nixPath` are in scope.
+            NixValue::Path(Anchor::Store, x) => {
+                let find_file = Rc::new(Expr::Apply {
+                    function: self.resolve(&Symbol::new("__findFile"), 0)?,
+                    argument: self.resolve(&Symbol::new("__nixPath"), 0)?,
+                    site: self.call_site(span),
+                });
+                return Ok(Expr::Apply {
+                    function: find_file,
+                    argument: Rc::new(Expr::Constant(x.into())),
+                    site: self.call_site(span),
+                });
+            }
+            NixValue::Path(_, x) => Value::Path(x),
+        }))
+    }
+
+    fn compile_string(&mut self, node: Str) -> Result<Expr> {
+        let mut parts = Vec::new();
+        for part in node.parts() {
+            match part {
+                rnix::StrPart::Literal(x) => parts.push(StrPart::Literal(x.into())),
+                rnix::StrPart::Ast(x) => {
+                    let inner = expect_child(x.first_child())?;
+                    parts.push(StrPart::Interpolation(self.compile(inner)?))
+                }
+            }
+        }
+        match parts.as_slice() {
+            [] => Ok(Expr::Constant("".into())),
+            [StrPart::Literal(x)] => Ok(Expr::Constant(x.to_owned().into())),
+            _ => Ok(Expr::String(parts)),
+        }
+    }
+
+    fn compile_lambda(&mut self, node: Lambda) -> Result<Expr> {
+        let arg = expect_child(node.arg())?;
+        let span = node.node().text_range();
+        let (param, names) = if let Some(pattern) = Pattern::cast(arg.clone()) {
+            let mut names = Vec::new();
+            let mut entries = Vec::new();
+            for entry in pattern.entries() {
+                names.push(Symbol::new(expect_child(entry.name())?.as_str()));
+                entries.push(entry);
+            }
+            let bind = pattern.at().map(|x| Symbol::new(x.as_str()));
+            names.extend(bind.iter().cloned());
+
+            // Default values can refer to the other arguments.
+            self.scopes.push(Scope::Bindings(names.clone()));
+            let formals = entries
+                .into_iter()
+                .zip(&names)
+                .map(|(entry, name)| {
+                    Ok(Formal {
+                        name: name.to_owned(),
+                        default: entry.default().map(|x| self.compile(x)).transpose()?,
+                    })
+                })
+                .collect::<Result<_>>();
+            self.scopes.pop();
+            (
+                Param::Pattern {
+                    formals: formals?,
+                    ellipsis: pattern.ellipsis(),
+                    bind,
+                },
+                names,
+            )
+        } else {
+            let name = Symbol::new(cast::<Ident>(arg)?.as_str());
+            (Param::Ident(name.clone()), vec![name])
+        };
+
+        self.scopes.push(Scope::Bindings(names.clone()));
+        let body = self.compile(expect_child(node.body())?);
+        self.scopes.pop();
+        Ok(Expr::Lambda(Rc::new(ir::Lambda {
+            param,
+            names: names.into(),
+            body: body?,
+            span,
+            location: self.location(span.start().into()),
+        })))
+    }
+
+    fn compile_attr_key(&mut self, node: SyntaxNode) -> Result<AttrKey> {
+        match node.kind() {
+            SyntaxKind::NODE_IDENT => {
+                Ok(AttrKey::Static(Symbol::new(cast::<Ident>(node)?.as_str())))
+            }
+            _ => {
+                let expr = match Dynamic::cast(node.clone()) {
+                    Some(dynamic) => self.compile(expect_child(dynamic.inner())?)?,
+                    None => self.compile(node)?,
+                };
+                match &*expr {
+                    Expr::Constant(Value::String(x)) => Ok(AttrKey::Static(Symbol::from_bytes(x))),
+                    _ => Ok(AttrKey::Dynamic(expr)),
+                }
+            }
+        }
+    }
+
+    fn compile_attr_path(&mut self, path: Vec<SyntaxNode>) -> Result<Vec<AttrKey>> {
+        path.into_iter().map(|x| self.compile_attr_key(x)).collect()
+    }
+
+    /// The name of an attribute whose key starts with `first`, if it's known
+    /// without compiling the key: an identifier or a string without
+    /// interpolation.
+    fn static_name(first: SyntaxNode) -> Result<Option<Symbol>> {
+        Ok(match first.kind() {
+            SyntaxKind::NODE_IDENT => Some(Symbol::new(cast::<Ident>(first)?.as_str())),
+            SyntaxKind::NODE_STRING => match cast::<Str>(first)?.parts().as_slice() {
+                [rnix::StrPart::Literal(x)] => Some(Symbol::new(x)),
+                _ => None,
+            },
+            _ => None,
+        })
+    }
+
+    /// The names of the attributes a block defines statically, in order of
+    /// first definition. These become the slots of a recursive scope.
+    fn static_names<T: EntryHolder>(&mut self, node: &T) -> Result<Vec<Symbol>> {
+        let mut names: Vec<Symbol> = Vec::new();
+        for child in node.node().children() {
+            let mut found = Vec::new();
+            if let Some(inherit) = Inherit::cast(child.clone()) {
+                found.extend(inherit.idents().map(|x| Symbol::new(x.as_str())));
+            } else if let Some(kv) = KeyValue::cast(child) {
+                let first = expect_child(expect_child(kv.key())?.path().next())?;
+                found.extend(Self::static_name(first)?);
+            }
+            for name in found {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Add the entries and `inherit`s of `node` to `builder`. In the outermost
+    /// level of a recursive block, plain `inherit`s refer to the enclosing
+    /// scope rather than to the block itself.
+    fn add_entries<T: EntryHolder>(
+        &mut self,
+        builder: &mut AttrBuilder,
+        node: &T,
+        recursive: bool,
+    ) -> Result<()> {
+        for child in node.node().children() {
+            if let Some(inherit) = Inherit::cast(child.clone()) {
+                let from = match inherit.from() {
+                    Some(from) => Some(self.compile(expect_child(from.inner())?)?),
+                    None => None,
+                };
+                for ident in inherit.idents() {
+                    let name = Symbol::new(ident.as_str());
+                    let value = match &from {
+                        Some(from) => Rc::new(Expr::Select {
+                            set: from.clone(),
+                            path: vec![AttrKey::Static(name.clone())],
+                            default: None,
+                            span: ident.node().text_range(),
+                        }),
+                        None => self.resolve(&name, if recursive { 1 } else { 0 })?,
+                    };
+                    self.insert(builder, vec![AttrKey::Static(name)], Err(value))?;
+                }
+            } else if let Some(kv) = KeyValue::cast(child) {
+                let path = expect_child(kv.key())?.path().collect();
+                let path = self.compile_attr_path(path)?;
+                self.insert(builder, path, Ok(expect_child(kv.value())?))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Insert a value at `path`, which is either syntax still to be compiled
+    /// or an already-compiled expression.
+    fn insert(
+        &mut self,
+        builder: &mut AttrBuilder,
+        mut path: Vec<AttrKey>,
+        value: std::result::Result<SyntaxNode, ExprRef>,
+    ) -> Result<()> {
+        let key = path.remove(0);
+        let existing = match &key {
+            AttrKey::Static(name) => builder
+                .attrs
+                .iter_mut()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v),
+            AttrKey::Dynamic(_) => None,
+        };
+        let duplicate = || match &key {
+            AttrKey::Static(name) => EvalError::DuplicateAttr(name.to_owned().into()),
+            AttrKey::Dynamic(_) => EvalError::DuplicateAttr("(dynamic)".into()),
+        };
+
+        let binding = match existing {
+            Some(Binding::Nested(nested)) => {
+                if !path.is_empty() {
+                    return self.insert(nested, path, value);
+                }
+                match value.as_ref().ok().and_then(mergeable) {
+                    Some(set) => return self.add_entries(nested, &set, false),
+                    None => return Err(duplicate()),
+                }
+            }
+            Some(Binding::Expr(_)) => return Err(duplicate()),
+            None => {
+                if !path.is_empty() {
+                    let mut nested = AttrBuilder::default();
+                    self.insert(&mut nested, path, value)?;
+                    Binding::Nested(nested)
+                } else {
+                    match value {
+                        Ok(node) => match mergeable(&node) {
+                            Some(set) => {
+                                let mut nested = AttrBuilder::default();
+                                self.add_entries(&mut nested, &set, false)?;
+                                Binding::Nested(nested)
+                            }
+                            None => Binding::Expr(self.compile(node)?),
+                        },
+                        Err(expr) => Binding::Expr(expr),
+                    }
+                }
+            }
+        };
+        match key {
+            AttrKey::Static(name) => builder.attrs.push((name, binding)),
+            AttrKey::Dynamic(key) => builder.dynamic.push((key, binding)),
+        }
+        Ok(())
+    }
+
+    fn compile_attr_set(&mut self, node: AttrSet) -> Result<Expr> {
+        if node.recursive() {
+            self.compile_recursive(&node, false)
+        } else {
+            let mut builder = AttrBuilder::default();
+            self.add_entries(&mut builder, &node, false)?;
+            Ok(Expr::AttrSet(Rc::new(builder.finish())))
+        }
+    }
+
+    /// Compile a `rec { }` set: a scope with one slot per static attribute,
+    /// in which all of the attributes are evaluated.
+    fn compile_recursive<T: EntryHolder>(&mut self, node: &T, is_let: bool) -> Result<Expr> {
+        let names = self.static_names(node)?;
+        self.scopes.push(Scope::Bindings(names.clone()));
+        let mut builder = AttrBuilder::default();
+        let res = self.add_entries(&mut builder, node, true);
+        self.scopes.pop();
+        res?;
+
+        // Keys like `${"x"}` are dynamic even though compiling them folds
+        // them into constants.
+        if is_let {
+            for kv in node.entries() {
+                let first = expect_child(expect_child(kv.key())?.path().next())?;
+                if Self::static_name(first)?.is_none() {
+                    return Err(EvalError::DynamicLet);
+                }
+            }
+        }
+        let mut set = builder.finish();
+        // Put the attributes in the slots their names were compiled with.
+        // Those with keys that were folded into constants aren't variables,
+        // and take the slots after them.
+        let mut attrs = Vec::with_capacity(set.attrs.len());
+        for name in &names {
+            let i = set
+                .attrs
+                .iter()
+                .position(|(k, _)| k == name)
+                .ok_or(EvalError::Mismatch)?;
+            attrs.push(set.attrs.remove(i));
+        }
+        attrs.append(&mut set.attrs);
+        set.attrs = attrs;
+        let names = set.attrs.iter().map(|(k, _)| k.clone()).collect();
+        Ok(Expr::RecAttrSet {
+            set: Rc::new(set),
+            names,
+        })
+    }
+
+    fn compile_let_in(&mut self, node: LetIn) -> Result<Expr> {
+        let (set, names) = match self.compile_recursive(&node, true)? {
+            Expr::RecAttrSet { set, names } => (set, names),
+            _ => return Err(EvalError::Mismatch),
+        };
+        let bindings = set.attrs.iter().map(|(_, v)| v.clone()).collect();
+        self.scopes.push(Scope::Bindings(names.to_vec()));
+        let body = self.compile(expect_child(node.body())?);
+        self.scopes.pop();
+        Ok(Expr::Let {
+            names,
+            bindings,
+            body: body?,
+        })
+    }
+}
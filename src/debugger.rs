@@ -0,0 +1,227 @@
+//! Pausing evaluation to inspect it, on `builtins.break`, on errors, or one
+//! step at a time.
+
+use std::cell::RefCell;
+
+use rnix::{SyntaxNode, TextRange};
+
+use crate::{
+    compiler::compile_in,
+    evaluator::{eval_expr, Env, EvalError, EvaluationContext},
+    symbol::Symbol,
+    sync::{Lock, MaybeSend, Rc},
+    value::Value,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// A function application.
+    Apply,
+    /// A thunk being forced.
+    Force,
+}
+
+/// A function application or thunk force that's being evaluated.
+#[derive(Clone)]
+pub struct Frame {
+    kind: FrameKind,
+    span: Option<TextRange>,
+    env: Env,
+}
+
+impl Frame {
+    pub fn kind(&self) -> FrameKind {
+        self.kind
+    }
+
+    /// The source range being evaluated, if known.
+    pub fn span(&self) -> Option<TextRange> {
+        self.span
+    }
+
+    /// The variables in scope, innermost first. Their values may be thunks
+    /// that haven't been evaluated yet.
+    pub fn variables(&self) -> Vec<(Symbol, Value)> {
+        self.env.variables()
+    }
+
+    /// Evaluate `node` as if it appeared in this frame, with `globals` as
+    /// the variables outside of every scope.
+    pub fn eval(&self, node: SyntaxNode, globals: &EvaluationContext) -> Result<Value, EvalError> {
+        let expr = compile_in(node, globals, self.env.scope_names())?;
+        eval_expr(&expr, &self.env)
+    }
+}
+
+#[derive(Debug)]
+pub enum PauseReason<'a> {
+    /// `builtins.break` was called.
+    Break,
+    /// An error occurred in the innermost frame. Errors that an enclosing
+    /// `builtins.tryEval` catches don't pause.
+    Error(&'a EvalError),
+    /// The previous pause asked to step to the next frame.
+    Step,
+}
+
+/// The state of a paused evaluation.
+pub struct Paused<'a> {
+    pub reason: PauseReason<'a>,
+    /// The frames being evaluated, innermost first.
+    pub frames: &'a [Frame],
+}
+
+/// How to carry on after a pause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until the next `builtins.break` or error.
+    Continue,
+    /// Pause again when the next frame is entered.
+    Step,
+}
+
+/// A front-end for inspecting paused evaluations, such as an interactive
+/// prompt.
+///
+/// Evaluation done by the debugger while paused, such as through
+/// [`Frame::eval`], never pauses again.
+pub trait Debugger: MaybeSend {
+    fn pause(&self, paused: &Paused<'_>) -> Resume;
+}
+
+/// A debugger attached to a session.
+pub(crate) struct Debugging {
+    debugger: Rc<dyn Debugger>,
+    /// Kept here rather than per thread, so a step can end in a thunk that's
+    /// forced after the session's `eval` returns.
+    stepping: Lock<bool>,
+}
+
+impl Debugging {
+    pub(crate) fn new(debugger: Rc<dyn Debugger>) -> Self {
+        Self {
+            debugger,
+            stepping: Lock::new(false),
+        }
+    }
+}
+
+struct Stack {
+    debugging: Rc<Debugging>,
+    frames: Vec<Frame>,
+    /// Whether the error currently propagating has already paused, so that
+    /// it only does so in the frame it came from.
+    error_paused: bool,
+    /// How many calls to `builtins.tryEval` are being evaluated.
+    catching: usize,
+}
+
+thread_local! {
+    static STACK: RefCell<Option<Stack>> = RefCell::new(None);
+}
+
+/// Restores the previously attached debugger when dropped.
+pub(crate) struct Attached(Option<Stack>);
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        STACK.with(|stack| *stack.borrow_mut() = self.0.take());
+    }
+}
+
+/// Track frames for `debugging` on this thread, or stop tracking them if
+/// there's no debugger.
+pub(crate) fn attach(debugging: Option<&Rc<Debugging>>) -> Attached {
+    let stack = debugging.map(|debugging| Stack {
+        debugging: debugging.to_owned(),
+        frames: Vec::new(),
+        error_paused: false,
+        catching: 0,
+    });
+    Attached(STACK.with(|x| x.replace(stack)))
+}
+
+/// Run `f` as a new frame, if a debugger is attached.
+pub(crate) fn frame<T>(
+    kind: FrameKind,
+    span: Option<TextRange>,
+    env: &Env,
+    f: impl FnOnce() -> Result<T, EvalError>,
+) -> Result<T, EvalError> {
+    let stepping = STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let stack = stack.as_mut()?;
+        stack.frames.push(Frame {
+            kind,
+            span,
+            env: env.to_owned(),
+        });
+        // Evaluation carries on, so any error that paused was handled.
+        stack.error_paused = false;
+        let stepping = *stack.debugging.stepping.lock();
+        Some(stepping)
+    });
+    match stepping {
+        None => return f(),
+        Some(true) => pause(PauseReason::Step),
+        Some(false) => {}
+    }
+    let res = f();
+    if let Err(err) = &res {
+        let uncaught = STACK.with(|x| {
+            matches!(&*x.borrow(), Some(x)
+                if !x.error_paused && (x.catching == 0 || !err.is_catchable()))
+        });
+        if uncaught {
+            pause(PauseReason::Error(err));
+        }
+    }
+    STACK.with(|stack| {
+        if let Some(stack) = &mut *stack.borrow_mut() {
+            stack.frames.pop();
+            stack.error_paused = res.is_err();
+        }
+    });
+    res
+}
+
+/// Run `f` for `builtins.tryEval`, which catches the errors it returns that
+/// are catchable, so those don't pause.
+pub(crate) fn catching<T>(f: impl FnOnce() -> Result<T, EvalError>) -> Result<T, EvalError> {
+    STACK.with(|stack| {
+        if let Some(stack) = &mut *stack.borrow_mut() {
+            stack.catching += 1;
+        }
+    });
+    let res = f();
+    let caught = !matches!(&res, Err(err) if !err.is_catchable());
+    STACK.with(|stack| {
+        if let Some(stack) = &mut *stack.borrow_mut() {
+            stack.catching -= 1;
+            if caught {
+                stack.error_paused = false;
+            }
+        }
+    });
+    res
+}
+
+/// Pause for `builtins.break`, if a debugger is attached.
+pub(crate) fn breakpoint() {
+    pause(PauseReason::Break);
+}
+
+fn pause(reason: PauseReason<'_>) {
+    // Detach while paused, so evaluation by the debugger isn't tracked.
+    let stack = match STACK.with(|x| x.borrow_mut().take()) {
+        Some(x) => x,
+        None => return,
+    };
+    let frames: Vec<_> = stack.frames.iter().rev().cloned().collect();
+    let resume = stack.debugging.debugger.pause(&Paused {
+        reason,
+        frames: &frames,
+    });
+    *stack.debugging.stepping.lock() = resume == Resume::Step;
+    STACK.with(|x| *x.borrow_mut() = Some(stack));
+}
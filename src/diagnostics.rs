@@ -0,0 +1,145 @@
+//! Traces and warnings reported by builtins during evaluation.
+
+use std::fmt;
+
+use rnix::TextRange;
+
+use crate::{
+    builtins::BuiltinError,
+    evaluator::EvalError,
+    ir::Location,
+    sync::{Lock, MaybeSend, Rc},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// From `builtins.trace`.
+    Trace,
+    /// From `builtins.traceVerbose`, only reported if enabled with
+    /// [`Diagnostics::trace_verbose`].
+    VerboseTrace,
+    /// From `builtins.warn`.
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    /// The call that produced the diagnostic, if it was called directly from
+    /// Nix code.
+    pub span: Option<TextRange>,
+    /// The file, line and column of that call.
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            DiagnosticKind::Trace | DiagnosticKind::VerboseTrace => {
+                write!(f, "trace: {}", self.message)
+            }
+            DiagnosticKind::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// Somewhere to send diagnostics, such as a terminal or a log.
+pub trait DiagnosticSink: MaybeSend {
+    fn report(&self, diagnostic: Diagnostic);
+}
+
+/// Prints diagnostics to stderr, like `nix` does, after where they came
+/// from.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StderrSink;
+
+impl DiagnosticSink for StderrSink {
+    fn report(&self, diagnostic: Diagnostic) {
+        match &diagnostic.location {
+            Some(location) => eprintln!("{}: {}", location, diagnostic),
+            None => eprintln!("{}", diagnostic),
+        }
+    }
+}
+
+/// Keeps diagnostics in memory, for embedders that want to inspect them.
+#[derive(Default)]
+pub struct MemorySink(Lock<Vec<Diagnostic>>);
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove and return every diagnostic reported so far.
+    pub fn take(&self) -> Vec<Diagnostic> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
+impl DiagnosticSink for MemorySink {
+    fn report(&self, diagnostic: Diagnostic) {
+        self.0.lock().push(diagnostic);
+    }
+}
+
+/// How an evaluation session handles diagnostics.
+#[derive(Clone)]
+pub struct Diagnostics {
+    sink: Rc<dyn DiagnosticSink>,
+    trace_verbose: bool,
+    abort_on_warning: bool,
+}
+
+impl Diagnostics {
+    pub fn new(sink: Rc<dyn DiagnosticSink>) -> Self {
+        Self {
+            sink,
+            trace_verbose: false,
+            abort_on_warning: false,
+        }
+    }
+
+    /// Whether `builtins.traceVerbose` reports anything. Off by default.
+    pub fn trace_verbose(mut self, enabled: bool) -> Self {
+        self.trace_verbose = enabled;
+        self
+    }
+
+    /// Whether warnings abort evaluation after being reported, so that the
+    /// error shows where they came from.
+    pub fn abort_on_warning(mut self, enabled: bool) -> Self {
+        self.abort_on_warning = enabled;
+        self
+    }
+
+    pub(crate) fn trace_verbose_enabled(&self) -> bool {
+        self.trace_verbose
+    }
+
+    pub(crate) fn report(&self, diagnostic: Diagnostic) -> Result<(), EvalError> {
+        if diagnostic.kind == DiagnosticKind::Warning && self.abort_on_warning {
+            let message = diagnostic.message.clone();
+            self.sink.report(diagnostic);
+            return Err(BuiltinError::AbortedOnWarning(message.into()).into());
+        }
+        self.sink.report(diagnostic);
+        Ok(())
+    }
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new(Rc::new(StderrSink))
+    }
+}
+
+impl fmt::Debug for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Diagnostics")
+            .field("trace_verbose", &self.trace_verbose)
+            .field("abort_on_warning", &self.abort_on_warning)
+            .finish()
+    }
+}
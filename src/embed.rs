@@ -0,0 +1,350 @@
+//! Embedding the evaluator in an application.
+//!
+//! An [`Evaluator`] bundles everything a host application decides about
+//! evaluation: the global variables, the builtins, the search path used by
+//! `<name>` paths, what files and environment variables may be read, and the
+//! store. It keeps the files it imports cached for as long as it lives, so an
+//! application can create one per project and reuse it.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use rnix::parse;
+
+use crate::{
+    attrs::Attrs,
+    builtins::{BuiltinError, PrimOp, GLOBAL_BUILTINS},
+    evaluator::{EvalError, EvaluationContext, Session},
+    ir::Param,
+    sync::{Lock, MaybeSend, Rc},
+    value::Value,
+};
+
+type Result<T> = std::result::Result<T, EvalError>;
+
+/// What evaluation may read from the system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoPolicy {
+    /// Read any file and environment variable.
+    Unrestricted,
+    /// Only read files under the given directories and those of the search
+    /// path, and treat every environment variable as unset. Files passed to
+    /// [`Evaluator::eval_file`] are always readable.
+    Restricted(Vec<PathBuf>),
+}
+
+impl Default for IoPolicy {
+    fn default() -> Self {
+        Self::Unrestricted
+    }
+}
+
+/// Where `builtins.toFile` writes files and paths are copied to, and what
+/// `builtins.storeDir` is.
+pub trait Store: MaybeSend {
+    /// The directory store paths are in, such as `/nix/store`.
+    fn store_dir(&self) -> &str;
+
+    /// Add a file called `name` with the given contents, returning its store
+    /// path.
+    fn add_text(&self, name: &str, contents: &[u8]) -> io::Result<String>;
+
+    /// Copy the file or directory at `path` into the store, returning its
+    /// store path. `builtins.toJSON` does this with the paths it converts,
+    /// and writes them as they are when there's no store.
+    fn add_path(&self, path: &Path) -> io::Result<String>;
+}
+
+/// The configuration of an [`Evaluator`], shared with the sessions it
+/// evaluates in.
+#[derive(Default)]
+pub(crate) struct Host {
+    pub(crate) globals: EvaluationContext,
+    pub(crate) io: IoPolicy,
+    pub(crate) store: Option<Rc<dyn Store>>,
+    search_path: Vec<(String, PathBuf)>,
+    /// The values of the files imported so far, by canonical path.
+    imports: Lock<HashMap<PathBuf, Value>>,
+}
+
+impl Host {
+    /// Check that the file at `path` may be read.
+    pub(crate) fn check_read(&self, path: &Path) -> Result<()> {
+        let allowed = match &self.io {
+            IoPolicy::Unrestricted => return Ok(()),
+            IoPolicy::Restricted(allowed) => allowed,
+        };
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        let mut dirs = allowed
+            .iter()
+            .chain(self.search_path.iter().map(|(_, x)| x));
+        if dirs.any(|dir| path.starts_with(dir)) {
+            Ok(())
+        } else {
+            Err(BuiltinError::AccessDenied(path.to_string_lossy().into_owned().into()).into())
+        }
+    }
+
+    pub(crate) fn imported(&self, path: &Path) -> Option<Value> {
+        self.imports.lock().get(path).cloned()
+    }
+
+    pub(crate) fn cache_import(&self, path: PathBuf, value: Value) {
+        self.imports.lock().insert(path, value);
+    }
+
+    pub(crate) fn clear_imports(&self) {
+        let imports = std::mem::take(&mut *self.imports.lock());
+        drop(imports);
+    }
+}
+
+/// Configures an [`Evaluator`]. Every setting starts out as in Nix, with no
+/// search path and no store.
+#[derive(Default)]
+pub struct EvaluatorBuilder {
+    globals: Vec<(String, Value)>,
+    builtins: Vec<PrimOp>,
+    search_path: Vec<(String, PathBuf)>,
+    io: IoPolicy,
+    store: Option<Rc<dyn Store>>,
+    session: Session,
+}
+
+impl EvaluatorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a global variable, visible from every file evaluated.
+    pub fn global(mut self, name: impl Into<String>, value: Value) -> Self {
+        self.globals.push((name.into(), value));
+        self
+    }
+
+    /// Add a builtin, or replace the one with the same name. Builtins that
+    /// are also available without the `builtins.` prefix, like `map`, are
+    /// replaced there too.
+    pub fn builtin(mut self, primop: PrimOp) -> Self {
+        self.builtins.push(primop);
+        self
+    }
+
+    /// Resolve `<prefix/...>` paths under `path`. Entries are searched in
+    /// the order they're added, and an empty prefix matches every name, like
+    /// the entries of `NIX_PATH`.
+    pub fn search_path(mut self, prefix: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.search_path.push((prefix.into(), path.into()));
+        self
+    }
+
+    pub fn io_policy(mut self, io: IoPolicy) -> Self {
+        self.io = io;
+        self
+    }
+
+    pub fn store(mut self, store: Rc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Evaluate in `session`, to configure its diagnostics, limits and so on.
+    /// It must not have been used yet.
+    pub fn session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    pub fn build(self) -> Evaluator {
+        let mut globals = EvaluationContext::new();
+        for op in self.builtins {
+            let name = op.name();
+            let op = Value::PrimOp(Rc::new(op));
+            if GLOBAL_BUILTINS.contains(&name) {
+                globals = globals.with(name.into(), op.clone());
+            }
+            globals = globals.with_path(&["builtins".into(), name.into()], op);
+        }
+        let nix_path = Value::List(
+            self.search_path
+                .iter()
+                .map(|(prefix, path)| {
+                    let mut entry = Attrs::new();
+                    entry.insert_mut("prefix".into(), prefix.as_str().into());
+                    entry.insert_mut("path".into(), path.to_string_lossy().as_ref().into());
+                    Value::AttrSet(entry)
+                })
+                .collect(),
+        );
+        globals = globals
+            .with_path(&["builtins".into(), "nixPath".into()], nix_path.clone())
+            .with("__nixPath".into(), nix_path);
+        if let Some(store) = &self.store {
+            globals = globals.with_path(
+                &["builtins".into(), "storeDir".into()],
+                store.store_dir().into(),
+            );
+        }
+        for (name, value) in self.globals {
+            globals = globals.with(name, value);
+        }
+        // Compare canonical paths, so that symlinks and `..` can't be used to
+        // get around a restricted policy.
+        let canonical = |x: PathBuf| x.canonicalize().unwrap_or(x);
+        let io = match self.io {
+            IoPolicy::Restricted(dirs) => {
+                IoPolicy::Restricted(dirs.into_iter().map(canonical).collect())
+            }
+            io => io,
+        };
+        let host = Host {
+            globals,
+            io,
+            store: self.store,
+            search_path: self
+                .search_path
+                .into_iter()
+                .map(|(prefix, path)| (prefix, canonical(path)))
+                .collect(),
+            imports: Lock::default(),
+        };
+        let host = Rc::new(host);
+        Evaluator {
+            session: self.session.with_host(host.clone()),
+            host,
+        }
+    }
+}
+
+/// Evaluates Nix code on behalf of an application.
+///
+/// Everything evaluated by an evaluator, including the files it imports, is
+/// part of a single [`Session`], so values it returns stay usable for as long
+/// as it lives, and their memory is freed when it's dropped. Until then, the
+/// recursive values of every evaluation, like `let xs = [ xs ]; in xs`, are
+/// kept even once they're no longer used, so an application that evaluates
+/// indefinitely, like a server, should replace its evaluator from time to
+/// time.
+pub struct Evaluator {
+    session: Session,
+    host: Rc<Host>,
+}
+
+impl Evaluator {
+    /// An evaluator with the default settings.
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> EvaluatorBuilder {
+        EvaluatorBuilder::new()
+    }
+
+    /// The variables every file is evaluated with, including the builtins.
+    pub fn globals(&self) -> &EvaluationContext {
+        &self.host.globals
+    }
+
+    /// The session everything is evaluated in, for its statistics.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Evaluate `source`. Relative paths in it are resolved against the
+    /// current directory.
+    pub fn eval_str(&self, source: &str) -> Result<Value> {
+        self.eval_str_in(source, &Attrs::new())
+    }
+
+    /// Evaluate `source` with the variables in `scope` bound in addition to
+    /// the globals, as a REPL does with its own definitions.
+    pub fn eval_str_in(&self, source: &str, scope: &Attrs) -> Result<Value> {
+        let ast = parse(source)
+            .as_result()
+            .map_err(|x| EvalError::Parse("«string»".into(), x))?;
+        let dir = std::env::current_dir().ok();
+        self.session
+            .eval_in(ast.node(), scope, "«string»".into(), dir.as_deref())
+    }
+
+    /// Evaluate `source` as if it were the contents of the file at `path`,
+    /// such as an editor's unsaved changes to it: errors name `path`, and
+    /// relative paths are resolved against its directory.
+    pub fn eval_str_as(&self, source: &str, path: impl AsRef<Path>) -> Result<Value> {
+        let path = path.as_ref();
+        let file: Rc<str> = path.to_string_lossy().into();
+        let ast = parse(source)
+            .as_result()
+            .map_err(|x| EvalError::Parse(file.to_string().into(), x))?;
+        self.session
+            .eval_in(ast.node(), &Attrs::new(), file, path.parent())
+    }
+
+    /// Like [`eval_str_in`](Self::eval_str_in), but only check `source` for
+    /// errors now, and return a thunk that evaluates it when it's forced.
+    pub fn defer_str_in(&self, source: &str, scope: &Attrs) -> Result<Value> {
+        let ast = parse(source)
+            .as_result()
+            .map_err(|x| EvalError::Parse("«string»".into(), x))?;
+        let dir = std::env::current_dir().ok();
+        self.session
+            .defer_in(ast.node(), scope, "«string»".into(), dir.as_deref())
+    }
+
+    /// Evaluate the file at `path`, or its `default.nix` if it's a directory.
+    /// Files are only evaluated once; evaluating or importing them again
+    /// returns the same value.
+    pub fn eval_file(&self, path: impl AsRef<Path>) -> Result<Value> {
+        self.session.import(path.as_ref())
+    }
+
+    /// Forget the files evaluated so far, so that they're read again the
+    /// next time they're evaluated or imported.
+    pub fn forget_files(&self) {
+        self.host.clear_imports();
+    }
+
+    /// Evaluate `source` and, if it's a function taking an attribute set,
+    /// call it with the attributes of `args` that it names, like
+    /// `nix-instantiate --arg`.
+    pub fn eval_expr_with_args(&self, source: &str, args: &Attrs) -> Result<Value> {
+        self.auto_call(self.eval_str(source)?, args)
+    }
+
+    /// If `value` is a function taking an attribute set, call it with the
+    /// attributes of `args` that it names. Sets with a `__functor` are called
+    /// like the function it returns for them. Other values are returned as
+    /// they are.
+    pub fn auto_call(&self, value: Value, args: &Attrs) -> Result<Value> {
+        let value = value.materialize()?;
+        let lambda = match &value {
+            Value::Function(lambda, _) => lambda,
+            Value::AttrSet(attrs) => match attrs.get("__functor") {
+                Some(functor) => {
+                    let function = functor.to_owned().call(value.clone())?;
+                    return self.auto_call(function, args);
+                }
+                None => return Ok(value),
+            },
+            _ => return Ok(value),
+        };
+        let formals = match &lambda.param {
+            Param::Pattern { formals, .. } => formals,
+            Param::Ident(_) => return Ok(value),
+        };
+        let args = formals
+            .iter()
+            .filter_map(|x| Some((x.name.to_owned(), args.get(x.name.as_str())?.to_owned())))
+            .collect();
+        value.call(Value::AttrSet(args))
+    }
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
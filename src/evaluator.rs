@@ -1,12 +1,35 @@
-use std::fmt;
+use std::{
+    cell::RefCell,
+    fmt, fs,
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use rnix::{types::*, value::ValueError, NixValue, StrPart, SyntaxNode};
-use rpds::{HashTrieMap, List, Vector};
+use rnix::{
+    parse,
+    parser::ParseError,
+    types::{BinOpKind, UnaryOpKind},
+    value::ValueError,
+    SyntaxNode,
+};
+use rpds::List;
 use thiserror::Error;
 
 use crate::{
+    attrs::Attrs,
     builtins::{base_context, BuiltinError},
-    value::{ArithmeticError, Value},
+    compiler::{compile, compile_file},
+    debugger::{self, Debugger, Debugging, FrameKind},
+    diagnostics::Diagnostics,
+    embed::Host,
+    ir::{AttrKey, AttrSet, CallSite, Expr, ExprRef, Lambda, Param, StrPart, Var},
+    limits::{CancellationToken, Limits, Usage},
+    profiler::{self, Entry, Profiler},
+    stats::Stats,
+    string::{Context, NixString},
+    symbol::Symbol,
+    sync::{Lock, Rc, Weak},
+    value::{ArithmeticError, Thunk, Value, WeakThunk},
     ErrorString,
 };
 
@@ -16,6 +39,8 @@ pub enum EvalError {
     Mismatch,
     #[error("BUG: Node missing required children")]
     MissingChildren,
+    #[error("Failed to parse {0}")]
+    Parse(ErrorString, #[source] ParseError),
     #[error("An error occurred parsing a literal")]
     LiteralParse(#[from] ValueError),
     #[error("Unexpected token")]
@@ -32,6 +57,35 @@ pub enum EvalError {
     UnresolvedIdent(ErrorString),
     #[error("No such index {0} in attrset")]
     NoSuchIndex(ErrorString),
+    #[error("Attribute {0} is already defined")]
+    DuplicateAttr(ErrorString),
+    #[error("Dynamic attributes are not allowed in let")]
+    DynamicLet,
+    #[error("Infinite recursion encountered")]
+    InfiniteRecursion,
+    #[error("Assertion failed at {0:?}")]
+    AssertionFailed(rnix::TextRange),
+    #[error("Function called without required argument {0}")]
+    MissingArgument(ErrorString),
+    #[error("Function called with unexpected argument {0}")]
+    UnexpectedArgument(ErrorString),
+    #[error("Value used after its evaluation session ended")]
+    SessionEnded,
+    #[error("Evaluation exceeded the limit of {0} steps")]
+    StepLimit(u64),
+    #[error("Evaluation exceeded the time limit of {0:?}")]
+    TimeLimit(Duration),
+    #[error("Evaluation exceeded the depth limit of {0}")]
+    DepthLimit(u64),
+    #[error("A {0} exceeded the size limit of {1}")]
+    SizeLimit(ErrorString, usize),
+    #[error("Evaluation exceeded the limit of {0} values")]
+    ValueLimit(u64),
+    #[error("Evaluation was cancelled")]
+    Cancelled,
+    /// An error annotated by `builtins.addErrorContext`.
+    #[error("{0}")]
+    Context(ErrorString, #[source] Box<EvalError>),
 
     #[error("An arithmetic operation failed")]
     Arithmetic(#[from] ArithmeticError),
@@ -39,24 +93,57 @@ pub enum EvalError {
     Builtin(#[from] BuiltinError),
 }
 
+impl EvalError {
+    /// Whether `builtins.tryEval` can catch the error. As in Nix, only failed
+    /// assertions and `throw` are catchable; everything else, including type
+    /// errors and infinite recursion, propagates.
+    pub fn is_catchable(&self) -> bool {
+        match self {
+            EvalError::AssertionFailed(_) => true,
+            EvalError::Builtin(e) => e.is_catchable(),
+            EvalError::Context(_, e) => e.is_catchable(),
+            EvalError::Mismatch
+            | EvalError::MissingChildren
+            | EvalError::Parse(_, _)
+            | EvalError::LiteralParse(_)
+            | EvalError::UnexpectedToken
+            | EvalError::UnexpectedNode
+            | EvalError::TypeMismatch(_, _)
+            | EvalError::NotYetImplemented(_)
+            | EvalError::NotEnabled(_)
+            | EvalError::UnresolvedIdent(_)
+            | EvalError::NoSuchIndex(_)
+            | EvalError::DuplicateAttr(_)
+            | EvalError::DynamicLet
+            | EvalError::InfiniteRecursion
+            | EvalError::MissingArgument(_)
+            | EvalError::UnexpectedArgument(_)
+            | EvalError::SessionEnded
+            | EvalError::StepLimit(_)
+            | EvalError::TimeLimit(_)
+            | EvalError::DepthLimit(_)
+            | EvalError::SizeLimit(_, _)
+            | EvalError::ValueLimit(_)
+            | EvalError::Cancelled
+            | EvalError::Arithmetic(_) => false,
+        }
+    }
+}
+
 type Result<T> = std::result::Result<T, EvalError>;
 
 #[derive(Clone, PartialEq)]
-pub struct EvaluationContext(HashTrieMap<String, Value>);
+pub struct EvaluationContext(Attrs);
 
-fn assoc_in(
-    set: HashTrieMap<String, Value>,
-    path: List<String>,
-    val: Value,
-) -> HashTrieMap<String, Value> {
+fn assoc_in(set: Attrs, path: List<String>, val: Value) -> Attrs {
     if path.is_empty() {
         panic!("BUG: path should never be less than 1 len!")
     } else if path.len() == 1 {
-        set.insert(path.first().unwrap().to_owned(), val)
+        set.insert(path.first().unwrap().into(), val)
     } else if let Some(child) = set.get(path.first().unwrap()) {
         if let Value::AttrSet(child) = child {
             set.insert(
-                path.first().unwrap().to_owned(),
+                path.first().unwrap().into(),
                 Value::AttrSet(assoc_in(child.clone(), path.drop_first().unwrap(), val)),
             )
         } else {
@@ -64,12 +151,8 @@ fn assoc_in(
         }
     } else {
         set.insert(
-            path.first().unwrap().to_owned(),
-            Value::AttrSet(assoc_in(
-                HashTrieMap::new(),
-                path.drop_first().unwrap(),
-                val,
-            )),
+            path.first().unwrap().into(),
+            Value::AttrSet(assoc_in(Attrs::new(), path.drop_first().unwrap(), val)),
         )
     }
 }
@@ -80,7 +163,7 @@ impl EvaluationContext {
     }
 
     pub fn with(&self, ident: String, val: Value) -> Self {
-        Self(self.0.insert(ident, val))
+        Self(self.0.insert(ident.into(), val))
     }
 
     pub fn with_path(&self, path: &[String], val: Value) -> Self {
@@ -95,17 +178,18 @@ impl EvaluationContext {
         self.0.get(ident)
     }
 
+    /// The names of the variables, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.keys().map(|x| x.as_str())
+    }
+
     pub fn merge(&self, other: &Self) -> Self {
-        let mut res = self.0.clone();
-        for (k, v) in other.0.iter() {
-            res.insert_mut(k.to_owned(), v.to_owned());
-        }
-        Self(res)
+        Self(self.0.update(&other.0))
     }
 }
 
-impl From<HashTrieMap<String, Value>> for EvaluationContext {
-    fn from(m: HashTrieMap<String, Value>) -> Self {
+impl From<Attrs> for EvaluationContext {
+    fn from(m: Attrs) -> Self {
         Self(m)
     }
 }
@@ -126,237 +210,830 @@ pub(crate) fn nyi<S: Into<ErrorString>, T>(feature: S) -> Result<T> {
     Err(EvalError::NotYetImplemented(feature.into()))
 }
 
-fn cast<T: TypedNode>(from: SyntaxNode) -> Result<T> {
-    T::cast(from).ok_or(EvalError::Mismatch)
+struct Scope {
+    slots: Lock<Vec<Value>>,
+    /// The names of the slots, for the debugger. `None` for a `with` scope,
+    /// whose only slot holds the namespace.
+    names: Option<Rc<[Symbol]>>,
+    parent: Env,
+    session: Option<Rc<SessionState>>,
 }
 
-fn expect_child<T>(x: Option<T>) -> Result<T> {
-    x.ok_or(EvalError::MissingChildren)
-}
+/// The runtime environment an expression is evaluated in: a chain of scopes
+/// whose slots are addressed by the `(depth, slot)` pairs assigned by the
+/// compiler.
+#[derive(Clone, Default)]
+pub struct Env(Option<Rc<Scope>>);
 
-fn eval_root(node: Root, context: EvaluationContext) -> Result<Value> {
-    eval_ctx(expect_child(node.inner())?, context)
-}
+impl Env {
+    /// An empty environment whose recursive scopes are tracked by `session`.
+    fn root(session: &Rc<SessionState>) -> Self {
+        Self(Some(Rc::new(Scope {
+            slots: Lock::new(Vec::new()),
+            names: Some(Rc::from(Vec::new())),
+            parent: Env::default(),
+            session: Some(session.to_owned()),
+        })))
+    }
 
-fn eval_literal(node: rnix::types::Value) -> Result<Value> {
-    Ok(match node.to_value()? {
-        NixValue::Float(x) => Value::Floating(x),
-        NixValue::Integer(x) => Value::Integer(x),
-        NixValue::String(x) => Value::String(x),
-        NixValue::Path(_, x) => Value::Path(x),
-    })
-}
+    fn push(&self, names: Option<Rc<[Symbol]>>, slots: Vec<Value>) -> Self {
+        Self(Some(Rc::new(Scope {
+            slots: Lock::new(slots),
+            names,
+            parent: self.clone(),
+            session: self.0.as_ref().and_then(|x| x.session.to_owned()),
+        })))
+    }
 
-fn eval_bin_op(node: BinOp, context: EvaluationContext) -> Result<Value> {
-    let lhs = eval_ctx(expect_child(node.lhs())?, context.clone())?.materialize()?;
-    let rhs_node = expect_child(node.rhs())?;
-    let rhs = || eval_ctx(rhs_node, context)?.materialize();
-    match node.operator() {
-        BinOpKind::Concat => nyi("concatenation"),
-        BinOpKind::IsSet => nyi("isset"),
-        BinOpKind::Update => nyi("updating"),
-        BinOpKind::Add => Ok(lhs.add(&rhs()?)?),
-        BinOpKind::Sub => Ok(lhs.sub(&rhs()?)?),
-        BinOpKind::Mul => Ok(lhs.mul(&rhs()?)?),
-        BinOpKind::Div => Ok(lhs.div(&rhs()?)?),
-        BinOpKind::And => {
-            if let Value::Boolean(lhs) = lhs {
-                if lhs {
-                    rhs()
-                } else {
-                    Ok(false.into())
-                }
-            } else {
-                Err(EvalError::TypeMismatch(
-                    "boolean".into(),
-                    lhs.human_readable_type().into(),
-                ))
+    /// Push a scope whose slots may refer to the scope itself.
+    fn push_recursive<F: FnOnce(&Env) -> Result<Vec<Value>>>(
+        &self,
+        names: &Rc<[Symbol]>,
+        f: F,
+    ) -> Result<Self> {
+        let env = self.push(Some(names.to_owned()), Vec::new());
+        let slots = f(&env)?;
+        if let Some(scope) = &env.0 {
+            *scope.slots.lock() = slots;
+            if let Some(session) = &scope.session {
+                session.register(scope);
             }
         }
-        BinOpKind::Or => {
-            if let Value::Boolean(lhs) = lhs {
-                if lhs {
-                    Ok(true.into())
-                } else {
-                    rhs()
+        Ok(env)
+    }
+
+    /// Make this environment's session the current one on this thread until
+    /// the guard is dropped, so builtins can find its diagnostics however
+    /// they're reached.
+    pub(crate) fn enter(&self) -> Option<CurrentSession> {
+        let session = self.0.as_ref()?.session.as_ref()?;
+        CURRENT_SESSION.with(|current| {
+            let mut current = current.borrow_mut();
+            if matches!(&*current, Some(x) if Rc::ptr_eq(x, session)) {
+                return None;
+            }
+            let previous = current.replace(session.to_owned());
+            Some(CurrentSession {
+                previous,
+                entered: Instant::now(),
+                _debugger: debugger::attach(session.debugger.as_ref()),
+                _profiler: profiler::attach(session.profiler.as_ref()),
+            })
+        })
+    }
+
+    /// The variables bound in this environment, innermost first. Variables
+    /// brought into scope by `with` aren't included.
+    pub(crate) fn variables(&self) -> Vec<(Symbol, Value)> {
+        let mut res: Vec<(Symbol, Value)> = Vec::new();
+        let mut scope = self.0.as_ref();
+        while let Some(x) = scope {
+            if let Some(names) = &x.names {
+                let slots = x.slots.lock();
+                for (name, value) in names.iter().zip(slots.iter()) {
+                    if !res.iter().any(|(x, _)| x == name) {
+                        res.push((name.to_owned(), value.to_owned()));
+                    }
                 }
-            } else {
-                Err(EvalError::TypeMismatch(
-                    "boolean".into(),
-                    lhs.human_readable_type().into(),
-                ))
             }
+            scope = x.parent.0.as_ref();
         }
-        BinOpKind::Implication => {
-            if let Value::Boolean(lhs) = lhs {
-                if lhs {
-                    rhs()
-                } else {
-                    Ok(true.into())
-                }
-            } else {
-                Err(EvalError::TypeMismatch(
-                    "boolean".into(),
-                    lhs.human_readable_type().into(),
-                ))
+        res
+    }
+
+    /// The names of each scope's slots, outermost first, as needed to
+    /// compile code that runs in this environment.
+    pub(crate) fn scope_names(&self) -> Vec<Option<Rc<[Symbol]>>> {
+        let mut res = Vec::new();
+        let mut scope = self.0.as_ref();
+        while let Some(x) = scope {
+            res.push(x.names.to_owned());
+            scope = x.parent.0.as_ref();
+        }
+        res.reverse();
+        res
+    }
+
+    fn lookup(&self, depth: usize, slot: usize) -> Option<Value> {
+        let mut scope = self.0.as_ref()?;
+        for _ in 0..depth {
+            scope = scope.parent.0.as_ref()?;
+        }
+        let slots = scope.slots.lock();
+        slots.get(slot).cloned()
+    }
+}
+
+impl PartialEq for Env {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Some(l), Some(r)) => Rc::ptr_eq(l, r),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT_SESSION: RefCell<Option<Rc<SessionState>>> = RefCell::new(None);
+    static CALL_SITE: RefCell<Option<CallSite>> = RefCell::new(None);
+}
+
+/// Restores the previously current session and its debugger when dropped.
+pub(crate) struct CurrentSession {
+    previous: Option<Rc<SessionState>>,
+    entered: Instant,
+    _debugger: debugger::Attached,
+    _profiler: profiler::Attached,
+}
+
+impl Drop for CurrentSession {
+    fn drop(&mut self) {
+        CURRENT_SESSION.with(|current| {
+            let mut current = current.borrow_mut();
+            if let Some(stats) = current.as_ref().and_then(|x| x.stats.as_ref()) {
+                stats.lock().wall_time += self.entered.elapsed();
             }
+            *current = self.previous.take();
+        });
+    }
+}
+
+/// Check the current session's usage against its limits, if it has any.
+pub(crate) fn limit(f: impl FnOnce(&Usage) -> Result<()>) -> Result<()> {
+    CURRENT_SESSION.with(
+        |current| match current.borrow().as_ref().and_then(|x| x.usage.as_ref()) {
+            Some(usage) => f(usage),
+            None => Ok(()),
+        },
+    )
+}
+
+/// Check the size of a newly built value against the current session's
+/// limits.
+fn checked(value: Value) -> Result<Value> {
+    limit(|x| x.check(&value))?;
+    Ok(value)
+}
+
+/// Have the current session release the value of `thunk` when it ends.
+pub(crate) fn track(thunk: WeakThunk) {
+    CURRENT_SESSION.with(|current| {
+        if let Some(session) = &*current.borrow() {
+            session.track(thunk);
         }
-        BinOpKind::Equal => Ok(lhs.compare(&rhs()?)?.is_eq().into()),
-        BinOpKind::Less => Ok(lhs.compare(&rhs()?)?.is_lt().into()),
-        BinOpKind::LessOrEq => Ok(lhs.compare(&rhs()?)?.is_le().into()),
-        BinOpKind::More => Ok(lhs.compare(&rhs()?)?.is_gt().into()),
-        BinOpKind::MoreOrEq => Ok(lhs.compare(&rhs()?)?.is_ge().into()),
-        BinOpKind::NotEqual => Ok(lhs.compare(&rhs()?)?.is_ne().into()),
+    })
+}
+
+/// Update the statistics of the current session, if it collects them.
+pub(crate) fn record(f: impl FnOnce(&mut Stats)) {
+    CURRENT_SESSION.with(|current| {
+        if let Some(stats) = current.borrow().as_ref().and_then(|x| x.stats.as_ref()) {
+            f(&mut stats.lock());
+        }
+    })
+}
+
+/// The diagnostics settings of the current session, or the defaults outside of
+/// any session.
+pub(crate) fn diagnostics() -> Diagnostics {
+    CURRENT_SESSION.with(|current| match &*current.borrow() {
+        Some(session) => session.diagnostics.to_owned(),
+        None => Diagnostics::default(),
+    })
+}
+
+/// Run `f` with `site` as the position of the builtin call being evaluated.
+/// Calls made by builtins themselves have no position, and keep that of the
+/// call they're part of.
+pub(crate) fn at_call_site<T>(site: Option<&CallSite>, f: impl FnOnce() -> T) -> T {
+    match site {
+        Some(site) => {
+            let previous = CALL_SITE.with(|x| x.replace(Some(site.to_owned())));
+            let res = f();
+            CALL_SITE.with(|x| *x.borrow_mut() = previous);
+            res
+        }
+        None => f(),
     }
 }
 
-fn eval_apply(node: Apply, context: EvaluationContext) -> Result<Value> {
-    let f = eval_ctx(expect_child(node.lambda())?, context.clone())?;
-    let arg = thunkify(expect_child(node.value())?, context)?;
-    f.materialize()?.call(arg)
+/// The position of the builtin call being evaluated.
+pub(crate) fn call_site() -> Option<CallSite> {
+    CALL_SITE.with(|x| x.borrow().clone())
 }
 
-fn eval_ident(node: Ident, context: EvaluationContext) -> Result<Value> {
-    let ident = node.as_str();
-    if let Some(v) = context.get(ident) {
-        Ok(v.to_owned())
-    } else {
-        Err(EvalError::UnresolvedIdent(ident.to_string().into()))
+/// State shared by everything evaluated in a [`Session`].
+#[derive(Default)]
+struct SessionState {
+    /// The recursive scopes created during the session.
+    scopes: Lock<Vec<Weak<Scope>>>,
+    /// The thunks evaluated during the session to values that hold others.
+    thunks: Lock<Vec<WeakThunk>>,
+    diagnostics: Diagnostics,
+    debugger: Option<Rc<Debugging>>,
+    profiler: Option<Rc<Profiler>>,
+    stats: Option<Lock<Stats>>,
+    usage: Option<Usage>,
+    host: Option<Rc<Host>>,
+}
+
+impl SessionState {
+    fn register(&self, scope: &Rc<Scope>) {
+        let mut scopes = self.scopes.lock();
+        // Forget scopes that have already been freed before growing, so that
+        // a long session doesn't accumulate dead entries.
+        if scopes.len() == scopes.capacity() {
+            scopes.retain(|x| x.strong_count() > 0);
+        }
+        scopes.push(Rc::downgrade(scope));
+    }
+
+    fn track(&self, thunk: WeakThunk) {
+        let mut thunks = self.thunks.lock();
+        if thunks.len() == thunks.capacity() {
+            thunks.retain(WeakThunk::is_alive);
+        }
+        thunks.push(thunk);
     }
 }
 
-fn eval_select(node: Select, context: EvaluationContext) -> Result<Value> {
-    let set = eval_ctx(expect_child(node.set())?, context)?;
-    let index = expect_child(expect_child(node.index())?.first_token())?;
-    let index_text = index.text().to_string();
-    if let Value::AttrSet(set) = set {
-        if let Some(v) = set.get(&index_text) {
-            Ok(v.to_owned())
-        } else {
-            Err(EvalError::NoSuchIndex(index_text.into()))
+/// An evaluation session, which owns the memory of the values it produces.
+///
+/// Recursive scopes (`let`, `rec { }` and function patterns with defaults)
+/// hold thunks and closures that refer back to the scope itself, and thunks
+/// can evaluate to values holding the thunk, as in `let xs = [ xs ]`, so
+/// reference counting alone never frees them. A session keeps track of every
+/// such scope and thunk and breaks those cycles when it's dropped, so a
+/// long-running process can evaluate any number of expressions in successive
+/// sessions without leaking.
+///
+/// Values produced by a session must not be used after it's dropped: anything
+/// that still needs a variable from a recursive scope fails with
+/// [`EvalError::SessionEnded`].
+#[derive(Default)]
+pub struct Session(Rc<SessionState>);
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report traces and warnings according to `diagnostics`, rather than
+    /// printing them to stderr.
+    ///
+    /// # Panics
+    ///
+    /// If the session has already been used.
+    pub fn with_diagnostics(mut self, diagnostics: Diagnostics) -> Self {
+        self.state_mut().diagnostics = diagnostics;
+        self
+    }
+
+    /// Pause evaluation with `debugger` on `builtins.break` and on errors that
+    /// aren't caught.
+    ///
+    /// # Panics
+    ///
+    /// If the session has already been used.
+    pub fn with_debugger(mut self, debugger: Rc<dyn Debugger>) -> Self {
+        self.state_mut().debugger = Some(Rc::new(Debugging::new(debugger)));
+        self
+    }
+
+    /// Record where evaluation spends its time with `profiler`.
+    ///
+    /// # Panics
+    ///
+    /// If the session has already been used.
+    pub fn with_profiler(mut self, profiler: Rc<Profiler>) -> Self {
+        self.state_mut().profiler = Some(profiler);
+        self
+    }
+
+    /// Collect [`Stats`] about what the session evaluates.
+    ///
+    /// # Panics
+    ///
+    /// If the session has already been used.
+    pub fn with_stats(mut self) -> Self {
+        self.state_mut().stats = Some(Lock::default());
+        self
+    }
+
+    /// The statistics collected so far, if enabled with
+    /// [`with_stats`](Self::with_stats).
+    pub fn stats(&self) -> Option<Stats> {
+        self.0.stats.as_ref().map(|x| x.lock().to_owned())
+    }
+
+    /// Bound what the session may evaluate.
+    ///
+    /// # Panics
+    ///
+    /// If the session has already been used.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.state_mut()
+            .usage
+            .get_or_insert_with(Usage::default)
+            .limits = limits;
+        self
+    }
+
+    /// Let evaluation be stopped from another thread with `token`.
+    ///
+    /// # Panics
+    ///
+    /// If the session has already been used.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.state_mut()
+            .usage
+            .get_or_insert_with(Usage::default)
+            .cancellation = Some(token);
+        self
+    }
+
+    /// Evaluate on behalf of an [`Evaluator`](crate::embed::Evaluator).
+    pub(crate) fn with_host(mut self, host: Rc<Host>) -> Self {
+        self.state_mut().host = Some(host);
+        self
+    }
+
+    fn state_mut(&mut self) -> &mut SessionState {
+        Rc::get_mut(&mut self.0).expect("session configured after it was used")
+    }
+
+    pub fn eval(&self, node: SyntaxNode, context: &EvaluationContext) -> Result<Value> {
+        let expr = compile(node, context)?;
+        eval_root(Some(&self.0), &expr, "«string»".into())
+    }
+
+    /// Evaluate `node` from `file` against the globals of the session's
+    /// evaluator and the variables in `scope`, resolving relative paths
+    /// against `dir`.
+    pub(crate) fn eval_in(
+        &self,
+        node: SyntaxNode,
+        scope: &Attrs,
+        file: Rc<str>,
+        dir: Option<&Path>,
+    ) -> Result<Value> {
+        let expr = self.compile_in(node, scope, file.clone(), dir)?;
+        eval_root(Some(&self.0), &expr, file)
+    }
+
+    /// Like [`eval_in`](Self::eval_in), but return a thunk that evaluates
+    /// `node` when it's forced.
+    pub(crate) fn defer_in(
+        &self,
+        node: SyntaxNode,
+        scope: &Attrs,
+        file: Rc<str>,
+        dir: Option<&Path>,
+    ) -> Result<Value> {
+        let expr = self.compile_in(node, scope, file, dir)?;
+        let env = Env::root(&self.0);
+        let _session = env.enter();
+        Ok(Value::Thunk(Thunk::new(env.clone(), expr)))
+    }
+
+    fn compile_in(
+        &self,
+        node: SyntaxNode,
+        scope: &Attrs,
+        file: Rc<str>,
+        dir: Option<&Path>,
+    ) -> Result<ExprRef> {
+        let host = self.0.host.clone().unwrap_or_default();
+        let globals = host.globals.merge(&scope.to_owned().into());
+        compile_file(node, &globals, file, dir)
+    }
+
+    /// Evaluate the file at `path` as `import` does, without checking whether
+    /// it may be read.
+    pub(crate) fn import(&self, path: &Path) -> Result<Value> {
+        let _session = Env::root(&self.0).enter();
+        import_in(Some(&self.0), path)
+    }
+}
+
+/// Evaluate a whole file or string in `session`.
+fn eval_root(session: Option<&Rc<SessionState>>, expr: &Expr, file: Rc<str>) -> Result<Value> {
+    let env = session.map_or_else(Env::default, Env::root);
+    let _session = env.enter();
+    record(|x| x.files_parsed += 1);
+    limit(|x| {
+        x.start();
+        Ok(())
+    })?;
+    // The root is a frame too, so the debugger can pause on errors
+    // outside of any function call.
+    profiler::frame(
+        || Entry::File(file),
+        || {
+            debugger::frame(FrameKind::Force, expr.span(), &env, || {
+                eval_expr(expr, &env)
+            })
+        },
+    )
+}
+
+/// Evaluate the file at `path` for `builtins.import`, in the current
+/// session.
+pub(crate) fn import(path: &Path) -> Result<Value> {
+    let session = CURRENT_SESSION.with(|current| current.borrow().clone());
+    if let Some(host) = session.as_ref().and_then(|x| x.host.as_ref()) {
+        host.check_read(path)?;
+    }
+    import_in(session.as_ref(), path)
+}
+
+fn import_in(session: Option<&Rc<SessionState>>, path: &Path) -> Result<Value> {
+    // Outside of an evaluator, files are imported with the default globals
+    // and aren't cached.
+    let host = session.and_then(|x| x.host.clone()).unwrap_or_default();
+    let mut path = path.to_owned();
+    if path.is_dir() {
+        path.push("default.nix");
+    }
+    let io_error = |e| BuiltinError::Io(path.to_string_lossy().into_owned().into(), e);
+    let path = path.canonicalize().map_err(io_error)?;
+    if let Some(value) = host.imported(&path) {
+        return Ok(value);
+    }
+    let source = fs::read_to_string(&path).map_err(io_error)?;
+    let file: Rc<str> = path.to_string_lossy().into();
+    let ast = parse(&source)
+        .as_result()
+        .map_err(|x| EvalError::Parse(file.to_string().into(), x))?;
+    let expr = compile_file(ast.node(), &host.globals, file.clone(), path.parent())?;
+    let value = eval_root(session, &expr, file)?;
+    record(|x| x.files_imported += 1);
+    host.cache_import(path, value.clone());
+    Ok(value)
+}
+
+/// The configuration of the current session's evaluator, if it has one.
+pub(crate) fn host() -> Option<Rc<Host>> {
+    CURRENT_SESSION.with(|current| current.borrow().as_ref()?.host.clone())
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Imported values refer back to the session through their scopes.
+        if let Some(host) = &self.0.host {
+            host.clear_imports();
         }
-    } else {
-        Err(EvalError::TypeMismatch(
+        let scopes = std::mem::take(&mut *self.0.scopes.lock());
+        for scope in scopes.iter().filter_map(Weak::upgrade) {
+            // Take the slots out before dropping them, since that may free
+            // other scopes.
+            let slots = std::mem::take(&mut *scope.slots.lock());
+            drop(slots);
+        }
+        let thunks = std::mem::take(&mut *self.0.thunks.lock());
+        for thunk in &thunks {
+            thunk.release();
+        }
+    }
+}
+
+/// Defer evaluation of `expr`, unless it's cheap and safe to evaluate now.
+fn thunk(expr: &ExprRef, env: &Env) -> Value {
+    match &**expr {
+        Expr::Constant(x) => x.to_owned(),
+        Expr::Lambda(x) => Value::Function(x.to_owned(), env.to_owned()),
+        // Slots in a recursive scope that's still being built aren't
+        // available yet, so those references have to stay lazy.
+        Expr::Var(Var::Local { depth, slot }) => env
+            .lookup(*depth, *slot)
+            .unwrap_or_else(|| Value::Thunk(Thunk::new(env.to_owned(), expr.to_owned()))),
+        _ => Value::Thunk(Thunk::new(env.to_owned(), expr.to_owned())),
+    }
+}
+
+fn expect_attrs(x: Value) -> Result<Attrs> {
+    match x {
+        Value::AttrSet(x) => Ok(x),
+        x => Err(EvalError::TypeMismatch(
             "attribute set".into(),
-            set.human_readable_type().into(),
-        ))
+            x.human_readable_type().into(),
+        )),
+    }
+}
+
+fn expect_bool(x: Value) -> Result<bool> {
+    match x {
+        Value::Boolean(x) => Ok(x),
+        x => Err(EvalError::TypeMismatch(
+            "boolean".into(),
+            x.human_readable_type().into(),
+        )),
     }
 }
 
-fn eval_list(node: rnix::types::List, context: EvaluationContext) -> Result<Value> {
-    let mut v = Vector::new();
-    for item in node.items() {
-        v.push_back_mut(thunkify(item, context.clone())?);
+/// Evaluate a dynamic attribute name. `None` means the attribute should be
+/// skipped, as with `{ ${null} = 1; }`.
+fn eval_attr_name(expr: &Expr, env: &Env) -> Result<Option<Symbol>> {
+    match eval_expr(expr, env)?.materialize()? {
+        Value::String(x) => Ok(Some(Symbol::from_bytes(&x))),
+        Value::Null => Ok(None),
+        x => Err(EvalError::TypeMismatch(
+            "string".into(),
+            x.human_readable_type().into(),
+        )),
     }
-    Ok(Value::List(v))
 }
 
-fn eval_string(node: Str, context: EvaluationContext) -> Result<Value> {
-    let mut s = String::new();
-    for part in node.parts() {
-        match part {
-            StrPart::Literal(x) => s += &x,
-            StrPart::Ast(x) => s += &eval_ctx(x, context.clone())?.to_string(),
+/// Evaluate an attribute name in a selection or `?`. Unlike in bindings, a
+/// `null` name can't be skipped there, so it's an error.
+fn eval_attr_key(key: &AttrKey, env: &Env) -> Result<Symbol> {
+    match key {
+        AttrKey::Static(x) => Ok(x.to_owned()),
+        AttrKey::Dynamic(x) => eval_attr_name(x, env)?
+            .ok_or_else(|| EvalError::TypeMismatch("string".into(), "null".into())),
+    }
+}
+
+fn eval_dynamic_attrs(set: &AttrSet, env: &Env, mut attrs: Attrs) -> Result<Value> {
+    for (k, v) in set.dynamic.iter() {
+        if let Some(k) = eval_attr_name(k, env)? {
+            if attrs.contains_key(&k) {
+                return Err(EvalError::DuplicateAttr(k.into()));
+            }
+            attrs.insert_mut(k, thunk(v, env));
         }
     }
-    Ok(s.into())
+    record_attrs(&attrs);
+    checked(Value::AttrSet(attrs))
 }
 
-fn eval_lambda(node: Lambda, context: EvaluationContext) -> Result<Value> {
-    let arg = expect_child(expect_child(node.arg())?.first_token())?;
-    let arg_s = arg.text().to_string();
-    let body = expect_child(node.body())?;
-    Ok(Value::Function(arg_s, context, body))
+fn record_attrs(attrs: &Attrs) {
+    record(|x| {
+        x.attrsets += 1;
+        x.attrset_elements += attrs.len() as u64;
+    });
 }
 
-fn eval_paren(node: Paren, context: EvaluationContext) -> Result<Value> {
-    let body = expect_child(node.inner())?;
-    eval_ctx(body, context)
+fn eval_select(
+    set: &ExprRef,
+    path: &[AttrKey],
+    default: Option<&ExprRef>,
+    env: &Env,
+) -> Result<Value> {
+    let mut v = eval_expr(set, env)?;
+    for key in path {
+        let name = eval_attr_key(key, env)?;
+        match v.materialize()? {
+            Value::AttrSet(set) => match set.get(&name) {
+                Some(x) => v = x.to_owned(),
+                None => match default {
+                    Some(default) => return eval_expr(default, env),
+                    None => return Err(EvalError::NoSuchIndex(name.into())),
+                },
+            },
+            x => match default {
+                Some(default) => return eval_expr(default, env),
+                None => {
+                    return Err(EvalError::TypeMismatch(
+                        "attribute set".into(),
+                        x.human_readable_type().into(),
+                    ))
+                }
+            },
+        }
+    }
+    Ok(v)
 }
 
-fn eval_let_in(node: LetIn, context: EvaluationContext) -> Result<Value> {
-    let mut ctx = context;
-    for entry in node.entries() {
-        let k = expect_child(entry.key())?;
-        let v = thunkify(expect_child(entry.value())?, ctx.clone())?;
-        let mut path_vec = vec![];
-        for part in k.path() {
-            let token = expect_child(part.first_token())?;
-            path_vec.push(token.text().to_string());
+fn eval_has_attr(set: &ExprRef, path: &[AttrKey], env: &Env) -> Result<Value> {
+    let mut v = eval_expr(set, env)?;
+    for key in path {
+        let name = eval_attr_key(key, env)?;
+        match v.materialize()? {
+            Value::AttrSet(set) => match set.get(&name) {
+                Some(x) => v = x.to_owned(),
+                None => return Ok(false.into()),
+            },
+            _ => return Ok(false.into()),
         }
-        ctx = ctx.with_path(&path_vec, v);
     }
-    eval_ctx(expect_child(node.body())?, ctx)
+    Ok(true.into())
 }
 
-fn eval_with(node: With, context: EvaluationContext) -> Result<Value> {
-    let ns = expect_child(node.namespace())?;
-    let ns_val = eval_ctx(ns, context.clone())?;
-    if let Value::AttrSet(ns) = ns_val {
-        let body = expect_child(node.body())?;
-        let ctx = context.merge(&ns.into());
-        thunkify(body, ctx)
-    } else {
-        Err(EvalError::TypeMismatch(
-            "attribute set".into(),
-            ns_val.human_readable_type().into(),
-        ))
+fn eval_bin_op(operator: BinOpKind, lhs: &ExprRef, rhs: &ExprRef, env: &Env) -> Result<Value> {
+    let lhs = eval_expr(lhs, env)?.materialize()?;
+    let rhs = || eval_expr(rhs, env)?.materialize();
+    match operator {
+        BinOpKind::Concat => match (lhs, rhs()?) {
+            (Value::List(l), Value::List(r)) => {
+                let mut res = l;
+                for x in r.iter() {
+                    res.push_back_mut(x.to_owned());
+                }
+                record(|x| {
+                    x.lists += 1;
+                    x.list_elements += res.len() as u64;
+                });
+                checked(Value::List(res))
+            }
+            (Value::List(_), r) => Err(EvalError::TypeMismatch(
+                "list".into(),
+                r.human_readable_type().into(),
+            )),
+            (l, _) => Err(EvalError::TypeMismatch(
+                "list".into(),
+                l.human_readable_type().into(),
+            )),
+        },
+        BinOpKind::Update => {
+            let res = expect_attrs(lhs)?.update(&expect_attrs(rhs()?)?);
+            record_attrs(&res);
+            checked(Value::AttrSet(res))
+        }
+        // Compiled to `Expr::HasAttr`
+        BinOpKind::IsSet => Err(EvalError::Mismatch),
+        BinOpKind::Add => checked(lhs.add(&rhs()?)?),
+        BinOpKind::Sub => Ok(lhs.sub(&rhs()?)?),
+        BinOpKind::Mul => Ok(lhs.mul(&rhs()?)?),
+        BinOpKind::Div => Ok(lhs.div(&rhs()?)?),
+        BinOpKind::And => Ok((expect_bool(lhs)? && expect_bool(rhs()?)?).into()),
+        BinOpKind::Or => Ok((expect_bool(lhs)? || expect_bool(rhs()?)?).into()),
+        BinOpKind::Implication => Ok((!expect_bool(lhs)? || expect_bool(rhs()?)?).into()),
+        BinOpKind::Equal => Ok(lhs.equals(&rhs()?)?.into()),
+        BinOpKind::NotEqual => Ok((!lhs.equals(&rhs()?)?).into()),
+        BinOpKind::Less => Ok(lhs.compare(&rhs()?)?.is_lt().into()),
+        BinOpKind::LessOrEq => Ok(lhs.compare(&rhs()?)?.is_le().into()),
+        BinOpKind::More => Ok(lhs.compare(&rhs()?)?.is_gt().into()),
+        BinOpKind::MoreOrEq => Ok(lhs.compare(&rhs()?)?.is_ge().into()),
     }
 }
 
-pub fn thunkify(node: SyntaxNode, context: EvaluationContext) -> Result<Value> {
-    match node.kind() {
-        rnix::SyntaxKind::NODE_IDENT => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_SELECT => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_STRING => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_LAMBDA => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_LET_IN => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_LIST => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_PAREN => thunkify(
-            expect_child(Paren::cast(node).ok_or(EvalError::Mismatch)?.inner())?,
-            context,
-        ),
-        rnix::SyntaxKind::NODE_ROOT => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_ATTR_SET => eval_ctx(node, context),
-        rnix::SyntaxKind::NODE_LITERAL => eval_ctx(node, context),
-        _ => Ok(Value::Thunk(context, node)),
+/// Bind a function's argument into a new scope, laid out as described by
+/// `Param`.
+pub(crate) fn bind_param(lambda: &Lambda, env: &Env, arg: Value) -> Result<Env> {
+    let names = &lambda.names;
+    match &lambda.param {
+        Param::Ident(_) => Ok(env.push(Some(names.to_owned()), vec![arg])),
+        Param::Pattern {
+            formals,
+            ellipsis,
+            bind,
+        } => {
+            let args = expect_attrs(arg.materialize()?)?;
+            if !ellipsis {
+                if let Some(k) = args.keys().find(|k| !formals.iter().any(|f| &f.name == *k)) {
+                    return Err(EvalError::UnexpectedArgument(k.to_owned().into()));
+                }
+            }
+            env.push_recursive(names, |new_env| {
+                let mut slots = Vec::with_capacity(lambda.param.slots());
+                for formal in formals {
+                    slots.push(match (args.get(&formal.name), &formal.default) {
+                        (Some(v), _) => v.to_owned(),
+                        (None, Some(default)) => thunk(default, new_env),
+                        (None, None) => {
+                            return Err(EvalError::MissingArgument(formal.name.to_owned().into()))
+                        }
+                    });
+                }
+                if bind.is_some() {
+                    slots.push(Value::AttrSet(args.clone()));
+                }
+                Ok(slots)
+            })
+        }
     }
 }
 
-pub fn eval_ctx(node: SyntaxNode, context: EvaluationContext) -> Result<Value> {
-    match node.kind() {
-        rnix::SyntaxKind::NODE_APPLY => eval_apply(cast(node)?, context),
-        rnix::SyntaxKind::NODE_ASSERT => nyi("assert"),
-        rnix::SyntaxKind::NODE_KEY => nyi("key"),
-        rnix::SyntaxKind::NODE_DYNAMIC => nyi("dynamic"),
-        rnix::SyntaxKind::NODE_ERROR => nyi("error"),
-        rnix::SyntaxKind::NODE_IDENT => eval_ident(cast(node)?, context),
-        rnix::SyntaxKind::NODE_IF_ELSE => nyi("if_else"),
-        rnix::SyntaxKind::NODE_SELECT => eval_select(cast(node)?, context),
-        rnix::SyntaxKind::NODE_INHERIT => nyi("inherit"),
-        rnix::SyntaxKind::NODE_INHERIT_FROM => nyi("inherit from"),
-        rnix::SyntaxKind::NODE_STRING => eval_string(cast(node)?, context),
-        rnix::SyntaxKind::NODE_STRING_INTERPOL => nyi("string interpolation"),
-        rnix::SyntaxKind::NODE_LAMBDA => eval_lambda(cast(node)?, context),
-        rnix::SyntaxKind::NODE_LEGACY_LET => nyi("legacy let"),
-        rnix::SyntaxKind::NODE_LET_IN => eval_let_in(cast(node)?, context),
-        rnix::SyntaxKind::NODE_LIST => eval_list(cast(node)?, context),
-        rnix::SyntaxKind::NODE_BIN_OP => eval_bin_op(cast(node)?, context),
-        rnix::SyntaxKind::NODE_OR_DEFAULT => nyi("or"),
-        rnix::SyntaxKind::NODE_PAREN => eval_paren(cast(node)?, context),
-        rnix::SyntaxKind::NODE_PATTERN => nyi("patterns"),
-        rnix::SyntaxKind::NODE_PAT_BIND => nyi("patterns"),
-        rnix::SyntaxKind::NODE_PAT_ENTRY => nyi("patterns"),
-        rnix::SyntaxKind::NODE_ROOT => eval_root(cast(node)?, context),
-        rnix::SyntaxKind::NODE_ATTR_SET => nyi("attribute sets"),
-        rnix::SyntaxKind::NODE_KEY_VALUE => nyi("attribute sets"),
-        rnix::SyntaxKind::NODE_UNARY_OP => nyi("unary operators"),
-        rnix::SyntaxKind::NODE_LITERAL => eval_literal(cast(node)?),
-        rnix::SyntaxKind::NODE_WITH => eval_with(cast(node)?, context),
-        _ => Err(EvalError::UnexpectedNode),
+/// Evaluate an expression. The result may be a thunk; use
+/// [`Value::materialize`] to get at its contents.
+pub(crate) fn eval_expr(expr: &Expr, env: &Env) -> Result<Value> {
+    match expr {
+        Expr::Constant(x) => Ok(x.to_owned()),
+        Expr::Var(Var::Local { depth, slot }) => {
+            env.lookup(*depth, *slot).ok_or(EvalError::SessionEnded)
+        }
+        Expr::Var(Var::Dynamic { name, withs }) => {
+            for depth in withs {
+                let ns = env
+                    .lookup(*depth, 0)
+                    .ok_or(EvalError::SessionEnded)?
+                    .materialize()?;
+                if let Some(v) = expect_attrs(ns)?.get(name) {
+                    return Ok(v.to_owned());
+                }
+            }
+            Err(EvalError::UnresolvedIdent(name.to_owned().into()))
+        }
+        Expr::Apply {
+            function,
+            argument,
+            site,
+        } => debugger::frame(FrameKind::Apply, Some(site.span), env, || {
+            let f = eval_expr(function, env)?.materialize()?;
+            f.call_at(thunk(argument, env), Some(site))
+        }),
+        Expr::Lambda(x) => Ok(Value::Function(x.to_owned(), env.to_owned())),
+        Expr::List(items) => {
+            record(|x| {
+                x.lists += 1;
+                x.list_elements += items.len() as u64;
+            });
+            checked(Value::List(items.iter().map(|x| thunk(x, env)).collect()))
+        }
+        Expr::AttrSet(set) => {
+            let mut attrs = Attrs::new();
+            for (k, v) in set.attrs.iter() {
+                attrs.insert_mut(k.to_owned(), thunk(v, env));
+            }
+            eval_dynamic_attrs(set, env, attrs)
+        }
+        Expr::RecAttrSet { set, names } => {
+            let env = env.push_recursive(names, |env| {
+                Ok(set.attrs.iter().map(|(_, v)| thunk(v, env)).collect())
+            })?;
+            let mut attrs = Attrs::new();
+            for (slot, (k, _)) in set.attrs.iter().enumerate() {
+                attrs.insert_mut(
+                    k.to_owned(),
+                    env.lookup(0, slot).ok_or(EvalError::MissingChildren)?,
+                );
+            }
+            eval_dynamic_attrs(set, &env, attrs)
+        }
+        Expr::Let {
+            names,
+            bindings,
+            body,
+        } => {
+            let env = env.push_recursive(names, |env| {
+                Ok(bindings.iter().map(|x| thunk(x, env)).collect())
+            })?;
+            eval_expr(body, &env)
+        }
+        Expr::With { namespace, body } => {
+            eval_expr(body, &env.push(None, vec![thunk(namespace, env)]))
+        }
+        Expr::Select {
+            set, path, default, ..
+        } => eval_select(set, path, default.as_ref(), env),
+        Expr::HasAttr { set, path } => eval_has_attr(set, path, env),
+        Expr::If {
+            condition,
+            body,
+            else_body,
+        } => {
+            if expect_bool(eval_expr(condition, env)?.materialize()?)? {
+                eval_expr(body, env)
+            } else {
+                eval_expr(else_body, env)
+            }
+        }
+        Expr::Assert {
+            condition,
+            body,
+            span,
+        } => {
+            if expect_bool(eval_expr(condition, env)?.materialize()?)? {
+                eval_expr(body, env)
+            } else {
+                Err(EvalError::AssertionFailed(*span))
+            }
+        }
+        Expr::BinOp { operator, lhs, rhs } => eval_bin_op(*operator, lhs, rhs, env),
+        Expr::UnaryOp { operator, value } => {
+            let value = eval_expr(value, env)?.materialize()?;
+            match operator {
+                UnaryOpKind::Invert => Ok((!expect_bool(value)?).into()),
+                UnaryOpKind::Negate => Ok(Value::Integer(0).sub(&value)?),
+            }
+        }
+        Expr::String(parts) => {
+            let mut bytes = Vec::new();
+            let mut context = Context::new();
+            for part in parts {
+                match part {
+                    StrPart::Literal(x) => bytes.extend_from_slice(x),
+                    StrPart::Interpolation(x) => {
+                        let x = eval_expr(x, env)?.coerce_to_string()?;
+                        bytes.extend_from_slice(&x);
+                        context.extend(x.context());
+                    }
+                }
+            }
+            checked(NixString::from(bytes).with_context(&context).into())
+        }
     }
 }
 
+/// Evaluate outside of any [`Session`]. Cyclic values created along the way
+/// are never freed, so long-running processes should use a session instead.
+pub fn eval_ctx(node: SyntaxNode, context: EvaluationContext) -> Result<Value> {
+    let expr = compile(node, &context)?;
+    eval_expr(&expr, &Env::default())
+}
+
 pub fn eval(node: SyntaxNode) -> Result<Value> {
     eval_ctx(node, EvaluationContext::new())
 }
@@ -0,0 +1,178 @@
+use std::fmt;
+
+use rnix::{
+    types::{BinOpKind, UnaryOpKind},
+    TextRange,
+};
+
+use crate::{string::NixString, symbol::Symbol, sync::Rc, value::Value};
+
+pub type ExprRef = Rc<Expr>;
+
+/// A compiled Nix expression.
+///
+/// The compiler lowers the `rnix` syntax tree into this representation once,
+/// resolving every variable reference to its binding site so that evaluation
+/// never has to look names up by string.
+pub enum Expr {
+    /// A value that is fully known at compile time: literals and globals.
+    Constant(Value),
+    Var(Var),
+    Apply {
+        function: ExprRef,
+        argument: ExprRef,
+        site: CallSite,
+    },
+    Lambda(Rc<Lambda>),
+    List(Vec<ExprRef>),
+    AttrSet(Rc<AttrSet>),
+    /// A `rec { }` set, whose attributes are evaluated in a scope with one
+    /// slot per static attribute, named by `names`.
+    RecAttrSet {
+        set: Rc<AttrSet>,
+        names: Rc<[Symbol]>,
+    },
+    /// A `let` block. The bindings occupy the slots of a new scope in which
+    /// both the bindings themselves and the body are evaluated.
+    Let {
+        names: Rc<[Symbol]>,
+        bindings: Vec<ExprRef>,
+        body: ExprRef,
+    },
+    /// A `with` block. The namespace occupies slot 0 of a new scope, which
+    /// dynamic variable lookups consult.
+    With {
+        namespace: ExprRef,
+        body: ExprRef,
+    },
+    Select {
+        set: ExprRef,
+        path: Vec<AttrKey>,
+        default: Option<ExprRef>,
+        span: TextRange,
+    },
+    HasAttr {
+        set: ExprRef,
+        path: Vec<AttrKey>,
+    },
+    If {
+        condition: ExprRef,
+        body: ExprRef,
+        else_body: ExprRef,
+    },
+    Assert {
+        condition: ExprRef,
+        body: ExprRef,
+        span: TextRange,
+    },
+    BinOp {
+        operator: BinOpKind,
+        lhs: ExprRef,
+        rhs: ExprRef,
+    },
+    UnaryOp {
+        operator: UnaryOpKind,
+        value: ExprRef,
+    },
+    String(Vec<StrPart>),
+}
+
+impl Expr {
+    /// The source range of the expression, for those that keep it.
+    pub fn span(&self) -> Option<TextRange> {
+        match self {
+            Expr::Apply { site, .. } => Some(site.span),
+            Expr::Select { span, .. } | Expr::Assert { span, .. } => Some(*span),
+            Expr::Lambda(x) => Some(x.span),
+            _ => None,
+        }
+    }
+}
+
+/// A resolved variable reference.
+#[derive(Debug, Clone)]
+pub enum Var {
+    /// A lexically bound variable, `depth` scopes up from the current one.
+    Local { depth: usize, slot: usize },
+    /// A variable that isn't bound lexically, and so must be looked up in the
+    /// namespaces of the enclosing `with` blocks at the given depths,
+    /// innermost first.
+    Dynamic { name: Symbol, withs: Vec<usize> },
+}
+
+pub enum AttrKey {
+    Static(Symbol),
+    Dynamic(ExprRef),
+}
+
+pub enum StrPart {
+    Literal(NixString),
+    Interpolation(ExprRef),
+}
+
+#[derive(Default)]
+pub struct AttrSet {
+    pub attrs: Vec<(Symbol, ExprRef)>,
+    pub dynamic: Vec<(ExprRef, ExprRef)>,
+}
+
+/// A position in a source file, for reporting.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Location {
+    /// The file name, or `«string»` for code that didn't come from a file.
+    pub file: Rc<str>,
+    /// The line, starting from 1.
+    pub line: u32,
+    /// The column in characters, starting from 1.
+    pub column: u32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Where a function is applied, which is where builtins report diagnostics
+/// from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+    pub span: TextRange,
+    pub location: Location,
+}
+
+pub struct Lambda {
+    pub param: Param,
+    /// The names of the slots the parameter is bound to.
+    pub names: Rc<[Symbol]>,
+    pub body: ExprRef,
+    pub span: TextRange,
+    pub location: Location,
+}
+
+pub enum Param {
+    /// `x: ...`, bound to slot 0.
+    Ident(Symbol),
+    /// `{ a, b ? c, ... } @ d: ...`. Formals are bound to slots in order,
+    /// followed by the `@` binding if there is one.
+    Pattern {
+        formals: Vec<Formal>,
+        ellipsis: bool,
+        bind: Option<Symbol>,
+    },
+}
+
+pub struct Formal {
+    pub name: Symbol,
+    pub default: Option<ExprRef>,
+}
+
+impl Param {
+    /// The number of slots in the scope created when the function is called.
+    pub fn slots(&self) -> usize {
+        match self {
+            Param::Ident(_) => 1,
+            Param::Pattern { formals, bind, .. } => formals.len() + bind.iter().count(),
+        }
+    }
+}
@@ -2,11 +2,38 @@ use std::borrow::Cow;
 
 pub type ErrorString = Cow<'static, str>;
 
+pub mod attrs;
+
 pub mod builtins;
 
+pub mod compiler;
+
+pub mod debugger;
+
+pub mod diagnostics;
+
+pub mod embed;
+
 pub mod evaluator;
 
+pub mod ir;
+
+pub mod limits;
+
+#[cfg(feature = "sync")]
+pub mod parallel;
+
+pub mod profiler;
+
 #[cfg(feature = "serde")]
 pub mod serde;
 
+pub mod stats;
+
+pub mod string;
+
+pub mod symbol;
+
+pub mod sync;
+
 pub mod value;
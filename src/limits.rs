@@ -0,0 +1,139 @@
+//! Bounding the cost of evaluating untrusted code.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{evaluator::EvalError, sync::Lock, value::Value};
+
+/// Limits on what a session may evaluate. Exceeding any of them fails
+/// evaluation with an error that `builtins.tryEval` can't catch.
+///
+/// Every limit is off by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The number of steps, each of which is a function call or a thunk
+    /// being forced.
+    pub max_steps: Option<u64>,
+    /// The time since the session first started evaluating.
+    pub timeout: Option<Duration>,
+    /// The nesting of function calls and thunk forces.
+    pub max_depth: Option<u64>,
+    pub max_list_len: Option<usize>,
+    /// The length of a string, in bytes.
+    pub max_string_len: Option<usize>,
+    pub max_attrset_len: Option<usize>,
+    /// The number of values allocated: thunks, and the elements of lists and
+    /// attribute sets.
+    pub max_values: Option<u64>,
+}
+
+/// Stops evaluation from another thread. Sessions sharing a token are all
+/// cancelled together.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make evaluation fail with [`EvalError::Cancelled`] at its next step.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Let evaluation go on again after [`cancel`](Self::cancel), such as
+    /// for the next expression entered in a REPL.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How often to check the clock, in steps.
+const CLOCK_INTERVAL: u64 = 256;
+
+/// The limits of a session and its usage so far.
+#[derive(Default)]
+pub(crate) struct Usage {
+    pub(crate) limits: Limits,
+    pub(crate) cancellation: Option<CancellationToken>,
+    started: Lock<Option<Instant>>,
+    steps: AtomicU64,
+    values: AtomicU64,
+}
+
+impl Usage {
+    /// Start the clock, if it hasn't been already.
+    pub(crate) fn start(&self) {
+        self.started.lock().get_or_insert_with(Instant::now);
+    }
+
+    /// Take a step at the given depth.
+    pub(crate) fn step(&self, depth: u64) -> Result<(), EvalError> {
+        if matches!(&self.cancellation, Some(x) if x.is_cancelled()) {
+            return Err(EvalError::Cancelled);
+        }
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(max) = self.limits.max_steps {
+            if steps > max {
+                return Err(EvalError::StepLimit(max));
+            }
+        }
+        if let Some(max) = self.limits.max_depth {
+            if depth > max {
+                return Err(EvalError::DepthLimit(max));
+            }
+        }
+        if let Some(timeout) = self.limits.timeout {
+            if steps % CLOCK_INTERVAL == 0 {
+                let started = *self.started.lock();
+                if matches!(started, Some(x) if x.elapsed() > timeout) {
+                    return Err(EvalError::TimeLimit(timeout));
+                }
+            }
+        }
+        self.check_values()
+    }
+
+    /// Count `n` newly allocated values, to be checked at the next step.
+    pub(crate) fn allocate(&self, n: u64) {
+        self.values.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Check the size of a newly built value, and count its elements.
+    pub(crate) fn check(&self, value: &Value) -> Result<(), EvalError> {
+        let (kind, len, max) = match value {
+            Value::List(x) => ("list", x.len(), self.limits.max_list_len),
+            Value::String(x) => ("string", x.len(), self.limits.max_string_len),
+            Value::AttrSet(x) => ("attribute set", x.len(), self.limits.max_attrset_len),
+            _ => return Ok(()),
+        };
+        if let Some(max) = max {
+            if len > max {
+                return Err(EvalError::SizeLimit(kind.into(), max));
+            }
+        }
+        if !matches!(value, Value::String(_)) {
+            self.allocate(len as u64);
+        }
+        self.check_values()
+    }
+
+    fn check_values(&self) -> Result<(), EvalError> {
+        match self.limits.max_values {
+            Some(max) if self.values.load(Ordering::Relaxed) > max => {
+                Err(EvalError::ValueLimit(max))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Evaluating independent parts of a value across a thread pool.
+
+use rayon::prelude::*;
+
+use crate::{
+    evaluator::{EvalError, EvaluationContext},
+    symbol::Symbol,
+    value::Value,
+};
+
+// Everything the evaluator hands out must be shareable between threads.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Value>();
+    assert_send_sync::<EvaluationContext>();
+    assert_send_sync::<EvalError>();
+};
+
+type Result<T> = std::result::Result<T, EvalError>;
+
+/// Deeply evaluate every attribute of `set` on rayon's global thread pool.
+///
+/// Each attribute gets its own result, so one failing package doesn't stop
+/// the rest from being evaluated. Results are in the set's iteration order.
+pub fn par_eval_attrs(set: Value) -> Result<Vec<(Symbol, Result<Value>)>> {
+    let set = match set.materialize()? {
+        Value::AttrSet(set) => set,
+        x => {
+            return Err(EvalError::TypeMismatch(
+                "set".into(),
+                x.human_readable_type().into(),
+            ))
+        }
+    };
+    let attrs: Vec<_> = set
+        .iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+    Ok(attrs
+        .into_par_iter()
+        .map(|(k, v)| (k, v.materialize_deep()))
+        .collect())
+}
@@ -0,0 +1,262 @@
+//! Measuring where evaluation spends its time.
+//!
+//! A [`Profiler`] attached to a session records a call tree of the lambdas,
+//! primops and files being evaluated. Time spent forcing a thunk is counted
+//! towards whatever forced it.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt, io,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ir::Location,
+    sync::{Lock, Rc},
+};
+
+/// Something that evaluation time is attributed to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Entry {
+    /// A lambda, identified by where it's defined.
+    Lambda(Location),
+    PrimOp(&'static str),
+    /// The evaluation of a whole file or string.
+    File(Rc<str>),
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Entry::Lambda(location) => write!(f, "«lambda» {}", location),
+            Entry::PrimOp(name) => write!(f, "builtins.{}", name),
+            Entry::File(file) => write!(f, "{}", file),
+        }
+    }
+}
+
+/// The totals recorded for an [`Entry`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotSpot {
+    pub entry: Entry,
+    pub calls: u64,
+    /// Time spent in the entry and everything it called. Recursive calls
+    /// aren't counted twice.
+    pub total: Duration,
+    /// Time spent in the entry itself.
+    pub own: Duration,
+}
+
+struct Node {
+    entry: Option<usize>,
+    children: HashMap<usize, usize>,
+    own: Duration,
+}
+
+#[derive(Default)]
+struct Data {
+    entries: Vec<HotSpot>,
+    ids: HashMap<Entry, usize>,
+    /// The call tree, rooted at index 0.
+    nodes: Vec<Node>,
+}
+
+impl Data {
+    fn enter(&mut self, entry: Entry, parent: usize) -> (usize, usize) {
+        let id = match self.ids.get(&entry) {
+            Some(id) => *id,
+            None => {
+                self.ids.insert(entry.clone(), self.entries.len());
+                self.entries.push(HotSpot {
+                    entry,
+                    calls: 0,
+                    total: Duration::ZERO,
+                    own: Duration::ZERO,
+                });
+                self.entries.len() - 1
+            }
+        };
+        self.entries[id].calls += 1;
+        let next = self.nodes.len();
+        let node = *self.nodes[parent].children.entry(id).or_insert(next);
+        if node == next {
+            self.nodes.push(Node {
+                entry: Some(id),
+                children: HashMap::new(),
+                own: Duration::ZERO,
+            });
+        }
+        (id, node)
+    }
+
+    /// The entries from the root down to `node`.
+    fn path(&self, mut node: usize, parents: &[usize]) -> Vec<usize> {
+        let mut path = Vec::new();
+        while let Some(id) = self.nodes[node].entry {
+            path.push(id);
+            node = parents[node];
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// Records where evaluation spends its time, across every session it's
+/// attached to.
+pub struct Profiler(Lock<Data>);
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self(Lock::new(Data {
+            nodes: vec![Node {
+                entry: None,
+                children: HashMap::new(),
+                own: Duration::ZERO,
+            }],
+            ..Data::default()
+        }))
+    }
+
+    /// Every entry seen so far, those that took the most time themselves
+    /// first.
+    pub fn hot_spots(&self) -> Vec<HotSpot> {
+        let mut res = self.0.lock().entries.clone();
+        res.sort_by(|a, b| b.own.cmp(&a.own).then(b.total.cmp(&a.total)));
+        res
+    }
+
+    /// Write the call tree in the collapsed stack format read by
+    /// `flamegraph.pl` and `inferno`: one line per stack, with the time spent
+    /// in its innermost entry in nanoseconds.
+    pub fn write_collapsed<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        let data = self.0.lock();
+        let mut parents = vec![0; data.nodes.len()];
+        for (i, node) in data.nodes.iter().enumerate() {
+            for child in node.children.values() {
+                parents[*child] = i;
+            }
+        }
+        let mut lines = Vec::new();
+        for (i, node) in data.nodes.iter().enumerate().skip(1) {
+            if node.own.is_zero() {
+                continue;
+            }
+            let frames: Vec<_> = data
+                .path(i, &parents)
+                .into_iter()
+                // `;` separates frames, so it can't appear in them.
+                .map(|id| data.entries[id].entry.to_string().replace(';', ":"))
+                .collect();
+            lines.push((frames.join(";"), node.own.as_nanos()));
+        }
+        lines.sort();
+        for (stack, nanos) in lines {
+            writeln!(w, "{} {}", stack, nanos)?;
+        }
+        Ok(())
+    }
+
+    /// Write a table of the `limit` entries that took the most time
+    /// themselves.
+    pub fn write_summary<W: io::Write>(&self, mut w: W, limit: usize) -> io::Result<()> {
+        writeln!(w, "{:>12} {:>12} {:>10}  entry", "own", "total", "calls")?;
+        for spot in self.hot_spots().into_iter().take(limit) {
+            writeln!(
+                w,
+                "{:>12.3?} {:>12.3?} {:>10}  {}",
+                spot.own, spot.total, spot.calls, spot.entry
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Active {
+    id: usize,
+    node: usize,
+    start: Instant,
+    /// Time spent in the frames this one called.
+    children: Duration,
+}
+
+struct Stack {
+    profiler: Rc<Profiler>,
+    frames: Vec<Active>,
+}
+
+thread_local! {
+    static STACK: RefCell<Option<Stack>> = RefCell::new(None);
+}
+
+/// Restores the previously attached profiler when dropped.
+pub(crate) struct Attached(Option<Stack>);
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        STACK.with(|stack| *stack.borrow_mut() = self.0.take());
+    }
+}
+
+/// Record frames with `profiler` on this thread, or stop recording them if
+/// there's no profiler.
+pub(crate) fn attach(profiler: Option<&Rc<Profiler>>) -> Attached {
+    let stack = profiler.map(|profiler| Stack {
+        profiler: profiler.to_owned(),
+        frames: Vec::new(),
+    });
+    Attached(STACK.with(|x| x.replace(stack)))
+}
+
+/// Run `f`, attributing the time it takes to `entry` if a profiler is
+/// attached.
+pub(crate) fn frame<T>(entry: impl FnOnce() -> Entry, f: impl FnOnce() -> T) -> T {
+    let entered = STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let stack = stack.as_mut()?;
+        let parent = stack.frames.last().map_or(0, |x| x.node);
+        let (id, node) = stack.profiler.0.lock().enter(entry(), parent);
+        stack.frames.push(Active {
+            id,
+            node,
+            start: Instant::now(),
+            children: Duration::ZERO,
+        });
+        Some(())
+    });
+    if entered.is_none() {
+        return f();
+    }
+    let res = f();
+    STACK.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let stack = match stack.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        let frame = match stack.frames.pop() {
+            Some(x) => x,
+            None => return,
+        };
+        let elapsed = frame.start.elapsed();
+        let own = elapsed.saturating_sub(frame.children);
+        let recursive = stack.frames.iter().any(|x| x.id == frame.id);
+        if let Some(parent) = stack.frames.last_mut() {
+            parent.children += elapsed;
+        }
+        let mut data = stack.profiler.0.lock();
+        data.nodes[frame.node].own += own;
+        let spot = &mut data.entries[frame.id];
+        spot.own += own;
+        if !recursive {
+            spot.total += elapsed;
+        }
+    });
+    res
+}
@@ -0,0 +1,221 @@
+use serde::{
+    de::{
+        self, value::StrDeserializer, DeserializeOwned, DeserializeSeed, EnumAccess,
+        IntoDeserializer, MapAccess, SeqAccess, Unexpected, VariantAccess, Visitor,
+    },
+    forward_to_deserialize_any,
+};
+
+use super::{Error, Segment};
+use crate::value::Value;
+
+/// Deserialize a `T` from `value`, forcing only the parts of it that `T`
+/// reads. Errors name the attribute path where they occurred.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(ValueDeserializer(value))
+}
+
+struct ValueDeserializer(Value);
+
+fn invalid_type<'de, V: Visitor<'de>>(value: &Value, visitor: &V) -> Error {
+    de::Error::invalid_type(Unexpected::Other(value.human_readable_type()), visitor)
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0.materialize()? {
+            Value::Null => visitor.visit_unit(),
+            Value::Boolean(x) => visitor.visit_bool(x),
+            Value::Integer(x) => visitor.visit_i64(x),
+            Value::Floating(x) => visitor.visit_f64(x),
+            Value::String(x) => match x.to_str() {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(x.as_bytes()),
+            },
+            Value::Path(x) => visitor.visit_string(x),
+            Value::List(x) => {
+                let items: Vec<_> = x.iter().cloned().collect();
+                visitor.visit_seq(Elements(items.into_iter().enumerate()))
+            }
+            Value::AttrSet(x) => {
+                let attrs: Vec<_> = x
+                    .iter()
+                    .map(|(k, v)| (k.as_str().to_owned(), v.to_owned()))
+                    .collect();
+                visitor.visit_map(Attributes {
+                    iter: attrs.into_iter(),
+                    value: None,
+                })
+            }
+            x => Err(invalid_type(&x, &visitor)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0.materialize()? {
+            Value::Null => visitor.visit_none(),
+            x => visitor.visit_some(ValueDeserializer(x)),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0.materialize()? {
+            Value::String(x) => visitor.visit_bytes(x.as_bytes()),
+            x => ValueDeserializer(x).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Enums are externally tagged: unit variants are strings, and others
+    /// are sets with a single attribute named after the variant.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.0.materialize()? {
+            Value::String(x) => {
+                visitor.visit_enum(x.to_string_lossy().into_owned().into_deserializer())
+            }
+            Value::AttrSet(x) if x.len() == 1 => {
+                let (name, value) = x.iter().next().expect("set has one attribute");
+                visitor.visit_enum(Variant {
+                    name: name.as_str().to_owned(),
+                    value: value.to_owned(),
+                })
+            }
+            x => Err(invalid_type(&x, &visitor)),
+        }
+    }
+
+    /// Ignored values aren't forced, so they can't fail.
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct Elements(std::iter::Enumerate<std::vec::IntoIter<Value>>);
+
+impl<'de> SeqAccess<'de> for Elements {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.0.next() {
+            Some((i, x)) => seed
+                .deserialize(ValueDeserializer(x))
+                .map(Some)
+                .map_err(|e| e.within(Segment::Index(i))),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct Attributes {
+    iter: std::vec::IntoIter<(String, Value)>,
+    /// The attribute whose name was just deserialized.
+    value: Option<(String, Value)>,
+}
+
+impl<'de> MapAccess<'de> for Attributes {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let (name, value) = match self.iter.next() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let key = seed
+            .deserialize(name.as_str().into_deserializer())
+            .map_err(|e: Error| e.within(Segment::Attr(name.clone())))?;
+        self.value = Some((name, value));
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (name, value) = self
+            .value
+            .take()
+            .expect("BUG: attribute value requested before its name");
+        seed.deserialize(ValueDeserializer(value))
+            .map_err(|e| e.within(Segment::Attr(name)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct Variant {
+    name: String,
+    value: Value,
+}
+
+impl<'de> EnumAccess<'de> for Variant {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let name: StrDeserializer<'_, Error> = self.name.as_str().into_deserializer();
+        let variant = seed.deserialize(name)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        let Variant { name, value } = self;
+        seed.deserialize(ValueDeserializer(value))
+            .map_err(|e| e.within(Segment::Attr(name)))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        let Variant { name, value } = self;
+        de::Deserializer::deserialize_seq(ValueDeserializer(value), visitor)
+            .map_err(|e| e.within(Segment::Attr(name)))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let Variant { name, value } = self;
+        de::Deserializer::deserialize_map(ValueDeserializer(value), visitor)
+            .map_err(|e| e.within(Segment::Attr(name)))
+    }
+}
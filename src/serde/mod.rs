@@ -0,0 +1,276 @@
+//! Converting between Nix values and Rust types with serde.
+//!
+//! Besides implementing `Serialize` and `Deserialize` for [`Value`], so that
+//! it can be read from and written to formats like JSON, this lets Nix files
+//! be used as typed configuration: [`from_value`] deserializes any Rust type
+//! directly from a value, forcing only the parts the type reads, and
+//! [`to_value`] builds a value from any Rust type.
+
+use std::{convert::TryInto, fmt};
+
+use serde::{
+    de::{self, Visitor},
+    ser::{self, Error as _, SerializeMap, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use thiserror::Error;
+
+use crate::{attrs::Attrs, evaluator::EvalError, stats::Stats, sync::Vector, value::Value};
+
+mod deserializer;
+pub use deserializer::from_value;
+
+mod serializer;
+pub use serializer::to_value;
+
+/// Where in a value an [`Error`] occurred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Attr(String),
+    Index(usize),
+}
+
+#[derive(Error, Debug)]
+pub enum ErrorKind {
+    #[error("{0}")]
+    Message(String),
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+}
+
+/// An error converting between a Nix value and a Rust type.
+#[derive(Error, Debug)]
+#[error("{}{kind}", Location(path))]
+pub struct Error {
+    /// Innermost first, as it's built up while the error propagates.
+    path: Vec<Segment>,
+    #[source]
+    kind: ErrorKind,
+}
+
+impl Error {
+    /// The attribute names and list indices leading to where the error
+    /// occurred, outermost first.
+    pub fn path(&self) -> impl Iterator<Item = &Segment> {
+        self.path.iter().rev()
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    fn within(mut self, segment: Segment) -> Self {
+        self.path.push(segment);
+        self
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Self {
+        Self {
+            path: Vec::new(),
+            kind: ErrorKind::Eval(e),
+        }
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self {
+            path: Vec::new(),
+            kind: ErrorKind::Message(msg.to_string()),
+        }
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        de::Error::custom(msg)
+    }
+}
+
+/// Formats a path like `a.b[2]: `, or nothing if it's empty.
+struct Location<'a>(&'a [Segment]);
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        for (i, segment) in self.0.iter().rev().enumerate() {
+            match segment {
+                Segment::Attr(name) if i == 0 => write!(f, "{}", name)?,
+                Segment::Attr(name) => write!(f, ".{}", name)?,
+                Segment::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        write!(f, ": ")
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Value::String(x) => serializer.serialize_str(&x.to_string_lossy()),
+            Value::Integer(x) => serializer.serialize_i64(*x),
+            Value::Floating(x) => serializer.serialize_f64(*x),
+            Value::Path(x) => serializer.serialize_str(x),
+            Value::Boolean(x) => serializer.serialize_bool(*x),
+            Value::Null => serializer.serialize_unit(),
+            Value::Function(_, _) => Err(S::Error::custom("cannot serialize functions")),
+            Value::AttrSet(x) => {
+                let mut map = serializer.serialize_map(Some(x.len()))?;
+                for (k, v) in x {
+                    map.serialize_entry(k.as_str(), v)?;
+                }
+                map.end()
+            }
+            Value::List(x) => {
+                let mut seq = serializer.serialize_seq(Some(x.len()))?;
+                for v in x {
+                    seq.serialize_element(v)?;
+                }
+                seq.end()
+            }
+            Value::Thunk(_) => Serialize::serialize(
+                &self
+                    .to_owned()
+                    .materialize_deep()
+                    .map_err(S::Error::custom)?,
+                serializer,
+            ),
+            Value::PrimOp(_) | Value::PrimOpApp(_, _) => {
+                Err(S::Error::custom("cannot serialize functions"))
+            }
+        }
+    }
+}
+
+impl Serialize for Stats {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(12))?;
+        map.serialize_entry("wallTime", &self.wall_time.as_secs_f64())?;
+        map.serialize_entry("thunksCreated", &self.thunks_created)?;
+        map.serialize_entry("thunksForced", &self.thunks_forced)?;
+        map.serialize_entry("functionCalls", &self.function_calls)?;
+        map.serialize_entry("primOpCalls", &self.primop_calls)?;
+        map.serialize_entry("attrSets", &self.attrsets)?;
+        map.serialize_entry("attrSetElements", &self.attrset_elements)?;
+        map.serialize_entry("lists", &self.lists)?;
+        map.serialize_entry("listElements", &self.list_elements)?;
+        map.serialize_entry("maxStackDepth", &self.max_stack_depth)?;
+        map.serialize_entry("filesParsed", &self.files_parsed)?;
+        map.serialize_entry("filesImported", &self.files_imported)?;
+        map.end()
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a value representable in Nix")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(v.into())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v.into())
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_i64(v.try_into().map_err(E::custom)?)
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v.into())
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_string(v.to_owned())
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(v.into())
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Value::String(v.into()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(Value::String(v.into()))
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Value::Null)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut v = Vector::default();
+        while let Some(x) = seq.next_element()? {
+            v.push_back_mut(x);
+        }
+        Ok(Value::List(v))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        let mut m = Attrs::new();
+        while let Some((k, v)) = map.next_entry::<String, _>()? {
+            m.insert_mut(k.into(), v);
+        }
+        Ok(Value::AttrSet(m))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
@@ -0,0 +1,328 @@
+use std::convert::TryInto;
+
+use serde::ser::{
+    self, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant,
+    SerializeTuple, SerializeTupleStruct, SerializeTupleVariant,
+};
+
+use super::{Error, Segment};
+use crate::{attrs::Attrs, sync::Vector, value::Value};
+
+/// Build a Nix value from `value`. Enums are externally tagged, as with
+/// [`from_value`](super::from_value).
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    value.serialize(ValueSerializer)
+}
+
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = ListBuilder;
+    type SerializeTuple = ListBuilder;
+    type SerializeTupleStruct = ListBuilder;
+    type SerializeTupleVariant = Tagged<ListBuilder>;
+    type SerializeMap = SetBuilder;
+    type SerializeStruct = SetBuilder;
+    type SerializeStructVariant = Tagged<SetBuilder>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        self.serialize_i64(v.try_into().map_err(<Error as ser::Error>::custom)?)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(v.to_string().into())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(v.into())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(Value::String(v.into()))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(variant.into())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        let value = value
+            .serialize(self)
+            .map_err(|e| e.within(Segment::Attr(variant.into())))?;
+        Ok(tagged(variant, value))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ListBuilder, Error> {
+        Ok(ListBuilder(Vector::default()))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListBuilder, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<ListBuilder, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Tagged<ListBuilder>, Error> {
+        Ok(Tagged(variant, self.serialize_seq(Some(len))?))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SetBuilder, Error> {
+        Ok(SetBuilder {
+            attrs: Attrs::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SetBuilder, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Tagged<SetBuilder>, Error> {
+        Ok(Tagged(variant, self.serialize_map(Some(len))?))
+    }
+}
+
+fn tagged(variant: &'static str, value: Value) -> Value {
+    let mut attrs = Attrs::new();
+    attrs.insert_mut(variant.into(), value);
+    Value::AttrSet(attrs)
+}
+
+struct ListBuilder(Vector<Value>);
+
+impl SerializeSeq for ListBuilder {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let value = to_value(value).map_err(|e| e.within(Segment::Index(self.0.len())))?;
+        self.0.push_back_mut(value);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::List(self.0))
+    }
+}
+
+impl SerializeTuple for ListBuilder {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        SerializeSeq::end(self)
+    }
+}
+
+impl SerializeTupleStruct for ListBuilder {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        SerializeSeq::end(self)
+    }
+}
+
+struct SetBuilder {
+    attrs: Attrs,
+    /// The name of the attribute whose value is serialized next.
+    key: Option<String>,
+}
+
+impl SetBuilder {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<(), Error> {
+        let value = to_value(value).map_err(|e| e.within(Segment::Attr(key.clone())))?;
+        self.attrs.insert_mut(key.into(), value);
+        Ok(())
+    }
+}
+
+impl SerializeMap for SetBuilder {
+    type Ok = Value;
+    type Error = Error;
+
+    /// Attribute names can be strings, or numbers and booleans, which are
+    /// converted to strings as in JSON.
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = match to_value(key)? {
+            Value::String(x) => x.to_string_lossy().into_owned(),
+            Value::Integer(x) => x.to_string(),
+            Value::Boolean(x) => x.to_string(),
+            x => {
+                return Err(ser::Error::custom(format!(
+                    "attribute names must be strings, not {}",
+                    x.human_readable_type()
+                )))
+            }
+        };
+        self.key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("BUG: map value serialized before its key");
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::AttrSet(self.attrs))
+    }
+}
+
+impl SerializeStruct for SetBuilder {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key.into(), value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        SerializeMap::end(self)
+    }
+}
+
+/// A variant holding a list or set, serialized as a set with a single
+/// attribute named after the variant.
+struct Tagged<T>(&'static str, T);
+
+impl SerializeTupleVariant for Tagged<ListBuilder> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let variant = self.0;
+        SerializeSeq::serialize_element(&mut self.1, value)
+            .map_err(|e| e.within(Segment::Attr(variant.into())))
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(tagged(self.0, SerializeSeq::end(self.1)?))
+    }
+}
+
+impl SerializeStructVariant for Tagged<SetBuilder> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let variant = self.0;
+        self.1
+            .insert(key.into(), value)
+            .map_err(|e| e.within(Segment::Attr(variant.into())))
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(tagged(self.0, SerializeMap::end(self.1)?))
+    }
+}
@@ -0,0 +1,45 @@
+//! Counts of the work done by an evaluation, like `NIX_SHOW_STATS` in Nix.
+
+use std::{cell::Cell, collections::BTreeMap, time::Duration};
+
+/// What a session has evaluated so far. Collected if enabled with
+/// [`Session::with_stats`](crate::evaluator::Session::with_stats).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Time spent evaluating, including values forced after
+    /// [`Session::eval`](crate::evaluator::Session::eval) returned.
+    pub wall_time: Duration,
+    pub thunks_created: u64,
+    pub thunks_forced: u64,
+    /// Calls to lambdas.
+    pub function_calls: u64,
+    /// Calls to each primop, by name.
+    pub primop_calls: BTreeMap<&'static str, u64>,
+    /// Attribute sets built by set literals and `//`.
+    pub attrsets: u64,
+    pub attrset_elements: u64,
+    /// Lists built by list literals and `++`.
+    pub lists: u64,
+    pub list_elements: u64,
+    /// The deepest nesting of function calls and thunk forces.
+    pub max_stack_depth: u64,
+    pub files_parsed: u64,
+    pub files_imported: u64,
+}
+
+thread_local! {
+    static DEPTH: Cell<u64> = Cell::new(0);
+}
+
+/// Run `f` one level deeper in the evaluation stack.
+pub(crate) fn nested<T>(f: impl FnOnce() -> T) -> T {
+    DEPTH.with(|x| x.set(x.get() + 1));
+    let res = f();
+    DEPTH.with(|x| x.set(x.get() - 1));
+    res
+}
+
+/// How deep in the evaluation stack this thread is.
+pub(crate) fn depth() -> u64 {
+    DEPTH.with(Cell::get)
+}
@@ -0,0 +1,293 @@
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::BTreeSet,
+    convert::TryFrom,
+    fmt::{self, Display, Write},
+    hash::{Hash, Hasher},
+    iter::FromIterator,
+    ops::{Add, Deref},
+    str::Utf8Error,
+    string::FromUtf8Error,
+};
+
+/// The store paths a string refers to, which Nix calls its context. Strings
+/// built from other strings keep the context of each, so that a derivation
+/// using them can depend on those paths. Derivations aren't supported, so
+/// only plain store paths are tracked, not derivation outputs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Context(BTreeSet<Box<str>>);
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn insert(&mut self, path: impl Into<Box<str>>) {
+        self.0.insert(path.into());
+    }
+
+    /// The store paths, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|x| &**x)
+    }
+}
+
+impl<'a> Extend<&'a str> for Context {
+    fn extend<T: IntoIterator<Item = &'a str>>(&mut self, iter: T) {
+        self.0.extend(iter.into_iter().map(Box::from))
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Context {
+    fn from_iter<T: IntoIterator<Item = &'a str>>(iter: T) -> Self {
+        let mut res = Self::new();
+        res.extend(iter);
+        res
+    }
+}
+
+/// A Nix string: an arbitrary sequence of bytes, and its [`Context`].
+///
+/// Nix makes no guarantee that strings are valid UTF-8 (`builtins.readFile` on
+/// a binary file is perfectly legal), so the evaluator stores them as bytes
+/// and only decodes them at the edges. Lengths and offsets are always in bytes.
+///
+/// As in Nix, strings compare by their bytes alone, whatever their context.
+#[derive(Clone, Default)]
+pub struct NixString {
+    // Both are boxed to keep strings, and so values, small. Appending to a
+    // string reallocates it, so long strings are best built as bytes first.
+    bytes: Box<[u8]>,
+    /// Only there if it isn't empty.
+    context: Option<Box<Context>>,
+}
+
+impl NixString {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The bytes of the string, without its context.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes.into_vec()
+    }
+
+    /// Borrow the string as UTF-8, failing if it contains invalid sequences.
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.bytes)
+    }
+
+    /// Decode the string as UTF-8, replacing invalid sequences with U+FFFD.
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.bytes)
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        let mut res = std::mem::take(&mut self.bytes).into_vec();
+        res.extend_from_slice(bytes);
+        self.bytes = res.into();
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.push_bytes(s.as_bytes())
+    }
+
+    /// Append `other`, and add its context to this string's.
+    pub fn push(&mut self, other: &NixString) {
+        self.push_bytes(other);
+        self.extend_context(other.context());
+    }
+
+    pub fn has_context(&self) -> bool {
+        self.context.is_some()
+    }
+
+    /// The store paths the string refers to.
+    pub fn context(&self) -> impl Iterator<Item = &str> {
+        self.context.iter().flat_map(|x| x.iter())
+    }
+
+    /// Add store paths to the string's context.
+    pub fn extend_context<'a>(&mut self, paths: impl IntoIterator<Item = &'a str>) {
+        let mut paths = paths.into_iter().peekable();
+        if paths.peek().is_some() {
+            self.context
+                .get_or_insert_with(Default::default)
+                .extend(paths);
+        }
+    }
+
+    /// The string with `context` added to its own.
+    pub fn with_context(mut self, context: &Context) -> Self {
+        self.extend_context(context.iter());
+        self
+    }
+
+    /// The string without any context, like
+    /// `builtins.unsafeDiscardStringContext`.
+    pub fn without_context(mut self) -> Self {
+        self.context = None;
+        self
+    }
+
+    /// Write the string as a quoted Nix string literal. Nix has no escape for
+    /// bytes that aren't valid UTF-8, so those are written as `\xNN` to show
+    /// what they are, and the result then isn't valid Nix: only strings that
+    /// are valid UTF-8 can be read back.
+    pub fn write_quoted<W: Write>(&self, f: &mut W) -> fmt::Result {
+        f.write_char('"')?;
+        let mut rest = self.as_bytes();
+        while !rest.is_empty() {
+            // The longest valid prefix, and the invalid sequence after it.
+            let (valid, invalid) = match std::str::from_utf8(rest) {
+                Ok(valid) => (valid, &[][..]),
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    let len = e.error_len().unwrap_or(after.len());
+                    let valid = std::str::from_utf8(valid).expect("is valid UTF-8");
+                    (valid, &after[..len])
+                }
+            };
+            rest = &rest[valid.len() + invalid.len()..];
+            let mut chars = valid.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => f.write_str("\\\"")?,
+                    '\\' => f.write_str("\\\\")?,
+                    '\n' => f.write_str("\\n")?,
+                    '\r' => f.write_str("\\r")?,
+                    '\t' => f.write_str("\\t")?,
+                    '$' if chars.peek() == Some(&'{') => f.write_str("\\$")?,
+                    c => f.write_char(c)?,
+                }
+            }
+            for b in invalid {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+impl Deref for NixString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl PartialEq for NixString {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for NixString {}
+
+impl PartialOrd for NixString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NixString {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bytes.cmp(&other.bytes)
+    }
+}
+
+impl Hash for NixString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bytes.hash(state)
+    }
+}
+
+impl AsRef<[u8]> for NixString {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<Vec<u8>> for NixString {
+    fn from(x: Vec<u8>) -> Self {
+        Self {
+            bytes: x.into(),
+            context: None,
+        }
+    }
+}
+
+impl From<&[u8]> for NixString {
+    fn from(x: &[u8]) -> Self {
+        Vec::from(x).into()
+    }
+}
+
+impl From<String> for NixString {
+    fn from(x: String) -> Self {
+        x.into_bytes().into()
+    }
+}
+
+impl From<&str> for NixString {
+    fn from(x: &str) -> Self {
+        x.as_bytes().into()
+    }
+}
+
+impl TryFrom<NixString> for String {
+    type Error = FromUtf8Error;
+
+    fn try_from(x: NixString) -> Result<Self, Self::Error> {
+        String::from_utf8(x.into_bytes())
+    }
+}
+
+impl Add<&NixString> for NixString {
+    type Output = NixString;
+
+    fn add(mut self, rhs: &NixString) -> NixString {
+        self.push(rhs);
+        self
+    }
+}
+
+impl FromIterator<u8> for NixString {
+    fn from_iter<T: IntoIterator<Item = u8>>(iter: T) -> Self {
+        iter.into_iter().collect::<Vec<_>>().into()
+    }
+}
+
+impl PartialEq<str> for NixString {
+    fn eq(&self, other: &str) -> bool {
+        *self.bytes == *other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for NixString {
+    fn eq(&self, other: &&str) -> bool {
+        *self.bytes == *other.as_bytes()
+    }
+}
+
+impl Display for NixString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+impl fmt::Debug for NixString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_quoted(f)
+    }
+}
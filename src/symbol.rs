@@ -0,0 +1,294 @@
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::HashSet,
+    fmt::{self, Display},
+    hash::{Hash, Hasher},
+    ops::Deref,
+};
+
+use crate::{sync::Rc, ErrorString};
+
+/// An interned identifier or attribute name.
+///
+/// Every distinct name is allocated once and shared, so symbols are compared
+/// and hashed by address rather than by contents. They're ordered by their
+/// contents, though, so that sorting symbols sorts the names.
+///
+/// Attribute names, like Nix strings, are bytes. Names that aren't valid
+/// UTF-8 are distinct by their bytes, and only shown with U+FFFD in place of
+/// the invalid ones.
+#[derive(Clone)]
+pub struct Symbol(Rc<Name>);
+
+struct Name {
+    text: Box<str>,
+    /// The bytes of the name, if they aren't the text's.
+    bytes: Option<Box<[u8]>>,
+}
+
+impl Name {
+    fn bytes(&self) -> &[u8] {
+        self.bytes.as_deref().unwrap_or(self.text.as_bytes())
+    }
+}
+
+impl Symbol {
+    /// Intern `name`, allocating it only if it isn't already in the table.
+    pub fn new(name: &str) -> Self {
+        Self::from_bytes(name.as_bytes())
+    }
+
+    /// Intern a name that may not be valid UTF-8.
+    pub fn from_bytes(name: &[u8]) -> Self {
+        with_table(name, |table| table.intern(name))
+    }
+
+    /// The symbol for `name`, if it's already been interned. If it hasn't,
+    /// no attribute can have that name.
+    pub fn lookup(name: &str) -> Option<Self> {
+        Self::lookup_bytes(name.as_bytes())
+    }
+
+    /// Like [`lookup`](Self::lookup), for a name that may not be valid UTF-8.
+    pub fn lookup_bytes(name: &[u8]) -> Option<Self> {
+        with_table(name, |table| {
+            table.strings.get(name).map(|x| Self(x.0.to_owned()))
+        })
+    }
+
+    /// The name, with U+FFFD in place of any bytes that aren't valid UTF-8.
+    pub fn as_str(&self) -> &str {
+        &self.0.text
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.bytes()
+    }
+}
+
+/// A name in the table, which is looked up by its bytes.
+struct Entry(Rc<Name>);
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.bytes() == other.0.bytes()
+    }
+}
+
+impl Eq for Entry {}
+
+impl Hash for Entry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.bytes().hash(state)
+    }
+}
+
+impl Borrow<[u8]> for Entry {
+    fn borrow(&self) -> &[u8] {
+        self.0.bytes()
+    }
+}
+
+struct Table {
+    strings: HashSet<Entry>,
+    /// The size at which the table is next swept for unused names.
+    sweep_at: usize,
+}
+
+impl Table {
+    const MIN_SWEEP: usize = 1024;
+
+    fn new() -> Self {
+        Self {
+            strings: HashSet::new(),
+            sweep_at: Self::MIN_SWEEP,
+        }
+    }
+
+    fn intern(&mut self, name: &[u8]) -> Symbol {
+        if let Some(x) = self.strings.get(name) {
+            return Symbol(x.0.to_owned());
+        }
+        // Names only referenced by the table itself can be dropped. Sweeping
+        // whenever the table doubles keeps this amortised constant time.
+        if self.strings.len() >= self.sweep_at {
+            self.strings.retain(|x| Rc::strong_count(&x.0) > 1);
+            self.sweep_at = (self.strings.len() * 2).max(Self::MIN_SWEEP);
+        }
+        let x = Rc::new(match std::str::from_utf8(name) {
+            Ok(text) => Name {
+                text: text.into(),
+                bytes: None,
+            },
+            Err(_) => Name {
+                text: String::from_utf8_lossy(name).into(),
+                bytes: Some(name.into()),
+            },
+        });
+        self.strings.insert(Entry(x.clone()));
+        Symbol(x)
+    }
+}
+
+/// Run `f` with the table that `name` belongs in.
+#[cfg(not(feature = "sync"))]
+fn with_table<T, F: FnOnce(&mut Table) -> T>(_name: &[u8], f: F) -> T {
+    use std::cell::RefCell;
+
+    thread_local! {
+        static TABLE: RefCell<Table> = RefCell::new(Table::new());
+    }
+    TABLE.with(|table| f(&mut table.borrow_mut()))
+}
+
+/// Run `f` with the table that `name` belongs in. Every name is looked up
+/// whenever an attribute is, so threads evaluating in parallel would mostly
+/// wait for each other behind a single lock. Names are spread by their hash
+/// across several tables instead, each with its own lock.
+#[cfg(feature = "sync")]
+fn with_table<T, F: FnOnce(&mut Table) -> T>(name: &[u8], f: F) -> T {
+    use crate::sync::Lock;
+    use once_cell::sync::Lazy;
+    use std::collections::hash_map::DefaultHasher;
+
+    const SHARDS: usize = 64;
+    static TABLES: Lazy<Vec<Lock<Table>>> =
+        Lazy::new(|| (0..SHARDS).map(|_| Lock::new(Table::new())).collect());
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    f(&mut TABLES[hasher.finish() as usize % SHARDS].lock())
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Rc::as_ptr(&self.0) as usize).hash(state)
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            Ordering::Equal
+        } else {
+            self.as_bytes().cmp(other.as_bytes())
+        }
+    }
+}
+
+impl Default for Symbol {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Symbol {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(x: &str) -> Self {
+        Self::new(x)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(x: String) -> Self {
+        Self::new(&x)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(x: &String) -> Self {
+        Self::new(x)
+    }
+}
+
+impl From<Symbol> for ErrorString {
+    fn from(x: Symbol) -> Self {
+        x.as_str().to_owned().into()
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// Something an attribute can be looked up by: either a [`Symbol`], or a
+/// plain string that's interned on demand.
+pub trait Key {
+    fn matches(&self, name: &Symbol) -> bool;
+    fn to_symbol(&self) -> Option<Symbol>;
+}
+
+impl Key for Symbol {
+    fn matches(&self, name: &Symbol) -> bool {
+        self == name
+    }
+
+    fn to_symbol(&self) -> Option<Symbol> {
+        Some(self.to_owned())
+    }
+}
+
+impl Key for str {
+    fn matches(&self, name: &Symbol) -> bool {
+        Key::matches(self.as_bytes(), name)
+    }
+
+    fn to_symbol(&self) -> Option<Symbol> {
+        Key::to_symbol(self.as_bytes())
+    }
+}
+
+impl Key for [u8] {
+    fn matches(&self, name: &Symbol) -> bool {
+        self == name.as_bytes()
+    }
+
+    fn to_symbol(&self) -> Option<Symbol> {
+        Symbol::lookup_bytes(self)
+    }
+}
+
+impl Key for String {
+    fn matches(&self, name: &Symbol) -> bool {
+        Key::matches(self.as_str(), name)
+    }
+
+    fn to_symbol(&self) -> Option<Symbol> {
+        Key::to_symbol(self.as_str())
+    }
+}
@@ -0,0 +1,81 @@
+//! Shared-ownership types used throughout the evaluator.
+//!
+//! By default these are the cheap single-threaded variants. With the `sync`
+//! feature they're swapped for atomically reference-counted, lock-protected
+//! equivalents, so that values can be sent to and shared between threads.
+
+#[cfg(not(feature = "sync"))]
+mod imp {
+    use std::cell::{RefCell, RefMut};
+
+    pub use std::rc::{Rc, Weak};
+
+    pub type RedBlackTreeMap<K, V> = rpds::RedBlackTreeMap<K, V>;
+    pub type Vector<T> = rpds::Vector<T>;
+
+    /// Bound for anything stored inside a value.
+    pub trait MaybeSend {}
+    impl<T> MaybeSend for T {}
+
+    #[derive(Default)]
+    pub struct Lock<T>(RefCell<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(x: T) -> Self {
+            Self(RefCell::new(x))
+        }
+
+        pub fn lock(&self) -> RefMut<'_, T> {
+            self.0.borrow_mut()
+        }
+    }
+
+    /// Identifies the thread forcing a thunk. Without `sync` there's only
+    /// ever one.
+    #[derive(Clone, Copy, PartialEq)]
+    pub struct ThreadMarker;
+
+    pub fn current_thread() -> ThreadMarker {
+        ThreadMarker
+    }
+}
+
+#[cfg(feature = "sync")]
+mod imp {
+    use std::{
+        sync::{Mutex, MutexGuard, PoisonError},
+        thread::{self, ThreadId},
+    };
+
+    pub use std::sync::{Arc as Rc, Weak};
+
+    pub type RedBlackTreeMap<K, V> = rpds::RedBlackTreeMapSync<K, V>;
+    pub type Vector<T> = rpds::VectorSync<T>;
+
+    /// Bound for anything stored inside a value.
+    pub trait MaybeSend: Send + Sync {}
+    impl<T: Send + Sync> MaybeSend for T {}
+
+    #[derive(Default)]
+    pub struct Lock<T>(Mutex<T>);
+
+    impl<T> Lock<T> {
+        pub fn new(x: T) -> Self {
+            Self(Mutex::new(x))
+        }
+
+        /// Nix evaluation is pure, so a panic while the lock was held can't
+        /// have left the contents in a state we care about.
+        pub fn lock(&self) -> MutexGuard<'_, T> {
+            self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    pub type ThreadMarker = ThreadId;
+
+    pub fn current_thread() -> ThreadMarker {
+        thread::current().id()
+    }
+}
+
+pub use imp::*;
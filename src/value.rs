@@ -1,15 +1,22 @@
 use std::{
+    cell::RefCell,
     cmp::Ordering,
     fmt::{self, Display},
-    rc::Rc,
 };
 
-use rnix::SyntaxNode;
-use rpds::{HashTrieMap, Vector};
 use thiserror::Error;
 
 use crate::{
-    evaluator::{eval_ctx, EvalError, EvaluationContext},
+    attrs::Attrs,
+    builtins::PrimOp,
+    debugger::{self, FrameKind},
+    evaluator::{self, bind_param, eval_expr, Env, EvalError},
+    ir::{CallSite, ExprRef, Lambda},
+    profiler::{self, Entry},
+    stats,
+    string::NixString,
+    symbol::Symbol,
+    sync::{current_thread, Lock, Rc, ThreadMarker, Vector, Weak},
     ErrorString,
 };
 
@@ -22,7 +29,7 @@ pub enum NumericValue {
 #[derive(Clone)]
 pub enum Value {
     // Scalar types
-    String(String),
+    String(NixString),
     Integer(i64),
     Floating(f64),
     Path(String),
@@ -30,18 +37,178 @@ pub enum Value {
     Null,
 
     // Complex types
-    Function(String, EvaluationContext, SyntaxNode),
-    AttrSet(HashTrieMap<String, Value>),
+    Function(Rc<Lambda>, Env),
+    AttrSet(Attrs),
     List(Vector<Value>),
 
     // Special types
-    Thunk(EvaluationContext, SyntaxNode),
-    BuiltinFunction(Rc<dyn Fn(Value) -> Result<Value, EvalError>>),
+    Thunk(Thunk),
+    PrimOp(Rc<PrimOp>),
+    /// A primop applied to fewer arguments than it takes.
+    PrimOpApp(Rc<PrimOp>, Rc<[Value]>),
+}
+
+enum ThunkState {
+    Suspended(Env, ExprRef),
+    /// The thunk is being forced by the given thread. Forcing it again on the
+    /// same thread means the value depends on itself. Another thread can
+    /// safely evaluate it independently, since evaluation is pure, and keeps
+    /// track of that in [`SHADOWED`].
+    Blackhole(ThreadMarker, Env, ExprRef),
+    Evaluated(Value),
+    /// The session the thunk was evaluated in has ended, and its value was
+    /// dropped in case it referred back to the thunk.
+    Released,
+}
+
+thread_local! {
+    /// The thunks this thread is evaluating while another thread holds their
+    /// blackhole, by address, so that it notices when they depend on
+    /// themselves too.
+    static SHADOWED: RefCell<Vec<usize>> = RefCell::new(Vec::new());
+}
+
+/// A deferred computation, which is evaluated at most once and shared by
+/// every copy of the value that contains it.
+#[derive(Clone)]
+pub struct Thunk(Rc<Lock<ThunkState>>);
+
+/// A reference to a thunk that doesn't keep it alive, held by the session it's
+/// evaluated in.
+pub(crate) struct WeakThunk(Weak<Lock<ThunkState>>);
+
+impl WeakThunk {
+    pub(crate) fn is_alive(&self) -> bool {
+        self.0.strong_count() > 0
+    }
+
+    /// Drop the thunk's value, if the thunk is still alive.
+    pub(crate) fn release(&self) {
+        if let Some(thunk) = self.0.upgrade() {
+            let state = std::mem::replace(&mut *thunk.lock(), ThunkState::Released);
+            drop(state);
+        }
+    }
+}
+
+impl Thunk {
+    pub(crate) fn new(env: Env, expr: ExprRef) -> Self {
+        evaluator::record(|x| x.thunks_created += 1);
+        // Thunks are only counted here, so creating one can't fail: the count
+        // is checked against the limit whenever a list or set is built.
+        let _ = evaluator::limit(|x| {
+            x.allocate(1);
+            Ok(())
+        });
+        Self(Rc::new(Lock::new(ThunkState::Suspended(env, expr))))
+    }
+
+    /// The thunk's value, if it has already been evaluated.
+    pub fn evaluated(&self) -> Option<Value> {
+        match &*self.0.lock() {
+            ThunkState::Evaluated(x) => Some(x.to_owned()),
+            _ => None,
+        }
+    }
+
+    pub fn force(&self) -> Result<Value, EvalError> {
+        let current = current_thread();
+        let address = Rc::as_ptr(&self.0) as usize;
+        let (env, expr, shadowed) = {
+            let mut state = self.0.lock();
+            match &*state {
+                ThunkState::Evaluated(x) => return Ok(x.to_owned()),
+                ThunkState::Released => return Err(EvalError::SessionEnded),
+                ThunkState::Blackhole(thread, _, _) if *thread == current => {
+                    return Err(EvalError::InfiniteRecursion)
+                }
+                ThunkState::Blackhole(_, env, expr) => {
+                    SHADOWED.with(|x| {
+                        let mut shadowed = x.borrow_mut();
+                        if shadowed.contains(&address) {
+                            return Err(EvalError::InfiniteRecursion);
+                        }
+                        shadowed.push(address);
+                        Ok(())
+                    })?;
+                    (env.to_owned(), expr.to_owned(), true)
+                }
+                ThunkState::Suspended(env, expr) => {
+                    let (env, expr) = (env.to_owned(), expr.to_owned());
+                    *state = ThunkState::Blackhole(current, env.clone(), expr.clone());
+                    (env, expr, false)
+                }
+            }
+        };
+        let res = {
+            let _session = env.enter();
+            let depth = stats::depth() + 1;
+            evaluator::record(|x| {
+                x.thunks_forced += 1;
+                x.max_stack_depth = x.max_stack_depth.max(depth);
+            });
+            let res = evaluator::limit(|x| x.step(depth)).and_then(|()| {
+                stats::nested(|| {
+                    debugger::frame(FrameKind::Force, expr.span(), &env, || {
+                        eval_expr(&expr, &env).and_then(Value::materialize)
+                    })
+                })
+            });
+            // A value holding other values may hold this thunk, as the list
+            // of `let xs = [ xs ]` does, which only the session can undo.
+            if let Ok(
+                Value::List(_) | Value::AttrSet(_) | Value::Function(..) | Value::PrimOpApp(..),
+            ) = &res
+            {
+                evaluator::track(WeakThunk(Rc::downgrade(&self.0)));
+            }
+            res
+        };
+        if shadowed {
+            SHADOWED.with(|x| x.borrow_mut().retain(|x| *x != address));
+        }
+        let mut state = self.0.lock();
+        match &res {
+            Ok(x) => *state = ThunkState::Evaluated(x.to_owned()),
+            Err(_) => {
+                // Leave the thunk as it was, so forcing it again reports the
+                // same error.
+                if matches!(&*state, ThunkState::Blackhole(thread, _, _) if *thread == current) {
+                    *state = ThunkState::Suspended(env, expr);
+                }
+            }
+        }
+        res
+    }
+}
+
+impl PartialEq for Thunk {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl From<NixString> for Value {
+    fn from(x: NixString) -> Self {
+        Value::String(x)
+    }
 }
 
 impl From<String> for Value {
     fn from(x: String) -> Self {
-        Value::String(x)
+        Value::String(x.into())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(x: &str) -> Self {
+        Value::String(x.into())
+    }
+}
+
+impl From<Symbol> for Value {
+    fn from(x: Symbol) -> Self {
+        Value::String(x.as_bytes().into())
     }
 }
 
@@ -107,11 +274,11 @@ impl Value {
             Value::Path(_) => "path",
             Value::Boolean(_) => "boolean",
             Value::Null => "null",
-            Value::Function(_, _, _) => "function",
+            Value::Function(_, _) => "function",
             Value::AttrSet(_) => "attribute set",
             Value::List(_) => "list",
-            Value::Thunk(_, _) => "thunk",
-            Value::BuiltinFunction(_) => "built-in function",
+            Value::Thunk(_) => "thunk",
+            Value::PrimOp(_) | Value::PrimOpApp(_, _) => "built-in function",
         }
     }
 
@@ -121,13 +288,22 @@ impl Value {
 
     pub fn add(&self, rhs_v: &Value) -> Result<Value, ArithmeticError> {
         if let Value::String(lhs) = self {
-            if let Value::String(rhs) = rhs_v {
-                Ok(Value::String(lhs.to_owned() + rhs))
-            } else {
-                Err(ArithmeticError::TypeMismatch(
+            match rhs_v {
+                Value::String(rhs) => Ok(Value::String(lhs.to_owned() + rhs)),
+                Value::Path(rhs) => Ok(Value::String(lhs.to_owned() + &rhs.as_str().into())),
+                _ => Err(ArithmeticError::TypeMismatch(
                     "string".into(),
                     rhs_v.human_readable_type().into(),
-                ))
+                )),
+            }
+        } else if let Value::Path(lhs) = self {
+            match rhs_v {
+                Value::String(rhs) => Ok(Value::Path(lhs.to_owned() + &rhs.to_string_lossy())),
+                Value::Path(rhs) => Ok(Value::Path(lhs.to_owned() + rhs)),
+                _ => Err(ArithmeticError::TypeMismatch(
+                    "string or path".into(),
+                    rhs_v.human_readable_type().into(),
+                )),
             }
         } else {
             match normalize_numerics(self, rhs_v)? {
@@ -177,6 +353,11 @@ impl Value {
     }
 
     pub fn compare(&self, rhs_v: &Value) -> Result<Ordering, ArithmeticError> {
+        match (self, rhs_v) {
+            (Value::String(lhs), Value::String(rhs)) => return Ok(lhs.cmp(rhs)),
+            (Value::Path(lhs), Value::Path(rhs)) => return Ok(lhs.cmp(rhs)),
+            _ => {}
+        }
         Ok(match normalize_numerics(self, rhs_v)? {
             Normalized::Integer(lhs, rhs) => lhs.cmp(&rhs),
             Normalized::Floating(lhs, rhs) => lhs
@@ -185,9 +366,88 @@ impl Value {
         })
     }
 
+    /// Deep equality, as implemented by Nix's `==` operator. Forces both
+    /// sides as far as needed to decide.
+    pub fn equals(&self, rhs_v: &Value) -> Result<bool, EvalError> {
+        let lhs = self.to_owned().materialize()?;
+        let rhs = rhs_v.to_owned().materialize()?;
+        Ok(match (&lhs, &rhs) {
+            (Value::List(l), Value::List(r)) => {
+                if l.len() != r.len() {
+                    return Ok(false);
+                }
+                for (l, r) in l.iter().zip(r.iter()) {
+                    if !l.equals(r)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            (Value::AttrSet(l), Value::AttrSet(r)) => {
+                // Derivations are equal if their outputs are.
+                if lhs.is_derivation()? && rhs.is_derivation()? {
+                    if let (Some(l), Some(r)) = (l.get("outPath"), r.get("outPath")) {
+                        return l.equals(r);
+                    }
+                }
+                if l.len() != r.len() {
+                    return Ok(false);
+                }
+                for (k, l) in l.iter() {
+                    match r.get(k) {
+                        Some(r) if l.equals(r)? => {}
+                        _ => return Ok(false),
+                    }
+                }
+                true
+            }
+            (l, _) if l.callable() => false,
+            (l, r) if l.is_numeric() && r.is_numeric() => l.compare(r)?.is_eq(),
+            (l, r) => l == r,
+        })
+    }
+
+    fn is_derivation(&self) -> Result<bool, EvalError> {
+        if let Value::AttrSet(set) = self {
+            if let Some(t) = set.get("type") {
+                return Ok(t.to_owned().materialize()? == Value::String("derivation".into()));
+            }
+        }
+        Ok(false)
+    }
+
+    /// Convert a value to a string the way string interpolation does: only
+    /// strings, paths and attribute sets with `__toString` or `outPath` are
+    /// accepted.
+    pub fn coerce_to_string(self) -> Result<NixString, EvalError> {
+        match self.materialize()? {
+            Value::String(x) => Ok(x),
+            Value::Path(x) => Ok(x.into()),
+            Value::AttrSet(set) => {
+                if let Some(f) = set.get("__toString") {
+                    f.to_owned()
+                        .materialize()?
+                        .call(Value::AttrSet(set.clone()))?
+                        .coerce_to_string()
+                } else if let Some(out_path) = set.get("outPath") {
+                    out_path.to_owned().coerce_to_string()
+                } else {
+                    Err(EvalError::TypeMismatch(
+                        "string".into(),
+                        "attribute set".into(),
+                    ))
+                }
+            }
+            x => Err(EvalError::TypeMismatch(
+                "string".into(),
+                x.human_readable_type().into(),
+            )),
+        }
+    }
+
     pub fn materializable(&self) -> bool {
         match self {
-            Self::Thunk(_, _) => true,
+            Self::Thunk(_) => true,
             Self::AttrSet(set) => set.values().any(|x| x.materializable()),
             Self::List(list) => list.iter().any(|x| x.materializable()),
             _ => false,
@@ -195,8 +455,8 @@ impl Value {
     }
 
     pub fn materialize(self) -> Result<Self, EvalError> {
-        if let Self::Thunk(ctx, body) = self {
-            eval_ctx(body, ctx)
+        if let Self::Thunk(thunk) = self {
+            thunk.force()
         } else {
             Ok(self)
         }
@@ -210,7 +470,7 @@ impl Value {
                 if let Some(v) = set.get(k) {
                     if v.materializable() {
                         deeply_materialized
-                            .insert_mut(k.to_string(), v.to_owned().materialize_deep()?);
+                            .insert_mut(k.to_owned(), v.to_owned().materialize_deep()?);
                     }
                 }
             }
@@ -230,27 +490,63 @@ impl Value {
         }
     }
 
+    /// Whether the value can be applied to an argument. This includes
+    /// attribute sets with a `__functor` attribute, which `builtins.isFunction`
+    /// does not consider to be functions.
     pub fn callable(&self) -> bool {
-        matches!(self, Value::Function(_, _, _) | Value::BuiltinFunction(_))
+        match self {
+            Value::Function(_, _) | Value::PrimOp(_) | Value::PrimOpApp(_, _) => true,
+            Value::AttrSet(set) => set.contains_key("__functor"),
+            _ => false,
+        }
     }
 
     pub fn call(self, val: Value) -> Result<Self, EvalError> {
-        if let Self::Function(param, ctx, body) = self {
-            let ctx = ctx.with(param, val);
-            eval_ctx(body, ctx)
-        } else if let Self::BuiltinFunction(f) = self {
-            f(val)
-        } else {
-            Err(EvalError::TypeMismatch(
+        self.call_at(val, None)
+    }
+
+    /// Apply the value to `val` at `site` in the source, which is where
+    /// builtins report diagnostics from.
+    pub(crate) fn call_at(self, val: Value, site: Option<&CallSite>) -> Result<Self, EvalError> {
+        match self.materialize()? {
+            Self::Function(lambda, env) => {
+                let depth = stats::depth() + 1;
+                evaluator::record(|x| {
+                    x.function_calls += 1;
+                    x.max_stack_depth = x.max_stack_depth.max(depth);
+                });
+                evaluator::limit(|x| x.step(depth))?;
+                stats::nested(|| {
+                    profiler::frame(
+                        || Entry::Lambda(lambda.location.clone()),
+                        || {
+                            let env = bind_param(&lambda, &env, val)?;
+                            eval_expr(&lambda.body, &env)
+                        },
+                    )
+                })
+            }
+            Self::PrimOp(op) => op.apply(&[], val, site),
+            Self::PrimOpApp(op, args) => op.apply(&args, val, site),
+            // `f x` is `f.__functor f x`.
+            Self::AttrSet(ref set) if set.contains_key("__functor") => {
+                let functor = set.get("__functor").unwrap().to_owned();
+                let this = Self::AttrSet(set.to_owned());
+                functor
+                    .materialize()?
+                    .call_at(this, site)?
+                    .call_at(val, site)
+            }
+            other => Err(EvalError::TypeMismatch(
                 "function".into(),
-                self.human_readable_type().into(),
-            ))
+                other.human_readable_type().into(),
+            )),
         }
     }
 
     fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, indent_count: usize) -> fmt::Result {
         match self {
-            Value::String(x) => write!(f, "\"{}\"", x),
+            Value::String(x) => x.write_quoted(f),
             Value::Integer(x) => write!(f, "{}", x),
             Value::Floating(x) => write!(f, "{}", x),
             Value::Path(x) => write!(f, "{}", x),
@@ -278,9 +574,12 @@ impl Value {
                 }
                 write!(f, "{}]", indent)
             }
-            Value::Thunk(ctx, node) => eval_ctx(node.to_owned(), ctx.to_owned())
+            Value::Thunk(thunk) => thunk
+                .force()
                 .map_err(|_| fmt::Error)?
                 .fmt_indented(f, indent_count),
+            Value::PrimOp(_) => write!(f, "«primop»"),
+            Value::PrimOpApp(_, _) => write!(f, "«primop-app»"),
             _ => write!(f, "<{}>", self.human_readable_type()),
         }
     }
@@ -298,6 +597,9 @@ impl From<&Value> for NumericValue {
     }
 }
 
+/// Thunks are forced as they're written, and one that fails makes formatting
+/// fail, which `to_string` panics on. Force values that may fail with
+/// [`Value::materialize_deep`] first.
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.fmt_indented(f, 0)
@@ -318,13 +620,12 @@ impl PartialEq for Value {
             (Self::Floating(l0), Self::Floating(r0)) => l0 == r0,
             (Self::Path(l0), Self::Path(r0)) => l0 == r0,
             (Self::Boolean(l0), Self::Boolean(r0)) => l0 == r0,
-            (Self::Function(l0, l1, l2), Self::Function(r0, r1, r2)) => {
-                l0 == r0 && l1 == r1 && l2 == r2
-            }
+            (Self::Function(l0, l1), Self::Function(r0, r1)) => Rc::ptr_eq(l0, r0) && l1 == r1,
             (Self::AttrSet(l0), Self::AttrSet(r0)) => l0 == r0,
             (Self::List(l0), Self::List(r0)) => l0 == r0,
-            (Self::Thunk(l0, l1), Self::Thunk(r0, r1)) => l0 == r0 && l1 == r1,
-            (Self::BuiltinFunction(_), Self::BuiltinFunction(_)) => false,
+            (Self::Thunk(l0), Self::Thunk(r0)) => l0 == r0,
+            (Self::PrimOp(l0), Self::PrimOp(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::PrimOpApp(_, _), Self::PrimOpApp(_, _)) => false,
             _ => core::mem::discriminant(self) == core::mem::discriminant(other),
         }
     }
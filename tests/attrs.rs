@@ -0,0 +1,114 @@
+//! Attribute sets change representation as they grow, and have to behave
+//! the same either way.
+
+use nix_evaluator::{attrs::Attrs, symbol::Symbol, value::Value};
+
+fn set(names: &[&str], value: i64) -> Attrs {
+    names
+        .iter()
+        .map(|x| (Symbol::new(x), Value::from(value)))
+        .collect()
+}
+
+fn names(attrs: &Attrs) -> Vec<&[u8]> {
+    attrs.keys().map(Symbol::as_bytes).collect()
+}
+
+const NINE: [&str; 9] = ["i", "h", "g", "f", "e", "d", "c", "b", "a"];
+
+#[test]
+fn inserting_past_the_small_limit() {
+    let mut attrs = set(&NINE[..Attrs::SMALL_MAX], 1);
+    assert!(matches!(attrs, Attrs::Small(_)));
+
+    // Replacing an attribute of a full small set keeps it small.
+    attrs.insert_mut("e".into(), 2.into());
+    assert!(matches!(attrs, Attrs::Small(_)));
+    assert_eq!(attrs.get("e"), Some(&Value::from(2)));
+
+    let large = attrs.insert("a".into(), 3.into());
+    assert!(matches!(large, Attrs::Large(_)));
+    assert!(matches!(attrs, Attrs::Small(_)));
+    assert_eq!(large.len(), 9);
+    assert_eq!(names(&large), names(&set(&NINE, 0)));
+    assert_eq!(large.get("a"), Some(&Value::from(3)));
+    assert_eq!(large.get("e"), Some(&Value::from(2)));
+    assert_eq!(large.get("i"), Some(&Value::from(1)));
+    assert_eq!(large.get("z"), None);
+
+    let mut removed = large.clone();
+    removed.remove_mut("e");
+    assert_eq!(removed.len(), 8);
+    assert_eq!(removed.get("e"), None);
+    assert_eq!(large.get("e"), Some(&Value::from(2)));
+}
+
+#[test]
+fn updating_past_the_small_limit() {
+    let left = set(&NINE[..5], 1);
+    let right = set(&NINE[4..], 2);
+    let both = left.update(&right);
+    assert!(matches!(both, Attrs::Large(_)));
+    assert_eq!(names(&both), names(&set(&NINE, 0)));
+    assert_eq!(both.get("i"), Some(&Value::from(1)));
+    assert_eq!(both.get("e"), Some(&Value::from(2)));
+    assert_eq!(both.get("a"), Some(&Value::from(2)));
+
+    // The right side wins whichever side is larger.
+    let small = set(&["a", "z"], 3);
+    let large = set(&NINE, 4);
+    let res = small.update(&large);
+    assert_eq!(res.len(), 10);
+    assert_eq!(res.get("a"), Some(&Value::from(4)));
+    assert_eq!(res.get("z"), Some(&Value::from(3)));
+    let res = large.update(&small);
+    assert_eq!(res.len(), 10);
+    assert_eq!(res.get("a"), Some(&Value::from(3)));
+    assert_eq!(res.get("b"), Some(&Value::from(4)));
+
+    assert!(large.update(&Attrs::new()) == large);
+    assert!(Attrs::new().update(&large) == large);
+}
+
+#[test]
+fn attributes_are_sorted_by_their_bytes() {
+    let sorted: [&[u8]; 7] = [
+        b"B",
+        b"Z",
+        b"a",
+        b"b",
+        "ä".as_bytes(),
+        "é".as_bytes(),
+        b"\xff",
+    ];
+    let mut reversed: Vec<_> = sorted.iter().rev().map(|x| Symbol::from_bytes(x)).collect();
+    let small: Attrs = reversed
+        .iter()
+        .map(|x| (x.to_owned(), Value::Null))
+        .collect();
+    assert!(matches!(small, Attrs::Small(_)));
+    assert_eq!(names(&small), sorted);
+
+    reversed.extend(["c", "d"].iter().map(|x| Symbol::new(x)));
+    let large: Attrs = reversed.into_iter().map(|x| (x, Value::Null)).collect();
+    assert!(matches!(large, Attrs::Large(_)));
+    let mut expected = sorted.to_vec();
+    expected.insert(4, b"c");
+    expected.insert(5, b"d");
+    assert_eq!(names(&large), expected);
+}
+
+#[test]
+fn unused_names_are_swept() {
+    let kept = Symbol::new("a name that's kept");
+    let name = "a name that's dropped";
+    drop(Symbol::new(name));
+    assert!(Symbol::lookup(name).is_some());
+
+    // Enough new names that every table is swept at least once.
+    for i in 0..200_000 {
+        Symbol::new(&format!("filler {}", i));
+    }
+    assert!(Symbol::lookup(name).is_none());
+    assert_eq!(Symbol::lookup("a name that's kept"), Some(kept));
+}
@@ -0,0 +1,151 @@
+//! Runs `nix_evaluator eval` as a script would, checking what it prints and
+//! its exit code.
+
+#![cfg(feature = "cli")]
+
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+struct Output {
+    code: i32,
+    stdout: String,
+    stderr: String,
+}
+
+fn eval_with_stdin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nix_evaluator"))
+        .arg("eval")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    Output {
+        code: output.status.code().unwrap(),
+        stdout: String::from_utf8(output.stdout).unwrap(),
+        stderr: String::from_utf8(output.stderr).unwrap(),
+    }
+}
+
+fn eval(args: &[&str]) -> Output {
+    eval_with_stdin(args, "")
+}
+
+/// Check that `args` succeed and print `stdout`.
+fn prints(args: &[&str], stdout: &str) {
+    let output = eval(args);
+    assert_eq!(output.code, 0, "{:?}: {}", args, output.stderr);
+    assert_eq!(output.stdout, stdout, "{:?}", args);
+}
+
+/// Check that `args` fail with `code` and an error containing `message`.
+fn fails(args: &[&str], code: i32, message: &str) {
+    let output = eval(args);
+    assert_eq!(output.code, code, "{:?}: {}", args, output.stderr);
+    assert_eq!(output.stdout, "", "{:?}", args);
+    assert!(
+        output.stderr.starts_with("error: ") && output.stderr.contains(message),
+        "{:?}: {}",
+        args,
+        output.stderr
+    );
+}
+
+#[test]
+fn sources() {
+    prints(&["-E", "1 + 1"], "2\n");
+    let output = eval_with_stdin(&[], "{ a = 1; }");
+    assert_eq!((output.code, output.stdout.as_str()), (0, "{ a = 1; }\n"));
+
+    let file = std::env::temp_dir().join(format!("nix_evaluator_cli_{}.nix", std::process::id()));
+    fs::write(&file, "[ 1 2 ]").unwrap();
+    let output = eval(&["-f", file.to_str().unwrap(), "--json"]);
+    fs::remove_file(&file).unwrap();
+    assert_eq!((output.code, output.stdout.as_str()), (0, "[1,2]\n"));
+}
+
+#[test]
+fn output_formats() {
+    let set = "{ a = 1; b = [ 1 (1 + 1) ]; }";
+    prints(&["-E", set, "--strict"], "{ a = 1; b = [ 1 2 ]; }\n");
+    prints(&["-E", set, "--json"], "{\"a\":1,\"b\":[1,2]}\n");
+    // Raw output is printed as it is, without a newline.
+    prints(&["-E", r#""a\nb""#, "--raw"], "a\nb");
+    fails(&["-E", "1", "--raw"], 1, "expected string, found integer");
+    fails(&["-E", "x: x", "--json"], 1, "to JSON");
+}
+
+#[test]
+fn attribute_paths() {
+    let set = r#"{ a.b = [ 1 { c = 2; } ]; "x.y" = 3; }"#;
+    prints(&["-E", set, "-A", "a.b.1.c"], "2\n");
+    prints(&["-E", set, "--attr", r#""x.y""#], "3\n");
+    fails(
+        &["-E", set, "-A", "a.d"],
+        1,
+        "attribute 'd' in selection path 'a.d' not found",
+    );
+    fails(&["-E", set, "-A", "a.b.2"], 1, "out of range");
+    fails(&["-E", set, "-A", "a.b.x"], 1, "not a list index");
+    fails(
+        &["-E", set, "-A", r#""x.y".z"#],
+        1,
+        "should be a set or a list",
+    );
+    fails(&["-E", set, "-A", r#""x"#], 1, "missing closing quote");
+}
+
+#[test]
+fn arguments() {
+    let f = "{ x, y ? 1 }: x + y";
+    prints(&["-E", f, "--arg", "x", "2"], "3\n");
+    prints(&["-E", f, "--arg", "x", "2", "--arg", "y", "1 + 2"], "5\n");
+    prints(
+        &["-E", r#"{ s }: s + "!""#, "--argstr", "s", "hi", "--raw"],
+        "hi!",
+    );
+    // Functions selected by an attribute path are called with them too.
+    prints(
+        &["-E", "{ f = { x }: x * 2; }", "-A", "f", "--arg", "x", "4"],
+        "8\n",
+    );
+    prints(&["-E", "3", "--apply", "x: x * 2"], "6\n");
+    fails(&["-E", f], 1, "without required argument x");
+    fails(
+        &["-E", f, "--arg", "x", "("],
+        1,
+        "failed to evaluate --arg x",
+    );
+}
+
+#[test]
+fn exit_codes() {
+    fails(&["-E", r#"throw "no""#], 1, "no");
+    fails(
+        &["-E", "1", "--json", "--raw"],
+        2,
+        "only one of --json and --raw",
+    );
+    fails(
+        &["-E", "1", "-f", "x.nix"],
+        2,
+        "only one of --expr and --file",
+    );
+    fails(&["-E"], 2, "-E requires an argument");
+    fails(&["--arg", "x"], 2, "--arg requires an argument");
+    fails(&["--bogus"], 2, "unrecognised argument '--bogus'");
+    let output = eval(&["--help"]);
+    assert_eq!(output.code, 0);
+    assert!(output.stdout.starts_with("usage: nix_evaluator eval"));
+}
@@ -0,0 +1,54 @@
+use nix_evaluator::{
+    debugger::{Debugger, PauseReason, Paused, Resume},
+    embed::Evaluator,
+    evaluator::Session,
+    sync::{Lock, Rc},
+};
+
+/// Records the message of every error it pauses on.
+#[derive(Default)]
+struct Errors(Lock<Vec<String>>);
+
+impl Debugger for Errors {
+    fn pause(&self, paused: &Paused<'_>) -> Resume {
+        if let PauseReason::Error(err) = paused.reason {
+            self.0.lock().push(err.to_string());
+        }
+        Resume::Continue
+    }
+}
+
+fn paused_on(source: &str) -> Vec<String> {
+    let errors = Rc::new(Errors::default());
+    let evaluator = Evaluator::builder()
+        .session(Session::new().with_debugger(errors.clone()))
+        .build();
+    let _ = evaluator
+        .eval_str(source)
+        .and_then(|x| x.materialize_deep());
+    drop(evaluator);
+    let errors = errors.0.lock().clone();
+    errors
+}
+
+#[test]
+fn errors_caught_by_try_eval_dont_pause() {
+    assert_eq!(
+        paused_on(r#"(builtins.tryEval (throw "a")).success"#),
+        Vec::<String>::new()
+    );
+}
+
+#[test]
+fn errors_pause_once() {
+    assert_eq!(paused_on(r#"[ (throw "a") ]"#).len(), 1);
+    assert_eq!(paused_on(r#"builtins.tryEval ({ a = 1; } + 1)"#).len(), 1);
+}
+
+#[test]
+fn errors_after_a_caught_one_pause() {
+    let errors =
+        paused_on(r#"let x = builtins.tryEval (throw "a"); in if x.success then 1 else throw "b""#);
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains('b'), "{:?}", errors);
+}
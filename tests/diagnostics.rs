@@ -0,0 +1,45 @@
+use nix_evaluator::{
+    builtins::BuiltinError,
+    diagnostics::{Diagnostics, MemorySink},
+    embed::Evaluator,
+    evaluator::{EvalError, Session},
+    sync::Rc,
+};
+
+#[test]
+fn diagnostics_have_a_location() {
+    let sink = Rc::new(MemorySink::new());
+    let evaluator = Evaluator::builder()
+        .session(Session::new().with_diagnostics(Diagnostics::new(sink.clone())))
+        .build();
+    evaluator
+        .eval_str_as(
+            "let f = x: x;\nin f (builtins.trace \"hi\" 1)",
+            "/dir/file.nix",
+        )
+        .and_then(|x| x.materialize())
+        .unwrap();
+    let diagnostics = sink.take();
+    assert_eq!(diagnostics.len(), 1);
+    let location = diagnostics[0].location.as_ref().unwrap();
+    assert_eq!(
+        (&*location.file, location.line, location.column),
+        ("/dir/file.nix", 2, 7)
+    );
+}
+
+#[test]
+fn trace_forces_values_before_writing_them() {
+    let sink = Rc::new(MemorySink::new());
+    let evaluator = Evaluator::builder()
+        .session(Session::new().with_diagnostics(Diagnostics::new(sink.clone())))
+        .build();
+    let eval = |source: &str| evaluator.eval_str(source).and_then(|x| x.materialize());
+    eval("builtins.trace { a = 1 + 1; } null").unwrap();
+    assert_eq!(sink.take()[0].message, "{\n  a = 2\n}");
+    assert!(matches!(
+        eval(r#"builtins.trace { a = abort "x"; } 1"#),
+        Err(EvalError::Builtin(BuiltinError::Aborted(_)))
+    ));
+    assert!(sink.take().is_empty());
+}
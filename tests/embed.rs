@@ -0,0 +1,119 @@
+use std::{io, path::Path};
+
+use nix_evaluator::{
+    attrs::Attrs,
+    builtins::{BuiltinError, PrimOp},
+    embed::{Evaluator, IoPolicy, Store},
+    evaluator::EvalError,
+    sync::Rc,
+    value::Value,
+};
+
+fn restricted() -> Evaluator {
+    Evaluator::builder()
+        .io_policy(IoPolicy::Restricted(Vec::new()))
+        .build()
+}
+
+#[test]
+fn auto_call_functor() {
+    let mut args = Attrs::new();
+    args.insert_mut("a".into(), 1.into());
+    let value = Evaluator::new()
+        .eval_expr_with_args(
+            "{ __functor = self: { a, b ? 2 }: a + b + self.c; c = 10; }",
+            &args,
+        )
+        .unwrap();
+    assert_eq!(value, Value::from(13));
+}
+
+#[test]
+fn restricted_get_env_is_empty() {
+    let value = restricted().eval_str(r#"builtins.getEnv "PATH""#).unwrap();
+    assert_eq!(value, Value::from(""));
+}
+
+#[test]
+fn restricted_find_file_checks_access() {
+    let res =
+        restricted().eval_str(r#"builtins.findFile [ { prefix = ""; path = "/etc"; } ] "passwd""#);
+    assert!(
+        matches!(res, Err(EvalError::Builtin(BuiltinError::AccessDenied(_)))),
+        "{:?}",
+        res.err()
+    );
+}
+
+#[test]
+fn primop_without_arguments_is_rejected() {
+    let res = PrimOp::new("constant", &[], &[], |_| Ok(Value::Null));
+    assert!(matches!(res, Err(BuiltinError::NoArguments(_))));
+}
+
+/// Keeps files in memory, under `/store`.
+struct TestStore;
+
+impl Store for TestStore {
+    fn store_dir(&self) -> &str {
+        "/store"
+    }
+
+    fn add_text(&self, name: &str, _: &[u8]) -> io::Result<String> {
+        Ok(format!("/store/{}", name))
+    }
+
+    fn add_path(&self, path: &Path) -> io::Result<String> {
+        Ok(format!(
+            "/store/{}",
+            path.file_name().unwrap().to_string_lossy()
+        ))
+    }
+}
+
+#[test]
+fn string_context() {
+    let evaluator = Evaluator::builder().store(Rc::new(TestStore)).build();
+    let eval = |source: &str| evaluator.eval_str(source)?.materialize_deep();
+    let file = r#"builtins.toFile "f" "x""#;
+    assert_eq!(
+        eval(&format!("builtins.getContext ({})", file)).unwrap(),
+        eval(r#"{ "/store/f" = { path = true; }; }"#).unwrap()
+    );
+    // Strings built from others keep their context.
+    for source in [
+        r#""a${f}b""#,
+        r#""a" + f"#,
+        r#"builtins.substring 0 1 f"#,
+        r#"builtins.concatStringsSep "," [ "a" f ]"#,
+        r#"builtins.replaceStrings [ "a" ] [ f ] "abc""#,
+        r#"builtins.toString [ 1 f ]"#,
+        r#"builtins.dirOf f"#,
+        r#"builtins.toJSON { a = [ f ]; }"#,
+    ] {
+        let source = format!("let f = {}; in builtins.hasContext ({})", file, source);
+        assert_eq!(eval(&source).unwrap(), Value::from(true), "{}", source);
+    }
+    // Paths that toJSON copies to the store are in the result's context.
+    assert_eq!(
+        eval("builtins.attrNames (builtins.getContext (builtins.toJSON [ /some/file ]))").unwrap(),
+        eval(r#"[ "/store/file" ]"#).unwrap()
+    );
+    assert_eq!(
+        eval(&format!(
+            "builtins.hasContext (builtins.unsafeDiscardStringContext ({}))",
+            file
+        ))
+        .unwrap(),
+        Value::from(false)
+    );
+    // Context doesn't affect equality.
+    assert_eq!(
+        eval(&format!(r#"{} == "/store/f""#, file)).unwrap(),
+        Value::from(true)
+    );
+    assert_eq!(
+        eval(r#"builtins.hasContext (builtins.toJSON { a = "b"; })"#).unwrap(),
+        Value::from(false)
+    );
+}
@@ -0,0 +1,331 @@
+use nix_evaluator::{builtins::BuiltinError, embed::Evaluator, evaluator::EvalError, value::Value};
+
+fn eval(source: &str) -> Result<Value, EvalError> {
+    Evaluator::new().eval_str(source)?.materialize_deep()
+}
+
+#[test]
+fn rec_set_with_constant_dynamic_key() {
+    assert_eq!(
+        eval(r#"rec { a = 1; ${"b"} = a; }.b"#).unwrap(),
+        Value::from(1)
+    );
+    assert_eq!(
+        eval(r#"let a = 1; in rec { x = a; ${"y"} = x; }.y"#).unwrap(),
+        Value::from(1)
+    );
+}
+
+#[test]
+fn let_with_constant_dynamic_key() {
+    assert!(matches!(
+        eval(r#"let ${"x"} = 1; in x"#),
+        Err(EvalError::DynamicLet)
+    ));
+}
+
+#[test]
+fn to_json_without_store_writes_paths() {
+    assert_eq!(
+        eval("builtins.toJSON [ /some/path ]").unwrap(),
+        Value::from(r#"["/some/path"]"#)
+    );
+}
+
+#[test]
+fn get_env_unset_is_empty() {
+    assert_eq!(
+        eval(r#"builtins.getEnv "NIX_EVALUATOR_TEST_UNSET""#).unwrap(),
+        Value::from("")
+    );
+}
+
+#[test]
+fn attribute_names_that_arent_utf8_are_distinct() {
+    let evaluator = Evaluator::builder()
+        .global("a", Value::String(vec![0xff].into()))
+        .global("b", Value::String(vec![0xfe].into()))
+        .build();
+    let eval = |source: &str| evaluator.eval_str(source)?.materialize_deep();
+    let set = "builtins.listToAttrs [ { name = a; value = 1; } { name = b; value = 2; } ]";
+    assert_eq!(
+        eval(&format!("builtins.length (builtins.attrNames ({}))", set)).unwrap(),
+        Value::from(2)
+    );
+    assert_eq!(
+        eval(&format!("builtins.getAttr b ({})", set)).unwrap(),
+        Value::from(2)
+    );
+    assert_eq!(eval(&format!("({}).${{a}}", set)).unwrap(), Value::from(1));
+}
+
+#[test]
+fn strings_that_arent_utf8_are_written_with_escapes() {
+    let value = Value::String(b"a\xffb\"\xe2\x82".to_vec().into());
+    assert_eq!(value.to_string(), r#""a\xffb\"\xe2\x82""#);
+    let value = Value::String("\u{e9}${x}".into());
+    assert_eq!(value.to_string(), r#""é\${x}""#);
+}
+
+#[test]
+fn abort_and_throw_take_strings() {
+    assert!(matches!(
+        eval(r#"builtins.throw { a = abort "x"; }"#),
+        Err(EvalError::TypeMismatch(..))
+    ));
+    assert!(matches!(
+        eval(r#"builtins.tryEval (builtins.throw { a = abort "x"; })"#),
+        Err(EvalError::TypeMismatch(..))
+    ));
+    assert!(matches!(eval("abort 1"), Err(EvalError::TypeMismatch(..))));
+    assert_eq!(
+        eval(r#"(builtins.tryEval (throw { outPath = "x"; })).success"#).unwrap(),
+        Value::from(false)
+    );
+}
+
+#[test]
+fn null_attribute_names_only_skip_bindings() {
+    assert_eq!(
+        eval(r#"{ ${null} = 1; a = 2; }"#).unwrap(),
+        eval("{ a = 2; }").unwrap()
+    );
+    assert!(matches!(
+        eval(r#"{ "" = 1; }.${null}"#),
+        Err(EvalError::TypeMismatch(..))
+    ));
+    assert!(matches!(
+        eval(r#"{ "" = 1; } ? ${null}"#),
+        Err(EvalError::TypeMismatch(..))
+    ));
+    assert!(matches!(
+        eval(r#"{ "" = 1; }.${null} or 2"#),
+        Err(EvalError::TypeMismatch(..))
+    ));
+}
+
+#[test]
+fn attribute_sets_with_a_functor_can_be_called() {
+    let counter = "{ __functor = self: x: self // { n = self.n + x; }; n = 0; }";
+    assert_eq!(
+        eval(&format!("((({}) 1) 2).n", counter)).unwrap(),
+        Value::from(3)
+    );
+    // Builtins call them like any other function.
+    assert_eq!(
+        eval("builtins.map { __functor = _: x: x * 2; } [ 1 2 ]").unwrap(),
+        eval("[ 2 4 ]").unwrap()
+    );
+    // The functor can itself be a set with a functor.
+    assert_eq!(
+        eval("{ __functor = { __functor = _: self: x: self.y + x; }; y = 1; } 2").unwrap(),
+        Value::from(3)
+    );
+    assert_eq!(
+        eval("builtins.isFunction { __functor = _: x: x; }").unwrap(),
+        Value::from(false)
+    );
+    assert!(matches!(
+        eval("{ __functor = 1; } 2"),
+        Err(EvalError::TypeMismatch(..))
+    ));
+    assert!(matches!(eval("{ } 2"), Err(EvalError::TypeMismatch(..))));
+}
+
+#[test]
+fn function_args_lists_formals() {
+    assert_eq!(
+        eval("builtins.functionArgs ({ a, b ? 1, ... }: a)").unwrap(),
+        eval("{ a = false; b = true; }").unwrap()
+    );
+    assert_eq!(
+        eval("builtins.functionArgs (x: x)").unwrap(),
+        eval("{ }").unwrap()
+    );
+    assert_eq!(
+        eval("builtins.functionArgs builtins.add").unwrap(),
+        eval("{ }").unwrap()
+    );
+    assert_eq!(
+        eval("builtins.functionArgs (builtins.add 1)").unwrap(),
+        eval("{ }").unwrap()
+    );
+    assert!(matches!(
+        eval("builtins.functionArgs { __functor = _: { a }: a; }"),
+        Err(EvalError::Builtin(BuiltinError::TypeMismatch(..)))
+    ));
+}
+
+/// `builtins.fromTOML` applied to `toml`, as a string literal.
+#[cfg(feature = "toml")]
+fn from_toml(toml: &str) -> Result<Value, EvalError> {
+    eval(&format!("builtins.fromTOML {}", Value::from(toml)))
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn from_toml_converts_tables() {
+    let toml = r#"
+        name = "x"
+        count = 3
+        ratio = 0.5
+        enabled = true
+        list = [1, 2]
+        [nested.table]
+        key = "value"
+        [[items]]
+        a = 1
+        [[items]]
+        a = 2
+    "#;
+    assert_eq!(
+        from_toml(toml).unwrap(),
+        eval(
+            r#"{
+              name = "x"; count = 3; ratio = 0.5; enabled = true; list = [ 1 2 ];
+              nested.table.key = "value";
+              items = [ { a = 1; } { a = 2; } ];
+            }"#
+        )
+        .unwrap()
+    );
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn from_toml_rejects_dates_and_times() {
+    for toml in [
+        "a = 1979-05-27T07:32:00Z",
+        "a = 1979-05-27T07:32:00",
+        "a = 1979-05-27",
+        "a = 07:32:00",
+        "a = [ 1979-05-27 ]",
+        "[a]\nb = 07:32:00",
+    ] {
+        let res = from_toml(toml);
+        assert!(
+            matches!(res, Err(EvalError::Builtin(BuiltinError::TomlDatetime))),
+            "{}: {:?}",
+            toml,
+            res
+        );
+    }
+    assert!(matches!(
+        from_toml("a = "),
+        Err(EvalError::Builtin(BuiltinError::Toml(_)))
+    ));
+    assert!(matches!(
+        eval("builtins.fromTOML 1"),
+        Err(EvalError::Builtin(BuiltinError::TypeMismatch(..)))
+    ));
+}
+
+/// The output of `builtins.toXML` for `source`, without the XML declaration
+/// and the `expr` element around it.
+fn to_xml(source: &str) -> String {
+    match eval(&format!("builtins.toXML ({})", source)).unwrap() {
+        Value::String(xml) => {
+            let xml = xml.to_str().unwrap();
+            let xml = xml
+                .strip_prefix("<?xml version='1.0' encoding='utf-8'?>\n<expr>\n")
+                .unwrap();
+            let xml = xml.strip_suffix("</expr>\n").unwrap();
+            xml.lines().map(|x| format!("{}\n", &x[2..])).collect()
+        }
+        x => panic!("not a string: {}", x),
+    }
+}
+
+#[test]
+fn to_xml_writes_derivations_once() {
+    let source = r#"
+      let drv = {
+        type = "derivation"; name = "x";
+        drvPath = "/store/x.drv"; outPath = "/store/x";
+      };
+      in [ drv drv (drv // { drvPath = ""; }) ]
+    "#;
+    assert_eq!(
+        to_xml(source),
+        r#"<list>
+  <derivation drvPath="/store/x.drv" outPath="/store/x">
+    <attr name="drvPath">
+      <string value="/store/x.drv" />
+    </attr>
+    <attr name="name">
+      <string value="x" />
+    </attr>
+    <attr name="outPath">
+      <string value="/store/x" />
+    </attr>
+    <attr name="type">
+      <string value="derivation" />
+    </attr>
+  </derivation>
+  <derivation drvPath="/store/x.drv" outPath="/store/x">
+    <repeated />
+  </derivation>
+  <derivation drvPath="" outPath="/store/x">
+    <repeated />
+  </derivation>
+</list>
+"#
+    );
+}
+
+#[test]
+fn to_xml_writes_values() {
+    assert_eq!(
+        to_xml(
+            r#"{ s = "a\"<&>\n"; n = null; b = true; f = { y, x ? 1, ... }@args: x; g = x: x; }"#
+        ),
+        r#"<attrs>
+  <attr name="b">
+    <bool value="true" />
+  </attr>
+  <attr name="f">
+    <function>
+      <attrspat ellipsis="1" name="args">
+        <attr name="x" />
+        <attr name="y" />
+      </attrspat>
+    </function>
+  </attr>
+  <attr name="g">
+    <function>
+      <varpat name="x" />
+    </function>
+  </attr>
+  <attr name="n">
+    <null />
+  </attr>
+  <attr name="s">
+    <string value="a&quot;&lt;&amp;&gt;&#xA;" />
+  </attr>
+</attrs>
+"#
+    );
+}
+
+#[test]
+fn to_xml_formats_floats_like_nix() {
+    for (float, xml) in [
+        ("1.0", "1"),
+        ("0.1", "0.1"),
+        ("3.14159265", "3.14159"),
+        ("123456.7", "123457"),
+        ("1234567.0", "1.23457e+06"),
+        ("1000000.0", "1e+06"),
+        ("0.0001", "0.0001"),
+        ("0.00001234", "1.234e-05"),
+        ("-2.5", "-2.5"),
+        ("0.0", "0"),
+    ] {
+        assert_eq!(
+            to_xml(float),
+            format!("<float value=\"{}\" />\n", xml),
+            "{}",
+            float
+        );
+    }
+}
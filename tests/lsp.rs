@@ -0,0 +1,200 @@
+//! Drives `nix_evaluator lsp` over its standard input and output, as an
+//! editor would.
+
+#![cfg(feature = "cli")]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+use lsp_types::Url;
+use serde_json::{json, Value};
+
+struct Client {
+    server: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+    next_id: u64,
+}
+
+impl Client {
+    fn start(root: &Url) -> Self {
+        let mut server = Command::new(env!("CARGO_BIN_EXE_nix_evaluator"))
+            .args(["lsp", "--stdio"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut client = Self {
+            input: server.stdin.take().unwrap(),
+            output: BufReader::new(server.stdout.take().unwrap()),
+            server,
+            next_id: 0,
+        };
+        let capabilities = client.request(
+            "initialize",
+            json!({ "processId": null, "rootUri": root, "capabilities": {} }),
+        );
+        assert!(capabilities["capabilities"]["hoverProvider"] == json!(true));
+        client.notify("initialized", json!({}));
+        client
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        write!(self.input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.input.flush().unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            self.output.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(x) = line.strip_prefix("Content-Length: ") {
+                length = Some(x.parse().unwrap());
+            }
+        }
+        let mut body = vec![0; length.expect("a Content-Length header")];
+        self.output.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /// Send a request and return its result, skipping any notifications
+    /// that come first.
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        loop {
+            let message = self.receive();
+            if message["id"] == json!(id) {
+                return message["result"].to_owned();
+            }
+        }
+    }
+
+    /// Open a document and return the diagnostics published for it.
+    fn open(&mut self, uri: &Url, text: &str) -> Value {
+        self.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": { "uri": uri, "languageId": "nix", "version": 1, "text": text }
+            }),
+        );
+        let message = self.receive();
+        assert_eq!(message["method"], "textDocument/publishDiagnostics");
+        message["params"]["diagnostics"].to_owned()
+    }
+
+    fn at(&mut self, method: &str, uri: &Url, line: u32, character: u32) -> Value {
+        self.request(
+            method,
+            json!({
+                "textDocument": { "uri": uri },
+                "position": { "line": line, "character": character }
+            }),
+        )
+    }
+
+    fn hover(&mut self, uri: &Url, line: u32, character: u32) -> Option<String> {
+        let hover = self.at("textDocument/hover", uri, line, character);
+        hover["contents"]["value"].as_str().map(str::to_owned)
+    }
+
+    fn shutdown(mut self) {
+        self.request("shutdown", Value::Null);
+        self.notify("exit", Value::Null);
+        assert!(self.server.wait().unwrap().success());
+    }
+}
+
+/// A workspace of files, removed when dropped.
+struct Workspace(PathBuf);
+
+impl Workspace {
+    fn new(files: &[(&str, &str)]) -> Self {
+        let root = std::env::temp_dir().join(format!("nix_evaluator_lsp_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        for (name, text) in files {
+            fs::write(root.join(name), text).unwrap();
+        }
+        Self(root)
+    }
+
+    fn uri(&self, name: &str) -> Url {
+        Url::from_file_path(self.0.join(name)).unwrap()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+const MAIN: &str = r#"let
+  lib = import ./lib.nix;
+  x = lib.double 21;
+  home = builtins.getEnv "HOME";
+  secret = builtins.readFile /etc/hostname;
+in
+  lib.double x + y
+"#;
+
+const LIB: &str = "{ double = n: n * 2; }\n";
+
+#[test]
+fn lsp_session() {
+    let workspace = Workspace::new(&[("main.nix", MAIN), ("lib.nix", LIB)]);
+    let root = Url::from_directory_path(&workspace.0).unwrap();
+    let main = workspace.uri("main.nix");
+    let mut client = Client::start(&root);
+
+    let diagnostics = client.open(&main, MAIN);
+    let diagnostics = diagnostics.as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert!(diagnostics[0]["message"].as_str().unwrap().contains('y'));
+    assert_eq!(
+        diagnostics[0]["range"]["start"],
+        json!({ "line": 6, "character": 17 })
+    );
+
+    // `x` is evaluated with the file it imports.
+    let hover = client.hover(&main, 2, 2).unwrap();
+    assert!(hover.contains("42"), "{}", hover);
+
+    // Evaluation can't see the environment or read outside the workspace.
+    let hover = client.hover(&main, 3, 2).unwrap();
+    assert!(hover.contains("\"\""), "{}", hover);
+    assert_eq!(client.hover(&main, 4, 2), None);
+
+    let definition = client.at("textDocument/definition", &main, 6, 13);
+    assert_eq!(definition["uri"], json!(main));
+    assert_eq!(
+        definition["range"]["start"],
+        json!({ "line": 2, "character": 2 })
+    );
+
+    let completion = client.at("textDocument/completion", &main, 6, 6);
+    let labels: Vec<_> = completion
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["label"].as_str().unwrap())
+        .collect();
+    assert_eq!(labels, ["double"]);
+
+    client.shutdown();
+}
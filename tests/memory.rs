@@ -0,0 +1,52 @@
+//! Sessions free the reference cycles of recursive scopes when they're
+//! dropped. Every allocation is counted to check that nothing is left behind.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicIsize, Ordering},
+};
+
+use nix_evaluator::evaluator::{EvaluationContext, Session};
+
+struct Counting;
+
+static LIVE: AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size() as isize, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size() as isize, Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn eval_in_session(source: &str) {
+    let ast = rnix::parse(source);
+    let session = Session::new();
+    let value = session.eval(ast.node(), &EvaluationContext::new()).unwrap();
+    value.materialize().unwrap();
+}
+
+#[test]
+fn recursive_values_are_freed_with_their_session() {
+    for source in [
+        "let xs = [ xs ]; in xs",
+        "let r = { b = r; }; in r.b.b",
+        "let f = n: if n == 0 then [ f ] else f (n - 1); in f 10",
+    ] {
+        // Intern symbols and set up thread-locals before measuring.
+        eval_in_session(source);
+        let before = LIVE.load(Ordering::SeqCst);
+        for _ in 0..2000 {
+            eval_in_session(source);
+        }
+        assert_eq!(LIVE.load(Ordering::SeqCst), before, "{}", source);
+    }
+}
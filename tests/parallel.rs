@@ -0,0 +1,109 @@
+//! Evaluation shared between threads, which needs the `sync` feature.
+
+#![cfg(feature = "sync")]
+
+use std::{
+    sync::{Arc, Barrier},
+    thread,
+    time::Duration,
+};
+
+use nix_evaluator::{
+    embed::Evaluator, evaluator::EvalError, parallel::par_eval_attrs, value::Value,
+};
+
+/// The unforced value of `x` in the set `source` evaluates to. Values can
+/// only be forced while their evaluator is alive.
+fn attr(evaluator: &Evaluator, source: &str) -> Value {
+    match evaluator.eval_str(source).unwrap().materialize().unwrap() {
+        Value::AttrSet(set) => set.get("x").unwrap().to_owned(),
+        x => panic!("not a set: {}", x),
+    }
+}
+
+/// Force `value` from several threads at once.
+fn force_together(value: Value, threads: usize) -> Vec<Result<Value, EvalError>> {
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let (value, barrier) = (value.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                value.materialize_deep()
+            })
+        })
+        .collect();
+    handles.into_iter().map(|x| x.join().unwrap()).collect()
+}
+
+#[test]
+fn attributes_are_evaluated_in_parallel() {
+    let evaluator = Evaluator::new();
+    let set = evaluator
+        .eval_str(r#"{ b = builtins.genList (i: i * 2) 3; a = 1 + 1; c = throw "no"; }"#)
+        .unwrap();
+    let results = par_eval_attrs(set).unwrap();
+    let names: Vec<_> = results.iter().map(|(k, _)| k.as_str()).collect();
+    assert_eq!(names, ["a", "b", "c"]);
+    assert_eq!(*results[0].1.as_ref().unwrap(), Value::from(2));
+    assert_eq!(
+        *results[1].1.as_ref().unwrap(),
+        evaluator.eval_str("[ 0 2 4 ]").unwrap()
+    );
+    assert!(matches!(results[2].1, Err(EvalError::Builtin(_))));
+
+    assert!(matches!(
+        par_eval_attrs(Value::from(1)),
+        Err(EvalError::TypeMismatch(..))
+    ));
+}
+
+#[test]
+fn threads_forcing_one_thunk_agree() {
+    let evaluator = Evaluator::new();
+    let x = attr(
+        &evaluator,
+        "{ x = builtins.foldl' (a: b: a + b) 0 (builtins.genList (i: i) 100000); }",
+    );
+    for res in force_together(x.clone(), 4) {
+        assert_eq!(res.unwrap(), Value::from(4999950000_i64));
+    }
+    assert_eq!(x.materialize().unwrap(), Value::from(4999950000_i64));
+}
+
+#[test]
+fn self_reference_is_found_while_another_thread_forces() {
+    // One thread evaluates `slow` while the other starts forcing `t`, and so
+    // has to evaluate `slow` again. The first thread then forces `t` too,
+    // finds it already being forced, and evaluates it itself. Its reference
+    // back to `t` must be caught, not followed until the stack overflows.
+    let evaluator = Evaluator::new();
+    let set = evaluator
+        .eval_str(
+            "let slow = builtins.all (i: i >= 0) (builtins.genList (i: i) 300000); \
+             t = if slow then t + 1 else 0; in { inherit slow t; }",
+        )
+        .unwrap()
+        .materialize()
+        .unwrap();
+    let get = |name| match &set {
+        Value::AttrSet(set) => set.get(name).unwrap().to_owned(),
+        x => panic!("not a set: {}", x),
+    };
+    let (slow, t) = (get("slow"), get("t"));
+    let first = {
+        let t = t.clone();
+        thread::spawn(move || {
+            slow.materialize().unwrap();
+            t.materialize()
+        })
+    };
+    thread::sleep(Duration::from_millis(10));
+    for res in [t.materialize(), first.join().unwrap()] {
+        assert!(
+            matches!(res, Err(EvalError::InfiniteRecursion)),
+            "{:?}",
+            res
+        );
+    }
+}
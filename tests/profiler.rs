@@ -0,0 +1,128 @@
+//! Profiling records a call tree of what evaluation spends its time in.
+
+use std::time::Duration;
+
+use nix_evaluator::{
+    embed::Evaluator,
+    evaluator::Session,
+    profiler::{Entry, Profiler},
+    sync::Rc,
+};
+
+const SOURCE: &str = "let
+  double = x: x * 2;
+in
+  builtins.foldl' (a: b: a + double b) 0 (builtins.genList (i: i) 1000)
+";
+
+fn profile(source: &str, file: &str) -> Rc<Profiler> {
+    let profiler = Rc::new(Profiler::new());
+    let evaluator = Evaluator::builder()
+        .session(Session::new().with_profiler(profiler.clone()))
+        .build();
+    evaluator
+        .eval_str_as(source, file)
+        .and_then(|x| x.materialize_deep())
+        .unwrap();
+    profiler
+}
+
+/// The stacks written by [`Profiler::write_collapsed`], and the nanoseconds
+/// spent in each.
+fn collapsed(profiler: &Profiler) -> Vec<(String, u128)> {
+    let mut out = Vec::new();
+    profiler.write_collapsed(&mut out).unwrap();
+    String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let (stack, nanos) = line.rsplit_once(' ').unwrap();
+            (stack.to_owned(), nanos.parse().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn collapsed_stacks() {
+    let profiler = profile(SOURCE, "/profiled;file.nix");
+    let lines = collapsed(&profiler);
+    // Frames are separated by `;`, so the one in the file name is replaced.
+    let file = "/profiled:file.nix";
+    let fold = format!("{};builtins.foldl'", file);
+    let step = format!("{};«lambda» {}:4:23", fold, file);
+    let gen = format!("{};builtins.genList", file);
+    let stacks: Vec<_> = lines.iter().map(|(stack, _)| stack.to_owned()).collect();
+    assert_eq!(
+        stacks,
+        [
+            file.to_owned(),
+            fold.clone(),
+            format!("{};«lambda» {}:4:20", fold, file),
+            step.clone(),
+            format!("{};«lambda» {}:2:12", step, file),
+            gen.clone(),
+            format!("{};«lambda» {}:4:61", gen, file),
+        ]
+    );
+
+    // Each line has the time spent in its innermost frame alone.
+    let own: Duration = profiler.hot_spots().iter().map(|x| x.own).sum();
+    let nanos: u128 = lines.iter().map(|(_, nanos)| nanos).sum();
+    assert_eq!(nanos, own.as_nanos());
+}
+
+#[test]
+fn hot_spots_count_calls_and_time() {
+    let profiler = profile(SOURCE, "/profiled.nix");
+    let spots = profiler.hot_spots();
+    assert!(spots.windows(2).all(|x| x[0].own >= x[1].own));
+    let spot = |entry: &Entry| spots.iter().find(|x| x.entry == *entry).unwrap();
+    let file = spot(&Entry::File("/profiled.nix".into()));
+    assert_eq!(file.calls, 1);
+    let fold = spot(&Entry::PrimOp("foldl'"));
+    assert_eq!(fold.calls, 1);
+    assert!(fold.own <= fold.total && fold.total <= file.total);
+    let double = spots
+        .iter()
+        .find(|x| matches!(&x.entry, Entry::Lambda(x) if x.line == 2))
+        .unwrap();
+    assert_eq!(double.calls, 1000);
+    assert_eq!(double.own, double.total);
+}
+
+#[test]
+fn recursive_calls_are_counted_once() {
+    let profiler = profile(
+        "let f = n: if n == 0 then 0 else f (n - 1); in f 100",
+        "/recursive.nix",
+    );
+    let spots = profiler.hot_spots();
+    let file = spots
+        .iter()
+        .find(|x| matches!(x.entry, Entry::File(_)))
+        .unwrap();
+    let f = spots
+        .iter()
+        .find(|x| matches!(x.entry, Entry::Lambda(_)))
+        .unwrap();
+    assert_eq!(f.calls, 101);
+    assert!(f.total <= file.total);
+
+    let lines = collapsed(&profiler);
+    let deepest = lines.iter().map(|(stack, _)| stack.split(';').count());
+    assert_eq!(deepest.max(), Some(102));
+}
+
+#[test]
+fn summary_lists_the_slowest_entries() {
+    let profiler = profile(SOURCE, "/profiled.nix");
+    let mut out = Vec::new();
+    profiler.write_summary(&mut out, 3).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].ends_with("entry"), "{}", out);
+    for (line, spot) in lines[1..].iter().zip(profiler.hot_spots()) {
+        assert!(line.ends_with(&spot.entry.to_string()), "{}", out);
+    }
+}
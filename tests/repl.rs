@@ -0,0 +1,418 @@
+//! Runs the REPL with its input from a pipe, checking what it prints.
+
+#![cfg(feature = "cli")]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A directory for a test's files, and the REPL's history, removed when
+/// dropped.
+struct Dir(PathBuf);
+
+impl Dir {
+    fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "nix_evaluator_repl_{}_{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        );
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// Write the file `name`, returning its path.
+    fn write(&self, name: &str, text: &str) -> String {
+        let path = self.0.join(name);
+        fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A running REPL.
+struct Repl {
+    child: Child,
+    input: ChildStdin,
+    output: BufReader<ChildStdout>,
+}
+
+struct Output {
+    /// What was printed after the banner, by line.
+    stdout: Vec<String>,
+    stderr: Vec<String>,
+}
+
+impl Repl {
+    /// Start the REPL, keeping its history in `dir`.
+    fn start(dir: &Path) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_nix_evaluator"))
+            .arg("repl")
+            .env("XDG_DATA_HOME", dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut repl = Self {
+            input: child.stdin.take().unwrap(),
+            output: BufReader::new(child.stdout.take().unwrap()),
+            child,
+        };
+        assert_eq!(repl.read(), "nix_evaluator version 0.0.0");
+        repl.read();
+        repl.read();
+        repl
+    }
+
+    fn send(&mut self, line: &str) {
+        writeln!(self.input, "{}", line).unwrap();
+        self.input.flush().unwrap();
+    }
+
+    /// The next line printed.
+    fn read(&mut self) -> String {
+        let mut line = String::new();
+        self.output.read_line(&mut line).unwrap();
+        line.trim_end_matches('\n').to_owned()
+    }
+
+    /// End the input, which exits the REPL, and return what it printed
+    /// that hasn't been read yet.
+    fn finish(self) -> Output {
+        let Self {
+            mut child,
+            input,
+            mut output,
+        } = self;
+        drop(input);
+        let mut stdout = String::new();
+        output.read_to_string(&mut stdout).unwrap();
+        let mut stderr = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let lines = |x: String| x.lines().map(str::to_owned).collect();
+        Output {
+            stdout: lines(stdout),
+            stderr: lines(stderr),
+        }
+    }
+}
+
+/// Run the REPL on `lines`, keeping its history in `dir`.
+fn repl_in(dir: &Path, lines: &[&str]) -> Output {
+    let mut repl = Repl::start(dir);
+    for line in lines {
+        repl.send(line);
+    }
+    repl.finish()
+}
+
+fn repl(lines: &[&str]) -> Output {
+    repl_in(&Dir::new().0, lines)
+}
+
+#[test]
+fn types_and_printing() {
+    let output = repl(&[
+        ":t 1",
+        r#":type "a""#,
+        ":t x: x",
+        "{ a = [ 1 ]; b = 2; }",
+        ":p { a = [ 1 ]; b = 2; }",
+        ":print [ (1 + 1) ]",
+    ]);
+    assert_eq!(
+        output.stdout,
+        [
+            "integer",
+            "string",
+            "function",
+            // Results are only printed one level deep, unlike with `:p`.
+            "{ a = [ ... ]; b = 2; }",
+            "{ a = [ 1 ]; b = 2; }",
+            "[ 2 ]",
+        ]
+    );
+    assert_eq!(output.stderr, Vec::<String>::new());
+}
+
+#[test]
+fn adding_and_loading_into_scope() {
+    let dir = Dir::new();
+    // Files holding functions taking sets are called, like `nix repl` does.
+    let file = dir.write("lib.nix", "{ n ? 1 }: { double = x: x * 2; n = n; }");
+    let output = repl_in(
+        &dir.0,
+        &[
+            ":a { x = 3; y = 4; }",
+            "x + y",
+            &format!(":l {}", file),
+            "double n",
+            ":add 1",
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        ["Added 2 variables.", "7", "Added 2 variables.", "2"]
+    );
+    assert_eq!(
+        output.stderr,
+        ["error: expected an attribute set, but found integer"]
+    );
+}
+
+#[test]
+fn reloading_files() {
+    let dir = Dir::new();
+    let file = dir.write("a.nix", "{ v = 1; }");
+    let mut repl = Repl::start(&dir.0);
+    repl.send(&format!(":l {}", file));
+    assert_eq!(repl.read(), "Added 1 variables.");
+    repl.send("v");
+    assert_eq!(repl.read(), "1");
+    dir.write("a.nix", "{ v = 2; }");
+    repl.send("v");
+    assert_eq!(repl.read(), "1");
+    repl.send(":r");
+    assert_eq!(repl.read(), "Added 1 variables.");
+    repl.send("v");
+    assert_eq!(repl.read(), "2");
+    let output = repl.finish();
+    assert_eq!(output.stdout, Vec::<String>::new());
+    assert_eq!(output.stderr, Vec::<String>::new());
+}
+
+#[test]
+fn documentation_and_help() {
+    let output = repl(&[":doc builtins.map", ":doc 1"]);
+    assert_eq!(
+        output.stdout,
+        [
+            "Synopsis: builtins.map f list",
+            "",
+            "    Apply `f` to every element of `list`."
+        ]
+    );
+    assert_eq!(
+        output.stderr,
+        ["error: only builtins have documentation, not a integer"]
+    );
+
+    let output = repl(&[":?"]);
+    assert_eq!(output.stdout[0], "The following commands are available:");
+    assert!(output.stdout.iter().any(|x| x.starts_with("  :doc <expr>")));
+}
+
+#[test]
+fn quitting_and_unknown_commands() {
+    let output = repl(&[":bogus", "1", ":q", "2"]);
+    assert_eq!(output.stdout, ["1"]);
+    assert_eq!(
+        output.stderr,
+        ["error: unknown command ':bogus', see :? for help"]
+    );
+    assert_eq!(repl(&[":quit", "2"]).stdout, Vec::<String>::new());
+}
+
+#[test]
+fn unfinished_input_continues_on_the_next_line() {
+    let output = repl(&[
+        "(1 +",
+        "2)",
+        "x = [",
+        "  1",
+        "]",
+        ":p { a = {",
+        "b = x; }; }",
+        r#""a"#,
+        r#"b""#,
+        // Commands that don't take expressions are never continued.
+        ":t",
+        ":?x",
+    ]);
+    assert_eq!(output.stdout, ["3", "{ a = { b = [ 1 ]; }; }", r#""a\nb""#]);
+    assert_eq!(output.stderr.len(), 2);
+}
+
+#[test]
+fn bindings_and_results() {
+    let output = repl(&[
+        // Bindings are lazy, so errors only show when they're used.
+        r#"x = throw "later""#,
+        "y = 2",
+        "z = y * 3",
+        "z",
+        "y = 10",
+        // `z` keeps the `y` it was bound with.
+        "z + y",
+        "_1 + _",
+        "_2",
+        "x",
+        // A comparison isn't a binding.
+        "y == 10",
+    ]);
+    assert_eq!(output.stdout, ["6", "16", "22", "16", "true"]);
+    assert_eq!(
+        output.stderr,
+        ["error: A call to a built-in function failed: Error thrown: later"]
+    );
+}
+
+#[test]
+fn sessions_are_saved_and_loaded() {
+    let dir = Dir::new();
+    let lib = dir.write("lib.nix", "{ n = 1; }");
+    let session = dir.0.join("session.nix");
+    let session = session.to_str().unwrap();
+    let output = repl_in(
+        &dir.0,
+        &[
+            &format!(":l {}", lib),
+            r#"s = "a \"quoted\" ${toString n}""#,
+            ":a { m = 2; }",
+            "1 + 1",
+            &format!(":save {}", session),
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        ["Added 1 variables.", "Added 1 variables.", "2"]
+    );
+    // Results aren't definitions.
+    assert_eq!(
+        fs::read_to_string(session).unwrap(),
+        format!(
+            r#"# Definitions from a nix_evaluator REPL session, in the order they were
+# made. Redo them with `:load-session`.
+[
+  {{ load = "{}"; }}
+  {{ name = "s"; value = "\"a \\\"quoted\\\" \${{toString n}}\""; }}
+  {{ add = "{{ m = 2; }}"; }}
+]
+"#,
+            lib
+        )
+    );
+
+    dir.write("lib.nix", "{ n = 3; }");
+    let output = repl_in(
+        &dir.0,
+        &[
+            &format!(":load-session {}", session),
+            "s + toString m",
+            // Redone definitions are part of the session, and :r redoes them.
+            r#"s = "b""#,
+            ":r",
+            "s",
+        ],
+    );
+    assert_eq!(
+        output.stdout,
+        [
+            "Added 1 variables.",
+            "Added 1 variables.",
+            r#""a \"quoted\" 32""#,
+            "Added 1 variables.",
+            "Added 1 variables.",
+            r#""b""#,
+        ]
+    );
+}
+
+#[test]
+fn history_is_kept_between_runs() {
+    let dir = Dir::new();
+    repl_in(&dir.0, &["1 + 1", ":t 1"]);
+    repl_in(&dir.0, &["2"]);
+    let history = fs::read_to_string(dir.0.join("nix_evaluator/history")).unwrap();
+    let lines: Vec<_> = history.lines().filter(|x| !x.starts_with('#')).collect();
+    assert_eq!(lines, ["1 + 1", ":t 1", "2"]);
+}
+
+#[test]
+fn errors_are_reported_and_the_repl_continues() {
+    let dir = Dir::new();
+    let missing = dir.0.join("missing.nix");
+    let missing = missing.to_str().unwrap();
+    let output = repl_in(
+        &dir.0,
+        &[
+            "1 +",
+            "+ 1",
+            "undefined",
+            r#"1 + "a""#,
+            &format!(":l {}", missing),
+            &format!(":load-session {}", missing),
+            &format!(":save {}/x", missing),
+            "1 + 1",
+        ],
+    );
+    assert_eq!(output.stdout, ["2"]);
+    assert_eq!(output.stderr.len(), 6, "{:?}", output.stderr);
+    assert!(output.stderr.iter().all(|x| x.starts_with("error: ")));
+    assert!(output.stderr[3].contains(missing));
+    assert!(output.stderr[4].starts_with(&format!("error: failed to read {}", missing)));
+    assert!(output.stderr[5].starts_with(&format!("error: failed to write {}/x", missing)));
+}
+
+#[test]
+fn reloading_keeps_definitions_that_fail() {
+    let dir = Dir::new();
+    let file = dir.write("a.nix", "{ v = 1; }");
+    let mut repl = Repl::start(&dir.0);
+    repl.send(&format!(":l {}", file));
+    assert_eq!(repl.read(), "Added 1 variables.");
+    repl.send("w = 2");
+    fs::remove_file(&file).unwrap();
+    repl.send(":r");
+    repl.send("w");
+    assert_eq!(repl.read(), "2");
+    dir.write("a.nix", "{ v = 3; }");
+    repl.send(":r");
+    assert_eq!(repl.read(), "Added 1 variables.");
+    repl.send("v + w");
+    assert_eq!(repl.read(), "5");
+    let output = repl.finish();
+    assert_eq!(output.stdout, Vec::<String>::new());
+    assert_eq!(output.stderr.len(), 1, "{:?}", output.stderr);
+    assert!(output.stderr[0].contains(&file));
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupting_cancels_the_evaluation() {
+    let dir = Dir::new();
+    let mut repl = Repl::start(&dir.0);
+    repl.send(
+        "builtins.foldl' (a: i: a + builtins.length (builtins.genList (x: x) 1000)) 0 \
+         (builtins.genList (x: x) 100000)",
+    );
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    let status = Command::new("kill")
+        .args(["-INT", &repl.child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    repl.send("1 + 1");
+    assert_eq!(repl.read(), "2");
+    let output = repl.finish();
+    assert_eq!(output.stdout, Vec::<String>::new());
+    assert_eq!(output.stderr, ["error: Evaluation was cancelled"]);
+}
@@ -0,0 +1,144 @@
+//! Nix values as typed configuration, through serde.
+
+#![cfg(feature = "serde")]
+
+use serde::{Deserialize, Serialize};
+
+use nix_evaluator::{
+    embed::Evaluator,
+    evaluator::EvalError,
+    serde::{from_value, to_value, Error, ErrorKind, Segment},
+    value::Value,
+};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Mode {
+    Fast,
+    Safe,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Backend {
+    Local(String),
+    Remote { host: String, port: u16 },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Config {
+    name: String,
+    ratio: f64,
+    tags: Vec<String>,
+    debug: Option<bool>,
+    mode: Mode,
+    backends: Vec<Backend>,
+}
+
+const CONFIG: &str = r#"{
+  name = "web";
+  ratio = 0.5;
+  tags = [ "a" "b" ];
+  debug = null;
+  mode = "Safe";
+  backends = [
+    { Local = "/run/web.sock"; }
+    { Remote = { host = "example.org"; port = 8080; }; }
+  ];
+  unused = throw "not read";
+}"#;
+
+fn config() -> Config {
+    Config {
+        name: "web".into(),
+        ratio: 0.5,
+        tags: vec!["a".into(), "b".into()],
+        debug: None,
+        mode: Mode::Safe,
+        backends: vec![
+            Backend::Local("/run/web.sock".into()),
+            Backend::Remote {
+                host: "example.org".into(),
+                port: 8080,
+            },
+        ],
+    }
+}
+
+fn from_nix<T: serde::de::DeserializeOwned>(source: &str) -> Result<T, Error> {
+    from_value(Evaluator::new().eval_str(source).unwrap())
+}
+
+fn path(e: &Error) -> Vec<Segment> {
+    e.path().cloned().collect()
+}
+
+#[test]
+fn values_deserialize_into_types() {
+    // Attributes the type doesn't have aren't forced.
+    assert_eq!(from_nix::<Config>(CONFIG).unwrap(), config());
+    assert_eq!(
+        from_nix::<Vec<Mode>>(r#"[ "Fast" (if true then "Safe" else "Fast") ]"#).unwrap(),
+        [Mode::Fast, Mode::Safe]
+    );
+    assert_eq!(from_nix::<Option<i64>>("1 + 1").unwrap(), Some(2));
+    assert_eq!(from_nix::<Option<i64>>("null").unwrap(), None);
+}
+
+#[test]
+fn types_round_trip_through_values() {
+    let value = to_value(&config()).unwrap();
+    let source = format!(r#"builtins.removeAttrs {} [ "unused" ]"#, CONFIG);
+    let expected = Evaluator::new()
+        .eval_str(&source)
+        .and_then(Value::materialize_deep)
+        .unwrap();
+    assert_eq!(value, expected);
+    assert_eq!(from_value::<Config>(value).unwrap(), config());
+
+    assert_eq!(
+        to_value(&(1, "a", ())).unwrap(),
+        Evaluator::new().eval_str(r#"[ 1 "a" null ]"#).unwrap()
+    );
+    assert_eq!(to_value(&'x').unwrap(), Value::from("x"));
+}
+
+#[test]
+fn errors_name_where_they_occurred() {
+    let e = from_nix::<Config>(&CONFIG.replace("port = 8080", r#"port = "80""#)).unwrap_err();
+    assert_eq!(
+        path(&e),
+        [
+            Segment::Attr("backends".into()),
+            Segment::Index(1),
+            Segment::Attr("Remote".into()),
+            Segment::Attr("port".into()),
+        ]
+    );
+    assert!(matches!(e.kind(), ErrorKind::Message(_)));
+    assert!(
+        e.to_string()
+            .starts_with("backends[1].Remote.port: invalid type"),
+        "{}",
+        e
+    );
+
+    let e = from_nix::<Config>(&CONFIG.replace(r#""a" "b""#, r#""a" (throw "b")"#)).unwrap_err();
+    assert_eq!(path(&e), [Segment::Attr("tags".into()), Segment::Index(1)]);
+    assert!(matches!(e.kind(), ErrorKind::Eval(EvalError::Builtin(_))));
+
+    let e = from_nix::<Config>(r#"{ name = "web"; }"#).unwrap_err();
+    assert_eq!(path(&e), []);
+    assert!(e.to_string().contains("missing field `ratio`"), "{}", e);
+
+    assert!(from_nix::<Mode>(r#""Slow""#).is_err());
+    assert!(from_nix::<Backend>(r#"{ Local = "a"; Remote = "b"; }"#).is_err());
+    assert!(from_nix::<u8>("256").is_err());
+    assert!(from_nix::<String>("x: x").is_err());
+}
+
+#[test]
+fn values_that_cant_be_built() {
+    let e = to_value(&u64::MAX).unwrap_err();
+    assert_eq!(path(&e), []);
+    let e = to_value(&[0, u64::MAX]).unwrap_err();
+    assert_eq!(path(&e), [Segment::Index(1)]);
+}
@@ -0,0 +1,99 @@
+//! Statistics count the work an evaluation does, like `NIX_SHOW_STATS`.
+
+use std::fs;
+
+use nix_evaluator::{embed::Evaluator, evaluator::Session, stats::Stats};
+
+const SOURCE: &str = "let
+  xs = [ 1 2 3 ];
+  f = x: x + 1;
+in
+  builtins.map f xs ++ [ ({ a = 1; b = 2; } // { c = 3; }) ]
+";
+
+fn stats_of(evaluator: &Evaluator, source: &str) -> Stats {
+    evaluator
+        .eval_str(source)
+        .and_then(|x| x.materialize_deep())
+        .unwrap();
+    evaluator.session().stats().unwrap()
+}
+
+fn evaluator() -> Evaluator {
+    Evaluator::builder()
+        .session(Session::new().with_stats())
+        .build()
+}
+
+#[test]
+fn stats_count_what_is_built_and_called() {
+    let stats = stats_of(&evaluator(), SOURCE);
+    assert_eq!(stats.function_calls, 3);
+    assert_eq!(
+        stats.primop_calls.into_iter().collect::<Vec<_>>(),
+        [("map", 1)]
+    );
+    // The two set literals and `//`.
+    assert_eq!(stats.attrsets, 3);
+    assert_eq!(stats.attrset_elements, 6);
+    // The two list literals and `++`.
+    assert_eq!(stats.lists, 3);
+    assert_eq!(stats.list_elements, 8);
+    assert_eq!(stats.files_parsed, 1);
+    assert_eq!(stats.files_imported, 0);
+    assert!(stats.wall_time > Default::default());
+}
+
+#[test]
+fn stats_accumulate_over_a_session() {
+    let dir = std::env::temp_dir().join(format!("nix_evaluator_stats_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("f.nix"),
+        "n: if n == 0 then 0 else n + import ./f.nix (n - 1)",
+    )
+    .unwrap();
+    let evaluator = evaluator();
+    let first = stats_of(&evaluator, "1 + 1");
+    let source = format!("import {}/f.nix 10", dir.display());
+    let stats = stats_of(&evaluator, &source);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Importing the same file again reuses its value.
+    assert_eq!(stats.files_imported, 1);
+    assert_eq!(stats.files_parsed, 3);
+    assert_eq!(stats.function_calls, 11);
+    assert!(stats.max_stack_depth >= 10);
+    assert!(stats.wall_time > first.wall_time);
+
+    assert_eq!(Evaluator::new().session().stats(), None);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn stats_are_written_as_json() {
+    let stats = stats_of(&evaluator(), SOURCE);
+    let json = serde_json::to_value(&stats).unwrap();
+    let keys: Vec<_> = json.as_object().unwrap().keys().collect();
+    assert_eq!(
+        keys,
+        [
+            "attrSetElements",
+            "attrSets",
+            "filesImported",
+            "filesParsed",
+            "functionCalls",
+            "listElements",
+            "lists",
+            "maxStackDepth",
+            "primOpCalls",
+            "thunksCreated",
+            "thunksForced",
+            "wallTime",
+        ]
+    );
+    assert_eq!(json["functionCalls"], 3);
+    assert_eq!(json["primOpCalls"], serde_json::json!({ "map": 1 }));
+    assert_eq!(json["lists"], 3);
+    assert_eq!(json["wallTime"], stats.wall_time.as_secs_f64());
+}